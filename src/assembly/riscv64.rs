@@ -48,6 +48,24 @@ pub unsafe fn mscratch_write(value: usize) {
     asm!("csrw mscratch, {}", in(reg) value, options(nostack))
 }
 
+/// # Safety
+/// The 'mhartid' register is accessible exclusively in machine mode.
+#[inline]
+pub unsafe fn mhartid_read() -> usize {
+    let value;
+    asm!("csrr {}, mhartid", out(reg) value, options(nomem, nostack));
+    value
+}
+
+/// # Safety
+/// The 'mstatus' register is accessible exclusively in machine mode.
+#[inline]
+pub unsafe fn mstatus_read() -> usize {
+    let value;
+    asm!("csrr {}, mstatus", out(reg) value, options(nomem, nostack));
+    value
+}
+
 /// # Safety
 /// The 'mstatus' register is accessible exclusively in machine mode.
 /// Please refer to the privileged ISA documentation for format details.
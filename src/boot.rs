@@ -1,27 +1,47 @@
 use crate::devices::shutdown;
+use crate::devices::virtio::common::{DeviceManager, DeviceType};
 use crate::devices::virtio::DeviceError;
-use crate::filesystem::virtual_fs::FilesystemType::Ext3;
+use crate::filesystem::dev_fs::DEV_PATH;
+use crate::filesystem::virtual_fs::FilesystemType::{Devices, Ext3};
 use crate::filesystem::virtual_fs::{MountPoint, VirtualFsManager};
+use crate::mmu::alloc_stats::AllocTag;
+use crate::print;
 use alloc::string::ToString;
 
 const MAX_PARTITIONS: u8 = 4;
 
 pub fn load_disk() -> Result<(), DeviceError> {
+    mount_root();
+    display_boot_file()?;
+    shutdown();
+    Ok(())
+}
+
+/// Monta la primera partición del primer disco como sistema de archivos raíz,
+/// y los dispositivos en `/dev`
+pub fn mount_root() {
+    let _tag = AllocTag::Vfs.enter();
     VirtualFsManager::init();
+    let device_id = DeviceManager::find(DeviceType::Block, 0).expect("No block device found");
     let mount_point = MountPoint {
         path: "/".to_string(),
         fs_type: Ext3 {
-            device_id: 0,
+            device_id,
             partition_id: 0,
         },
     };
     VirtualFsManager::push_mount_point(mount_point);
-    display_boot_file()?;
-    shutdown();
-    Ok(())
+    let dev_mount_point = MountPoint {
+        path: DEV_PATH.to_string(),
+        fs_type: Devices,
+    };
+    VirtualFsManager::push_mount_point(dev_mount_point);
 }
 
 fn display_boot_file() -> Result<(), DeviceError> {
-    VirtualFsManager::open("/boot/hello.md").unwrap();
+    let contents = VirtualFsManager::read_to_end("/boot/hello.md").unwrap();
+    for c in contents {
+        print!("{}", c as char);
+    }
     Ok(())
 }
@@ -12,6 +12,21 @@ const PLIC_INT_ENABLE: usize = PLIC_INT_BASE + 0x2000;
 const PLIC_THRESHOLD: usize = PLIC_INT_BASE + 0x20_0000;
 const PLIC_CLAIM: usize = PLIC_INT_BASE + 0x20_0004;
 
+/// Primer y último id de interrupción que atendemos: VirtIO = [1..8] y
+/// UART0 = 10
+const FIRST_INTERRUPT: u32 = 1;
+const LAST_INTERRUPT: u32 = 10;
+
+/// Habilita las interrupciones de los dispositivos VirtIO y del UART
+pub fn init() {
+    // Bajamos el threshold para que nuestras interrupciones lo superen
+    set_threshold(0);
+    for i in FIRST_INTERRUPT..=LAST_INTERRUPT {
+        enable(i);
+        set_priority(i, 1);
+    }
+}
+
 /// Habilita una interrupción interna según su id
 pub fn enable(id: u32) {
     let enables = PLIC_INT_ENABLE as *mut u32;
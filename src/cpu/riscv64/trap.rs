@@ -1,20 +1,30 @@
 use crate::assembly::riscv64;
 use crate::cpu::riscv64::plic;
+use crate::devices::console::Console;
 use crate::devices::uart_16550::{read_uart, Uart};
+use crate::devices::virtio::common::DeviceManager;
 use crate::mmu::map_table::{EntryBits, MapTable};
-use crate::mmu::riscv64::{MTIMECMP_ADDRESS, MTIME_ADDRESS, PAGE_SIZE};
+use crate::mmu::riscv64::{GLOBAL_PAGE_TABLE, MTIMECMP_ADDRESS, MTIME_ADDRESS, PAGE_SIZE};
+use crate::mmu::vma::Access;
+use crate::net::NetworkStack;
+use crate::system::process::SEGFAULT_STATUS;
+use crate::system::process_table::ProcessTable;
+use crate::system::scheduler::Scheduler;
 use crate::system::syscall::syscall_impl::execute_syscall;
 use crate::{print, println};
-use alloc::boxed::Box;
 use core::mem::size_of;
 use core::ptr::null_mut;
 
-/// Trap Frames para cada núcleo (8 núcleos en total)
+/// Cantidad máxima de núcleos soportados
+pub const MAX_HARTS: usize = 8;
+
+/// Trap Frames para cada núcleo
 /// TODO: Fix!
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::new(); 8];
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::new(); MAX_HARTS];
 
-pub const TIMER_OFFSET_VALUE: u64 = 1000;
-const MSECS_CYCLES: u64 = 10_000;
+/// Quantum del planificador, en milisegundos
+pub const TIMER_OFFSET_VALUE: u64 = 10;
+pub const MSECS_CYCLES: u64 = 10_000;
 
 const UART_INT: u32 = 10;
 
@@ -30,8 +40,14 @@ pub struct TrapFrame {
     pub satp: usize,
     pub trap_stack: *mut u8,
     pub hartid: usize,
+    /// Registro de control de punto flotante, que `trap.S` guarda con `fregs`
+    pub fcsr: usize,
 }
 
+// `trap.S` usa estas posiciones para guardar el frame
+const _: () = assert!(core::mem::offset_of!(TrapFrame, fregs) == 256);
+const _: () = assert!(core::mem::offset_of!(TrapFrame, fcsr) == 536);
+
 impl TrapFrame {
     /// Devuelve un TrapFrame inicializado en 0
     pub const fn new() -> Self {
@@ -41,6 +57,7 @@ impl TrapFrame {
             satp: 0,
             trap_stack: null_mut(),
             hartid: 0,
+            fcsr: 0,
         }
     }
 
@@ -55,8 +72,8 @@ impl TrapFrame {
         // El scratch apunta al contexto de mi frame
         let scratch_val = frame as *const TrapFrame as usize;
         unsafe { riscv64::mscratch_write(scratch_val) };
-        let trap_stack_mem = Box::<u8>::new(0);
-        let trap_stack = Box::<u8>::into_raw(trap_stack_mem);
+        // El stack ocupa una página entera, así que no lo pedimos al allocator global
+        let trap_stack = GLOBAL_PAGE_TABLE.get_root().alloc(1).unwrap().as_ptr();
         unsafe {
             // Reservo memoria para el stack de mi riscv64 handler
             // Como el stack crece de arriba hacia abajo, le paso la dirección del final del stack
@@ -96,16 +113,22 @@ extern "C" fn m_trap_handler(
             7 => {
                 // Machine timer
                 schedule_mtime_interrupt(TIMER_OFFSET_VALUE);
+                return_pc = Scheduler::schedule(hart, epc);
             }
             11 => {
                 // Machine external interrupt
                 if let Some(interrupt) = plic::next_interrupt() {
                     // Ocurrió una interrupción en el Claim register
                     match interrupt {
-                        1..=8 => {}
+                        1..=8 => {
+                            DeviceManager::handle_interrupt(interrupt);
+                            NetworkStack::poll();
+                        }
                         UART_INT => {
                             let uart = Uart::new(0x1000_0000);
-                            read_uart(&uart);
+                            if let Some(c) = read_uart(&uart) {
+                                Console::push_input(c);
+                            }
                         }
                         _ => {
                             println!("Unknown interrupt: {}", interrupt);
@@ -113,6 +136,10 @@ extern "C" fn m_trap_handler(
                     }
                     plic::complete(interrupt);
                 }
+                // Si el núcleo estaba ocioso, la interrupción pudo despertar a un proceso
+                if ProcessTable::get_current_pid(hart).is_none() {
+                    return_pc = Scheduler::schedule(hart, epc);
+                }
             }
             _ => {
                 panic!("Unhandled async riscv64 CPU#{} -> {}\n", hart, cause_num);
@@ -151,8 +178,13 @@ extern "C" fn m_trap_handler(
             }
             8 => {
                 // Environment (system) call from User mode
-                execute_syscall(frame, epc);
-                return_pc += 4;
+                return_pc = execute_syscall(frame, epc);
+                // Si la syscall bloqueó o terminó al proceso, cedemos la CPU
+                let still_running =
+                    ProcessTable::current().is_some_and(|process| process.is_running());
+                if !still_running {
+                    return_pc = Scheduler::schedule(hart, return_pc);
+                }
             }
             9 => {
                 // Environment (system) call from Supervisor mode
@@ -166,27 +198,15 @@ extern "C" fn m_trap_handler(
             // Page faults
             12 => {
                 // Instruction page fault
-                panic!(
-                    "Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
-                //return_pc += 4;
+                return_pc = page_fault(hart, epc, tval, Access::Execute);
             }
             13 => {
                 // Load page fault
-                println!(
-                    "Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
-                return_pc += 4;
+                return_pc = page_fault(hart, epc, tval, Access::Load);
             }
             15 => {
                 // Store page fault
-                println!(
-                    "Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
-                return_pc += 4;
+                return_pc = page_fault(hart, epc, tval, Access::Store);
             }
             _ => {
                 panic!("Unhandled sync riscv64 CPU#{} -> {}\n", hart, cause_num);
@@ -197,6 +217,45 @@ extern "C" fn m_trap_handler(
     return_pc
 }
 
+/// Page fault del proceso actual en la dirección `tval`. Si pertenece a un
+/// área válida del proceso se mapea una página nueva y se reintenta la
+/// instrucción. Si no, se termina al proceso con un reporte y se le cede la
+/// CPU al siguiente
+fn page_fault(hart: usize, epc: usize, tval: usize, access: Access) -> usize {
+    let Some(process) = ProcessTable::current() else {
+        panic!(
+            "{:?} page fault CPU#{} without process -> 0x{:08x}: 0x{:08x}",
+            access, hart, epc, tval
+        );
+    };
+    if process.handle_page_fault(tval, access) {
+        return epc;
+    }
+    let pid = process.get_pid();
+    println!(
+        "Segmentation fault CPU#{} PID {}: {:?} at 0x{:08x} (pc 0x{:08x})",
+        hart, pid, access, tval, epc
+    );
+    match process.vmas().find(tval) {
+        Some(vma) => println!("Address in {:?} area without permission", vma.kind),
+        None => println!("Address outside of the process memory areas"),
+    }
+    for vma in process.vmas().iter() {
+        println!(
+            "  0x{:08x}-0x{:08x} {:?} bits {:#x}",
+            vma.start, vma.end, vma.kind, vma.bits
+        );
+    }
+    ProcessTable::exit(pid, SEGFAULT_STATUS);
+    Scheduler::schedule(hart, epc)
+}
+
+/// Lee el registro `mtime`, que cuenta ciclos desde el arranque
+pub fn read_mtime() -> u64 {
+    let mtime = MTIME_ADDRESS as *const u64;
+    unsafe { mtime.read_volatile() }
+}
+
 /// Asigna un valor al registro `mtimecmp` relativo al tiempo actual
 /// Se lanza una interrupcción luego de `msecs` milisegundos
 pub fn schedule_mtime_interrupt(msecs: u64) {
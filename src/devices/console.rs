@@ -0,0 +1,96 @@
+//! # Consola
+//! Entrada y salida estándar de los procesos. La salida se imprime por el
+//! UART, y la entrada se acumula en un buffer que llenan las interrupciones del
+//! UART y del teclado VirtIO. Los procesos que leen sin datos disponibles quedan esperando hasta
+//! que llegue un caracter.
+use crate::system::process::Pid;
+use crate::system::process_table::ProcessTable;
+use crate::{print, println};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+struct ConsoleBuffer {
+    input: VecDeque<u8>,
+    /// Procesos bloqueados leyendo de la consola
+    waiters: Vec<Pid>,
+}
+
+pub struct Console {
+    buffer: UnsafeCell<ConsoleBuffer>,
+}
+
+unsafe impl Sync for Console {}
+
+static CONSOLE: Console = Console::empty();
+
+impl Console {
+    const fn empty() -> Self {
+        let buffer = ConsoleBuffer {
+            input: VecDeque::new(),
+            waiters: Vec::new(),
+        };
+        let buffer = UnsafeCell::new(buffer);
+        Self { buffer }
+    }
+
+    /// El buffer sólo se modifica dentro de los traps, que se atienden con
+    /// las interrupciones deshabilitadas
+    fn buffer() -> &'static mut ConsoleBuffer {
+        unsafe { &mut *CONSOLE.buffer.get() }
+    }
+
+    /// Agrega un caracter recibido y despierta a los procesos que esperan
+    pub fn push_input(c: u8) {
+        let buffer = Console::buffer();
+        buffer.input.push_back(c);
+        for pid in buffer.waiters.drain(..) {
+            ProcessTable::wake(pid);
+        }
+    }
+
+    /// Lee los caracteres disponibles. Si no hay ninguno, devuelve `None` y
+    /// `pid` queda registrado para despertar cuando llegue uno
+    pub fn read(pid: Pid, buf: &mut [u8]) -> Option<usize> {
+        let buffer = Console::buffer();
+        if buffer.input.is_empty() {
+            if !buffer.waiters.contains(&pid) {
+                buffer.waiters.push(pid);
+            }
+            return None;
+        }
+        let read = core::cmp::min(buf.len(), buffer.input.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.input.drain(..read)) {
+            *dst = src;
+        }
+        Some(read)
+    }
+
+    /// Muestra en pantalla un caracter ingresado y lo devuelve, convirtiendo
+    /// el *carriage return* en salto de línea
+    pub fn echo(c: u8) -> u8 {
+        match c {
+            8 | 127 => {
+                // Backspace
+                print!("{} {}", 8 as char, 8 as char);
+                c
+            }
+            10 | 13 => {
+                // Newline or carriage-return
+                println!();
+                b'\n'
+            }
+            _ => {
+                print!("{}", c as char);
+                c
+            }
+        }
+    }
+
+    pub fn write(data: &[u8]) -> usize {
+        for c in data {
+            print!("{}", *c as char);
+        }
+        data.len()
+    }
+}
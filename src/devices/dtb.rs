@@ -130,6 +130,20 @@ impl DtbReader {
         }
     }
 
+    /// Argumentos de línea de comandos del kernel (`/chosen/bootargs`), que en
+    /// QEMU se configuran con `-append`
+    pub fn get_bootargs(&self) -> Option<&str> {
+        let node_iterator = NodeIterator::new(self);
+        if let Some(FdtNode::PropNode(_, data, _)) = node_iterator
+            .skip_while(|node| !node.starts_with("chosen"))
+            .find(|node| node.has_prop_or_label("bootargs"))
+        {
+            Some(unsafe { NullTerminatedStr::as_str(data) })
+        } else {
+            None
+        }
+    }
+
     // Data[1] tiene el inicio de la memoria, data[3] el tamaño (O serán 2 enteros de 64 bits?)
     pub fn get_memory_info(&self) -> [usize; 2] {
         let mut res = [0; 2];
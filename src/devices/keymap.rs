@@ -0,0 +1,78 @@
+//! # Mapa de teclado
+//! Traduce los códigos de tecla de Linux (`KEY_*`), que usan los dispositivos
+//! de entrada VirtIO, a caracteres ASCII con la distribución de EE.UU.
+//! Sólo cubre el bloque alfanumérico principal.
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_KPENTER: u16 = 96;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_DELETE: u16 = 111;
+
+/// Valores de un evento `EV_KEY`
+const KEY_RELEASED: u32 = 0;
+const KEY_PRESSED: u32 = 1;
+const KEY_REPEATED: u32 = 2;
+
+/// Caracter de cada tecla, indexado por código. Los ceros son teclas que no
+/// generan caracteres
+const KEYMAP: &[u8; 58] =
+    b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// Estado de los modificadores del teclado
+#[derive(Default)]
+pub struct Keymap {
+    shift: bool,
+    ctrl: bool,
+    caps_lock: bool,
+}
+
+impl Keymap {
+    pub const fn new() -> Self {
+        Self {
+            shift: false,
+            ctrl: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Procesa un evento de tecla y devuelve el caracter que genera, si hay
+    pub fn key_event(&mut self, code: u16, value: u32) -> Option<u8> {
+        let pressed = match value {
+            KEY_PRESSED | KEY_REPEATED => true,
+            KEY_RELEASED => false,
+            _ => return None,
+        };
+        match code {
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT => self.shift = pressed,
+            KEY_LEFTCTRL | KEY_RIGHTCTRL => self.ctrl = pressed,
+            KEY_CAPSLOCK if value == KEY_PRESSED => self.caps_lock = !self.caps_lock,
+            _ if pressed => return self.translate(code),
+            _ => {}
+        }
+        None
+    }
+
+    fn translate(&self, code: u16) -> Option<u8> {
+        let c = match code {
+            KEY_KPENTER => b'\n',
+            KEY_DELETE => 0x7f,
+            _ => {
+                let keymap = if self.shift { KEYMAP_SHIFT } else { KEYMAP };
+                *keymap.get(code as usize)?
+            }
+        };
+        match c {
+            0 => None,
+            // Ctrl+letra genera los caracteres de control, como Ctrl+C (0x03)
+            c if self.ctrl && c.is_ascii_alphabetic() => Some(c.to_ascii_lowercase() & 0x1f),
+            c if self.caps_lock && c.is_ascii_lowercase() => Some(c.to_ascii_uppercase()),
+            c if self.caps_lock && c.is_ascii_uppercase() => Some(c.to_ascii_lowercase()),
+            c => Some(c),
+        }
+    }
+}
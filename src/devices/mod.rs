@@ -3,7 +3,9 @@ use crate::mmu::SIFIVE_TEST_ADDRESS;
 #[cfg(target_arch = "arm")]
 #[allow(dead_code)]
 pub mod bcm2836;
+pub mod console;
 pub mod dtb;
+pub mod keymap;
 #[cfg(target_arch = "arm")]
 #[allow(dead_code)]
 pub mod mini_uart;
@@ -1,4 +1,4 @@
-use crate::{print, println};
+use crate::devices::console::Console;
 use core::fmt::{Error, Write};
 
 /// Dispositivo UART
@@ -61,22 +61,10 @@ impl Uart {
     }
 }
 
-pub fn read_uart(uart: &Uart) {
-    if let Some(c) = uart.get_char() {
-        match c {
-            8 | 127 => {
-                // Backspace
-                print!("{} {}", 8 as char, 8 as char);
-            }
-            10 | 13 => {
-                // Newline or carriage-return
-                println!();
-            }
-            _ => {
-                print!("{}", c as char);
-            }
-        }
-    }
+/// Lee un caracter del UART y lo muestra en pantalla. Devuelve el caracter
+/// leído, convirtiendo el *carriage return* en salto de línea.
+pub fn read_uart(uart: &Uart) -> Option<u8> {
+    uart.get_char().map(Console::echo)
 }
 
 /// Utilizamos el dispositivo como canal de escritura
@@ -1,25 +1,46 @@
 use crate::assembly::riscv64::wfi;
 use crate::devices::virtio::common::*;
-use crate::mmu::riscv64::{PAGE_ORDER, PAGE_SIZE};
-use crate::{print, println};
+use crate::system::process::Pid;
+use crate::system::process_table::ProcessTable;
 use alloc::boxed::Box;
-use core::mem::size_of;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::ptr::read_volatile;
 
-/// Size of Virtio Queue ring
-const VIRTIO_QUEUE_SIZE: usize = 1 << 7;
+/// Cantidad de lecturas completas que guardamos en la caché
+pub const READ_CACHE_SIZE: usize = 32;
+/// Estado de un pedido que el dispositivo todavía no completó
+const STATUS_PENDING: u8 = 0x7F;
 
-const DESCRIPTOR_HEADER_SIZE: u64 = 16;
-
-struct DeviceBuilder {
+/// Driver de bloques con interrupciones.
+/// Puede haber varios pedidos en curso: cada uno ocupa una cadena de
+/// descriptores que se libera cuando el dispositivo lo publica en el anillo
+/// `used`. Las lecturas completadas quedan en una caché chica, de donde las
+/// toman los procesos que esperaban el disco al reintentar la syscall.
+pub struct BlockDevice {
     address: DeviceAddress,
+    queue: VirtQueue,
+    /// Pedidos en curso, indexados por el primer descriptor de su cadena
+    in_flight: BTreeMap<u16, Box<BlockRequest>>,
+    /// Estado de las escrituras y flush completados, hasta que los consulta
+    /// quien los pidió
+    finished: BTreeMap<u16, u8>,
+    /// Lecturas completadas, de la más vieja a la más nueva
+    read_cache: VecDeque<CachedRead>,
+    /// Proceso en cuyo nombre se hacen las lecturas. Si hay uno, las lecturas
+    /// que no están en caché no bloquean la CPU: el proceso se despierta
+    /// cuando terminan
+    waiter: Option<Pid>,
+    read_only: bool,
+    flush_supported: bool,
 }
 
-pub struct BlockDevice {
-    address: DeviceAddress,
-    queue: Box<SplitQueue<VIRTIO_QUEUE_SIZE>>,
-    driver_idx: usize,
-    device_idx: usize,
+/// Resultado de una lectura. Si falló no hay datos, y el error se entrega una
+/// única vez
+struct CachedRead {
+    offset: u64,
+    data: Option<Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -28,102 +49,39 @@ pub struct BlockRequest {
     req_type: u32,
     reserved: u32,
     sector: u64,
-    data: *const u8,
     status: u8,
-}
-
-const fn split_queue_padding<const RING_SIZE: usize>() -> usize {
-    (size_of::<Descriptor>() * RING_SIZE - size_of::<QueueAvailable<RING_SIZE>>()) % PAGE_SIZE
-}
-
-#[repr(C)]
-struct SplitQueue<const RING_SIZE: usize>
-where
-    [(); split_queue_padding::<RING_SIZE>()]: Sized,
-{
-    descriptor_table: [Descriptor; RING_SIZE],
-    available: QueueAvailable<RING_SIZE>,
-    padding: [u8; split_queue_padding::<RING_SIZE>()],
-    used: QueueUsed<RING_SIZE>,
-}
-
-#[repr(C)]
-#[derive(Clone, Copy, Debug, Default)]
-pub struct Descriptor {
-    pub addr: u64,
-    pub len: u32,
-    pub flags: u16,
-    pub next: u16,
-}
-
-#[repr(C)]
-pub struct QueueAvailable<const RING_SIZE: usize> {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [u16; RING_SIZE],
-    pub event: u16,
-}
-
-#[repr(C)]
-#[derive(Clone, Copy, Default)]
-pub struct UsedElem {
-    pub id: u32,
-    pub len: u32,
-}
-
-#[repr(C)]
-pub struct QueueUsed<const RING_SIZE: usize> {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [UsedElem; RING_SIZE],
-    pub event: u16,
+    /// Posición en el disco, en bytes
+    offset: u64,
+    data: Vec<u8>,
+    /// Las lecturas que se cruzan con una escritura posterior no se guardan
+    /// en la caché, ya que pueden tener datos viejos
+    cacheable: bool,
+    /// Procesos a despertar cuando se complete el pedido
+    waiters: Vec<Pid>,
 }
 
 impl BlockDevice {
     pub fn new(address: DeviceAddress) -> Result<BlockDevice, DeviceError> {
         let mut status = VirtioDeviceStatus::Acknowledge as u32 | VirtioDeviceStatus::Driver as u32;
         address.write_register(VirtioMmioRegister::Status, status);
-        // Negociación de features
-        let host_features = address.read_register(VirtioMmioRegister::HostFeatures);
-
+        // Negociación de features: sólo aceptamos las que el driver sabe manejar.
         // Si packed virtqueues está soportado, debería usarse, por el momento no está
-        /*
-        self.write_register(VirtioMmioRegister::HostFeaturesSel, 1);
-        let host_features_ext = self.read_register(VirtioMmioRegister::HostFeatures);
-        self.write_register(VirtioMmioRegister::HostFeaturesSel, 0);
-        let packed_queue_support = host_features_ext & VIRTIO_F_RING_PACKED != 0;
-         */
-        let guest_features = host_features & !VIRTIO_BLK_F_RO;
-        address.write_register(VirtioMmioRegister::GuestFeatures, guest_features);
+        let guest_features = address.negotiate_features(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
         status |= VirtioDeviceStatus::FeaturesOk as u32;
-        address.write_register(VirtioMmioRegister::Status, status);
-
-        // Valido negociación
-        let status_ok = address.read_register(VirtioMmioRegister::Status);
-        if status_ok | VirtioDeviceStatus::FeaturesOk as u32 == 0 {
-            return Err(DeviceError::InitializationError);
-        }
 
-        address.write_register(VirtioMmioRegister::QueueSel, 0);
-        let queue_max_num = address.read_register(VirtioMmioRegister::QueueNumMax);
-        println!("Max queue num: {}", queue_max_num);
-        address.write_register(VirtioMmioRegister::QueueNum, VIRTIO_QUEUE_SIZE as u32);
-        address.write_register(VirtioMmioRegister::GuestPageSize, PAGE_SIZE as u32);
-        // TODO: queue de largo dinámico
-        // Para mayor simplicidad usamos VirtQueue de tamaño fijo
-        println!("Alloc queue");
-        let queue = Box::new(SplitQueue::<VIRTIO_QUEUE_SIZE>::new());
-        let queue_addr = &*queue as *const _ as u32;
-        println!("queue addr {}", queue_addr);
-        address.write_register(VirtioMmioRegister::QueuePFN, queue_addr >> PAGE_ORDER);
+        let queue = VirtQueue::new(&address, 0)?;
 
         status |= VirtioDeviceStatus::DriverOk as u32;
         address.write_register(VirtioMmioRegister::Status, status);
         let block_device = BlockDevice {
             address,
             queue,
-            driver_idx: 0,
-            device_idx: 0,
+            in_flight: BTreeMap::new(),
+            finished: BTreeMap::new(),
+            read_cache: VecDeque::new(),
+            waiter: None,
+            read_only: guest_features & VIRTIO_BLK_F_RO != 0,
+            flush_supported: guest_features & VIRTIO_BLK_F_FLUSH != 0,
         };
         Ok(block_device)
     }
@@ -132,148 +90,251 @@ impl BlockDevice {
         self.address.read_register(VirtioMmioRegister::DeviceId)
     }
 
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Proceso en cuyo nombre se hacen las lecturas de `read`. Con `None`
+    /// todas las lecturas son sincrónicas
+    pub fn set_waiter(&mut self, waiter: Option<Pid>) {
+        self.waiter = waiter;
+    }
+
+    /// Lee de forma sincrónica, sin importar si hay un proceso esperando.
     /// Buffer *must* be multiple of 512 (sector size)
     pub fn read_sync(&mut self, buffer: &mut [u8], offset: u64) -> Result<(), DeviceError> {
+        self.read_for(buffer, offset, None)
+    }
+
+    /// Lee del disco. Si hay un proceso esperando (ver `set_waiter`) y los
+    /// datos no están en caché, encola el pedido y devuelve
+    /// `DeviceError::WouldBlock`: el proceso se despierta cuando el pedido se
+    /// completa y debe volver a leer.
+    /// Buffer *must* be multiple of 512 (sector size)
+    pub fn read(&mut self, buffer: &mut [u8], offset: u64) -> Result<(), DeviceError> {
+        self.read_for(buffer, offset, self.waiter)
+    }
+
+    fn read_for(
+        &mut self,
+        buffer: &mut [u8],
+        offset: u64,
+        waiter: Option<Pid>,
+    ) -> Result<(), DeviceError> {
         if buffer.len() & (0x7F) != 0 {
             return Err(DeviceError::BufferError);
         }
-        let request = self.new_request(buffer.as_mut_ptr(), buffer.len(), offset, false);
-        while !request.is_finished() {
-            unsafe { wfi() };
+        loop {
+            if let Some(result) = self.take_cached(buffer, offset) {
+                return result;
+            }
+            let head = match self.find_read(offset, buffer.len()) {
+                Some(head) => head,
+                None => self.submit(VIRTIO_BLK_T_IN, offset, vec![0; buffer.len()])?,
+            };
+            match waiter {
+                Some(pid) => {
+                    if let Some(request) = self.in_flight.get_mut(&head) {
+                        if !request.waiters.contains(&pid) {
+                            request.waiters.push(pid);
+                        }
+                    }
+                    return Err(DeviceError::WouldBlock);
+                }
+                None => self.wait_completion(head),
+            }
+        }
+    }
+
+    /// Escribe el buffer en el disco a partir de `offset`.
+    /// Buffer *must* be multiple of 512 (sector size)
+    pub fn write_sync(&mut self, buffer: &[u8], offset: u64) -> Result<(), DeviceError> {
+        if self.read_only {
+            return Err(DeviceError::ReadOnly);
         }
-        if request.status == 0 {
-            Ok(())
-        } else {
-            Err(DeviceError::IOError)
+        if buffer.len() & (0x7F) != 0 {
+            return Err(DeviceError::BufferError);
         }
+        self.invalidate(offset, buffer.len());
+        let head = self.submit(VIRTIO_BLK_T_OUT, offset, buffer.to_vec())?;
+        self.wait_status(head)
     }
 
-    /// Creo un nuevo request en el heap ya que los descriptors necesitan la ubicación del mismo
-    fn new_request(
-        &mut self,
-        buffer: *mut u8,
-        size: usize,
-        offset: u64,
-        write: bool,
-    ) -> Box<BlockRequest> {
-        let sector = offset / 512;
-        let request = Box::new(BlockRequest::new(buffer, sector, write));
-        let header_desc = Descriptor {
-            addr: &*request as *const _ as u64,
-            len: DESCRIPTOR_HEADER_SIZE as u32,
-            flags: 0,
-            next: 0,
-        };
-        self.queue_descriptor(header_desc, true);
-        let data_desc = Descriptor {
-            addr: buffer as u64,
-            len: size as u32,
-            flags: VIRTQ_DESC_F_WRITE,
-            next: 0,
-        };
-        self.queue_descriptor(data_desc, true);
-        let status_desc = Descriptor {
-            addr: &request.status as *const u8 as u64,
-            len: 1,
-            flags: VIRTQ_DESC_F_WRITE,
-            next: 0,
-        };
-        self.queue_descriptor(status_desc, false);
-        self.queue_available();
-        self.address
-            .write_register(VirtioMmioRegister::QueueNotify, 0);
-        request
+    /// Pide al dispositivo que persista las escrituras que tenga en caché.
+    /// Si no se negoció `VIRTIO_BLK_F_FLUSH` el dispositivo escribe de forma
+    /// directa y no hay nada que hacer
+    pub fn flush(&mut self) -> Result<(), DeviceError> {
+        if !self.flush_supported || self.read_only {
+            return Ok(());
+        }
+        let head = self.submit(VIRTIO_BLK_T_FLUSH, 0, Vec::new())?;
+        self.wait_status(head)
+    }
+
+    /// Procesa los pedidos que el dispositivo publicó en el anillo `used`:
+    /// libera sus descriptores, guarda el resultado y despierta a los
+    /// procesos que los esperaban
+    pub fn handle_interrupt(&mut self) {
+        self.address.ack_interrupt();
+        while let Some(used) = self.queue.pop_used() {
+            let head = used.id as u16;
+            if let Some(request) = self.in_flight.remove(&head) {
+                self.complete(head, *request);
+            }
+        }
     }
 
-    fn queue_descriptor(&mut self, mut desc: Descriptor, has_next: bool) {
-        if has_next {
-            desc.next = ((self.driver_idx + 1) % VIRTIO_QUEUE_SIZE) as u16;
-            desc.flags |= VIRTQ_DESC_F_NEXT;
+    fn complete(&mut self, head: u16, request: BlockRequest) {
+        for pid in &request.waiters {
+            ProcessTable::wake(*pid);
+        }
+        let status = unsafe { read_volatile(&request.status) };
+        if request.req_type != VIRTIO_BLK_T_IN {
+            self.finished.insert(head, status);
+        } else if request.cacheable {
+            let data = (status == 0).then_some(request.data);
+            self.cache_read(request.offset, data);
         }
-        self.queue.descriptor_table[self.driver_idx] = desc;
-        self.driver_idx = (self.driver_idx + 1) % VIRTIO_QUEUE_SIZE;
     }
 
-    fn queue_available(&mut self) {
-        let available_idx = self.queue.available.idx;
-        // self.driver_idx apunta a status
-        self.queue.available.ring[available_idx as usize] =
-            (self.driver_idx as u16).wrapping_sub(3);
-        self.queue.available.idx = available_idx.wrapping_add(1);
+    /// Espera la interrupción del dispositivo y procesa los pedidos completados
+    fn wait_interrupt(&mut self) {
+        unsafe { wfi() };
+        self.handle_interrupt();
     }
-}
 
-impl BlockRequest {
-    fn new(data: *mut u8, sector: u64, write: bool) -> Self {
-        let req_type = if write {
-            VIRTIO_BLK_T_OUT
-        } else {
-            VIRTIO_BLK_T_IN
-        };
-        BlockRequest {
-            req_type,
-            reserved: 0,
-            sector,
-            data,
-            status: 0x7F,
+    fn wait_completion(&mut self, head: u16) {
+        while self.in_flight.contains_key(&head) {
+            self.wait_interrupt();
         }
     }
 
-    pub fn is_finished(&self) -> bool {
-        let status;
-        unsafe {
-            status = read_volatile(&self.status);
+    /// Espera una escritura o flush y devuelve su resultado
+    fn wait_status(&mut self, head: u16) -> Result<(), DeviceError> {
+        self.wait_completion(head);
+        match self.finished.remove(&head) {
+            Some(0) => Ok(()),
+            _ => Err(DeviceError::IOError),
+        }
+    }
+
+    /// Copia la lectura de `offset` si está en la caché
+    fn take_cached(&mut self, buffer: &mut [u8], offset: u64) -> Option<Result<(), DeviceError>> {
+        let position = self.read_cache.iter().position(|cached| {
+            cached.offset == offset
+                && cached
+                    .data
+                    .as_ref()
+                    .is_none_or(|data| data.len() == buffer.len())
+        })?;
+        match &self.read_cache[position].data {
+            Some(data) => {
+                buffer.copy_from_slice(data);
+                Some(Ok(()))
+            }
+            None => {
+                self.read_cache.remove(position);
+                Some(Err(DeviceError::IOError))
+            }
         }
-        status != 0x7F
     }
-}
 
-impl<const RING_SIZE: usize> SplitQueue<RING_SIZE>
-where
-    [(); split_queue_padding::<RING_SIZE>()]: Sized,
-{
-    fn new() -> Self {
-        Self::default()
+    fn cache_read(&mut self, offset: u64, data: Option<Vec<u8>>) {
+        self.read_cache.retain(|cached| cached.offset != offset);
+        self.read_cache.push_back(CachedRead { offset, data });
+        if self.read_cache.len() > READ_CACHE_SIZE {
+            self.read_cache.pop_front();
+        }
     }
-}
-impl<const RING_SIZE: usize> Default for QueueAvailable<RING_SIZE>
-where
-    [(); split_queue_padding::<RING_SIZE>()]: Sized,
-{
-    fn default() -> Self {
-        Self {
-            flags: 0,
-            event: 0,
-            idx: 0,
-            ring: [0; RING_SIZE],
+
+    /// Descarta las lecturas que se solapan con una escritura
+    fn invalidate(&mut self, offset: u64, size: usize) {
+        let end = offset + size as u64;
+        let overlaps = |start: u64, len: usize| start < end && offset < start + len as u64;
+        self.read_cache.retain(|cached| {
+            let len = cached.data.as_ref().map_or(0, Vec::len);
+            !overlaps(cached.offset, len)
+        });
+        for request in self.in_flight.values_mut() {
+            if request.req_type == VIRTIO_BLK_T_IN && overlaps(request.offset, request.data.len()) {
+                request.cacheable = false;
+            }
         }
     }
-}
 
-impl<const RING_SIZE: usize> Default for QueueUsed<RING_SIZE>
-where
-    [(); split_queue_padding::<RING_SIZE>()]: Sized,
-{
-    fn default() -> Self {
-        Self {
+    /// Busca una lectura en curso de los mismos datos
+    fn find_read(&self, offset: u64, size: usize) -> Option<u16> {
+        self.in_flight
+            .iter()
+            .find(|(_, request)| {
+                request.req_type == VIRTIO_BLK_T_IN
+                    && request.cacheable
+                    && request.offset == offset
+                    && request.data.len() == size
+            })
+            .map(|(head, _)| *head)
+    }
+
+    /// Encola un pedido y devuelve el índice de su primer descriptor.
+    /// El request vive en el heap ya que los descriptores necesitan la ubicación del mismo.
+    /// Los flush no llevan datos
+    fn submit(&mut self, req_type: u32, offset: u64, data: Vec<u8>) -> Result<u16, DeviceError> {
+        let descriptors_needed = if data.is_empty() { 2 } else { 3 };
+        while self.queue.free_descriptors() < descriptors_needed {
+            if self.in_flight.is_empty() {
+                return Err(DeviceError::BufferError);
+            }
+            self.wait_interrupt();
+        }
+        let request = Box::new(BlockRequest::new(req_type, offset, data));
+        let mut chain = vec![Descriptor {
+            addr: &*request as *const _ as u64,
+            len: DESCRIPTOR_HEADER_SIZE as u32,
             flags: 0,
-            event: 0,
-            idx: 0,
-            ring: [UsedElem::default(); RING_SIZE],
+            next: 0,
+        }];
+        if !request.data.is_empty() {
+            // En las lecturas el dispositivo escribe el buffer, en las escrituras lo lee
+            let data_flags = if req_type == VIRTIO_BLK_T_IN {
+                VIRTQ_DESC_F_WRITE
+            } else {
+                0
+            };
+            chain.push(Descriptor {
+                addr: request.data.as_ptr() as u64,
+                len: request.data.len() as u32,
+                flags: data_flags,
+                next: 0,
+            });
         }
+        chain.push(Descriptor {
+            addr: &request.status as *const u8 as u64,
+            len: 1,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        });
+        let head = self
+            .queue
+            .push_chain(&chain)
+            .ok_or(DeviceError::BufferError)?;
+        self.in_flight.insert(head, request);
+        self.address
+            .write_register(VirtioMmioRegister::QueueNotify, 0);
+        Ok(head)
     }
 }
 
-impl<const RING_SIZE: usize> Default for SplitQueue<RING_SIZE>
-where
-    [(); split_queue_padding::<RING_SIZE>()]: Sized,
-{
-    fn default() -> Self {
-        Self {
-            descriptor_table: [Descriptor::default(); RING_SIZE],
-            available: Default::default(),
-            padding: [0; split_queue_padding::<RING_SIZE>()],
-            used: Default::default(),
+impl BlockRequest {
+    fn new(req_type: u32, offset: u64, data: Vec<u8>) -> Self {
+        BlockRequest {
+            req_type,
+            reserved: 0,
+            sector: offset / 512,
+            status: STATUS_PENDING,
+            offset,
+            data,
+            cacheable: true,
+            waiters: Vec::new(),
         }
     }
 }
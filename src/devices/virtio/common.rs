@@ -1,7 +1,18 @@
 use crate::devices::virtio::block_device::BlockDevice;
+use crate::devices::virtio::console_device::ConsoleDevice;
+use crate::devices::virtio::input_device::InputDevice;
+use crate::devices::virtio::net_device::NetDevice;
+use crate::devices::virtio::rng_device::RngDevice;
 use crate::devices::DeviceId;
+use crate::mmu::alloc_stats::AllocTag;
+use crate::mmu::riscv64::{PAGE_ORDER, PAGE_SIZE};
 use crate::{print, println};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::cell::{RefCell, UnsafeCell};
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
 
 const MMIO_VIRTIO_START: usize = 0x1000_1000;
 const MMIO_VIRTIO_DEVICES: usize = 8;
@@ -9,26 +20,44 @@ const MMIO_VIRTIO_STRIDE: usize = 0x1000;
 const MSG_LEN: usize = 13;
 
 #[repr(usize)]
-/// Layout from virtio-v1.1
+/// Layout from virtio-v1.1. `GuestPageSize` and `QueuePFN` only exist in the
+/// legacy interface (version 1), the `Queue*Low/High` and `QueueReady`
+/// registers only in the modern one (version 2)
 pub enum VirtioMmioRegister {
     MagicValue = 0x00,
     Version = 0x04,
     DeviceId = 0x08,
     VendorId = 0x0c,
     HostFeatures = 0x10,
-    //HostFeaturesSel = 0x14,
+    HostFeaturesSel = 0x14,
     GuestFeatures = 0x20,
-    //GuestFeaturesSel = 0x24,
+    GuestFeaturesSel = 0x24,
     GuestPageSize = 0x28,
     QueueSel = 0x030,
     QueueNumMax = 0x034,
     QueueNum = 0x038,
     //QueueAlign = 0x03c,
     QueuePFN = 0x040,
+    QueueReady = 0x044,
     QueueNotify = 0x050,
+    InterruptStatus = 0x060,
+    InterruptAck = 0x064,
     Status = 0x70,
+    QueueDescLow = 0x080,
+    QueueDescHigh = 0x084,
+    QueueDriverLow = 0x090,
+    QueueDriverHigh = 0x094,
+    QueueDeviceLow = 0x0a0,
+    QueueDeviceHigh = 0x0a4,
+    /// Comienzo del espacio de configuración propio de cada tipo de dispositivo
+    Config = 0x100,
 }
 
+/// Versión de la interfaz MMIO legacy
+const VIRTIO_MMIO_LEGACY: u32 = 1;
+/// Versión de la interfaz MMIO moderna
+const VIRTIO_MMIO_MODERN: u32 = 2;
+
 #[repr(u32)]
 /// Status in virtio-v1.1
 pub enum VirtioDeviceStatus {
@@ -37,6 +66,7 @@ pub enum VirtioDeviceStatus {
     Driver = 0x02,
     DriverOk = 0x04,
     FeaturesOk = 0x8,
+    Failed = 0x80,
 }
 
 #[derive(Debug)]
@@ -47,12 +77,44 @@ pub enum DeviceError {
     UnsupportedDevice(u32),
     BufferError,
     IOError,
+    /// No quedan bloques o inodos libres
+    NoSpace,
+    EntryExists,
+    /// El dispositivo no admite escrituras
+    ReadOnly,
+    /// El pedido quedó en curso, el proceso que espera se despierta cuando
+    /// se complete
+    WouldBlock,
 }
 
+pub const NET_DEVICE_ID: u32 = 1;
 pub const BLOCK_DEVICE_ID: u32 = 2;
+pub const CONSOLE_DEVICE_ID: u32 = 3;
+pub const RNG_DEVICE_ID: u32 = 4;
+pub const INPUT_DEVICE_ID: u32 = 18;
+
+/// Tipo de dispositivo, según el registro `DeviceId`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Net,
+    Block,
+    Console,
+    Rng,
+    Input,
+    Other(u32),
+}
 
 /// Readonly feature
-pub const VIRTIO_BLK_F_RO: u32 = 0x20;
+pub const VIRTIO_BLK_F_RO: u64 = 0x20;
+/// Cache flush command support
+pub const VIRTIO_BLK_F_FLUSH: u64 = 0x200;
+/// El dispositivo de red tiene una dirección MAC en el espacio de configuración
+pub const VIRTIO_NET_F_MAC: u64 = 0x20;
+/// La consola tiene varios puertos, que se administran con una cola de control
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 0x2;
+/// El dispositivo cumple la especificación 1.0 o posterior. Los dispositivos
+/// modernos no aceptan drivers que no lo negocien
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
 //const VIRTIO_F_RING_PACKED: u32 = 0x4;
 
 pub const VIRTQ_DESC_F_NEXT: u16 = 1;
@@ -61,6 +123,7 @@ pub const VIRTQ_DESC_F_WRITE: u16 = 2;
 
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 
 pub const DESCRIPTOR_HEADER_SIZE: u64 = 16;
 
@@ -73,8 +136,11 @@ struct DeviceBuilder {
     address: DeviceAddress,
 }
 
+/// Size of Virtio Queue ring
+pub const VIRTIO_QUEUE_SIZE: usize = 1 << 7;
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct Descriptor {
     pub addr: u64,
     pub len: u32,
@@ -91,6 +157,7 @@ pub struct QueueAvailable<const RING_SIZE: usize> {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy, Default)]
 pub struct UsedElem {
     pub id: u32,
     pub len: u32,
@@ -104,6 +171,34 @@ pub struct QueueUsed<const RING_SIZE: usize> {
     pub event: u16,
 }
 
+const fn split_queue_padding<const RING_SIZE: usize>() -> usize {
+    (size_of::<Descriptor>() * RING_SIZE - size_of::<QueueAvailable<RING_SIZE>>()) % PAGE_SIZE
+}
+
+#[repr(C)]
+struct SplitQueue<const RING_SIZE: usize>
+where
+    [(); split_queue_padding::<RING_SIZE>()]: Sized,
+{
+    descriptor_table: [Descriptor; RING_SIZE],
+    available: QueueAvailable<RING_SIZE>,
+    padding: [u8; split_queue_padding::<RING_SIZE>()],
+    used: QueueUsed<RING_SIZE>,
+}
+
+/// # Virtqueue
+/// Cola *split* compartida con un dispositivo. Lleva la cuenta de los
+/// descriptores libres: cada pedido ocupa una cadena de descriptores que
+/// vuelve a la lista de libres cuando el dispositivo la publica en el anillo
+/// `used`.
+pub struct VirtQueue {
+    queue: Box<SplitQueue<VIRTIO_QUEUE_SIZE>>,
+    /// Índices de los descriptores libres de la tabla
+    free_descriptors: Vec<u16>,
+    /// Próxima posición del anillo `used` que tenemos que procesar
+    used_idx: u16,
+}
+
 impl DeviceAddress {
     pub fn new(address: usize) -> Self {
         Self { address }
@@ -118,6 +213,257 @@ impl DeviceAddress {
         let address = (self.address + register as usize) as *mut u32;
         unsafe { address.write_volatile(value) }
     }
+
+    /// Lee un byte del espacio de configuración del dispositivo
+    pub fn read_config(&self, offset: usize) -> u8 {
+        let address = (self.address + VirtioMmioRegister::Config as usize + offset) as *const u8;
+        unsafe { address.read_volatile() }
+    }
+
+    /// Escribe un byte del espacio de configuración del dispositivo
+    pub fn write_config(&self, offset: usize, value: u8) {
+        let address = (self.address + VirtioMmioRegister::Config as usize + offset) as *mut u8;
+        unsafe { address.write_volatile(value) }
+    }
+
+    /// Escribe un valor de 64 bits en un par de registros `Low`/`High`
+    fn write_register_u64(&self, low: VirtioMmioRegister, high: VirtioMmioRegister, value: u64) {
+        self.write_register(low, value as u32);
+        self.write_register(high, (value >> 32) as u32);
+    }
+
+    /// `true` si el dispositivo usa la interfaz legacy (versión 1)
+    pub fn is_legacy(&self) -> bool {
+        self.read_register(VirtioMmioRegister::Version) == VIRTIO_MMIO_LEGACY
+    }
+
+    /// Lee los 64 bits de features que ofrece el dispositivo
+    fn read_host_features(&self) -> u64 {
+        self.write_register(VirtioMmioRegister::HostFeaturesSel, 0);
+        let low = self.read_register(VirtioMmioRegister::HostFeatures);
+        self.write_register(VirtioMmioRegister::HostFeaturesSel, 1);
+        let high = self.read_register(VirtioMmioRegister::HostFeatures);
+        ((high as u64) << 32) | low as u64
+    }
+
+    fn write_guest_features(&self, features: u64) {
+        self.write_register(VirtioMmioRegister::GuestFeaturesSel, 0);
+        self.write_register(VirtioMmioRegister::GuestFeatures, features as u32);
+        self.write_register(VirtioMmioRegister::GuestFeaturesSel, 1);
+        self.write_register(VirtioMmioRegister::GuestFeatures, (features >> 32) as u32);
+    }
+
+    /// Negocia las features: acepta las de `supported` que ofrece el
+    /// dispositivo (más `VIRTIO_F_VERSION_1` en los dispositivos modernos),
+    /// escribe el status `FEATURES_OK` y valida que el dispositivo lo acepte.
+    /// Devuelve las features aceptadas
+    pub fn negotiate_features(&self, supported: u64) -> Result<u64, DeviceError> {
+        let mut supported = supported;
+        if !self.is_legacy() {
+            supported |= VIRTIO_F_VERSION_1;
+        }
+        let guest_features = self.read_host_features() & supported;
+        if !self.is_legacy() && guest_features & VIRTIO_F_VERSION_1 == 0 {
+            self.fail();
+            return Err(DeviceError::InitializationError);
+        }
+        self.write_guest_features(guest_features);
+        let status = self.read_register(VirtioMmioRegister::Status);
+        self.write_register(
+            VirtioMmioRegister::Status,
+            status | VirtioDeviceStatus::FeaturesOk as u32,
+        );
+        // Valido negociación
+        let status_ok = self.read_register(VirtioMmioRegister::Status);
+        if status_ok & VirtioDeviceStatus::FeaturesOk as u32 == 0 {
+            self.fail();
+            return Err(DeviceError::InitializationError);
+        }
+        Ok(guest_features)
+    }
+
+    /// Avisa al dispositivo que el driver no puede usarlo
+    fn fail(&self) {
+        let status = self.read_register(VirtioMmioRegister::Status);
+        self.write_register(
+            VirtioMmioRegister::Status,
+            status | VirtioDeviceStatus::Failed as u32,
+        );
+    }
+
+    /// Confirma las interrupciones pendientes, para que el dispositivo baje
+    /// la línea de interrupción
+    pub fn ack_interrupt(&self) {
+        let status = self.read_register(VirtioMmioRegister::InterruptStatus);
+        self.write_register(VirtioMmioRegister::InterruptAck, status);
+    }
+}
+
+impl VirtQueue {
+    /// Crea la cola y la registra en el dispositivo como la cola `index`
+    pub fn new(address: &DeviceAddress, index: u32) -> Result<Self, DeviceError> {
+        address.write_register(VirtioMmioRegister::QueueSel, index);
+        let queue_max_num = address.read_register(VirtioMmioRegister::QueueNumMax);
+        println!("Max queue num: {}", queue_max_num);
+        // TODO: queue de largo dinámico
+        // Para mayor simplicidad usamos VirtQueue de tamaño fijo
+        if (queue_max_num as usize) < VIRTIO_QUEUE_SIZE {
+            return Err(DeviceError::InitializationError);
+        }
+        address.write_register(VirtioMmioRegister::QueueNum, VIRTIO_QUEUE_SIZE as u32);
+        let virt_queue = VirtQueue::unregistered();
+        let queue = &virt_queue.queue;
+        let queue_addr = &**queue as *const _ as u64;
+        println!("queue addr {}", queue_addr);
+        if address.is_legacy() {
+            // La interfaz legacy sólo recibe el número de página de la cola,
+            // las partes de la misma están en posiciones fijas
+            address.write_register(VirtioMmioRegister::GuestPageSize, PAGE_SIZE as u32);
+            address.write_register(
+                VirtioMmioRegister::QueuePFN,
+                (queue_addr >> PAGE_ORDER) as u32,
+            );
+        } else {
+            address.write_register_u64(
+                VirtioMmioRegister::QueueDescLow,
+                VirtioMmioRegister::QueueDescHigh,
+                &queue.descriptor_table as *const _ as u64,
+            );
+            address.write_register_u64(
+                VirtioMmioRegister::QueueDriverLow,
+                VirtioMmioRegister::QueueDriverHigh,
+                &queue.available as *const _ as u64,
+            );
+            address.write_register_u64(
+                VirtioMmioRegister::QueueDeviceLow,
+                VirtioMmioRegister::QueueDeviceHigh,
+                &queue.used as *const _ as u64,
+            );
+            address.write_register(VirtioMmioRegister::QueueReady, 1);
+        }
+        Ok(virt_queue)
+    }
+
+    /// Crea la cola sin registrarla en ningún dispositivo
+    pub(crate) fn unregistered() -> Self {
+        Self {
+            queue: Box::new(SplitQueue::<VIRTIO_QUEUE_SIZE>::default()),
+            free_descriptors: (0..VIRTIO_QUEUE_SIZE as u16).rev().collect(),
+            used_idx: 0,
+        }
+    }
+
+    /// Publica `head` en el anillo `used` como lo haría el dispositivo
+    #[cfg(test)]
+    pub(crate) fn complete(&mut self, head: u16, len: u32) {
+        let used_idx = self.queue.used.idx;
+        self.queue.used.ring[used_idx as usize % VIRTIO_QUEUE_SIZE] = UsedElem {
+            id: head as u32,
+            len,
+        };
+        self.queue.used.idx = used_idx.wrapping_add(1);
+    }
+
+    /// Cantidad de descriptores libres
+    pub fn free_descriptors(&self) -> usize {
+        self.free_descriptors.len()
+    }
+
+    /// Copia la cadena en descriptores libres, enlazados entre sí, y la
+    /// publica en el anillo `available`. Devuelve el índice del primer
+    /// descriptor, que identifica al pedido en el anillo `used`, o `None` si
+    /// no hay descriptores suficientes
+    pub fn push_chain(&mut self, chain: &[Descriptor]) -> Option<u16> {
+        if chain.is_empty() || chain.len() > self.free_descriptors.len() {
+            return None;
+        }
+        let indexes = self
+            .free_descriptors
+            .split_off(self.free_descriptors.len() - chain.len());
+        for (i, desc) in chain.iter().enumerate() {
+            let mut desc = *desc;
+            match indexes.get(i + 1) {
+                Some(next) => {
+                    desc.next = *next;
+                    desc.flags |= VIRTQ_DESC_F_NEXT;
+                }
+                None => desc.flags &= !VIRTQ_DESC_F_NEXT,
+            }
+            self.queue.descriptor_table[indexes[i] as usize] = desc;
+        }
+        let head = indexes[0];
+        // El índice `idx` crece indefinidamente, la posición en el anillo es su módulo
+        let available_idx = self.queue.available.idx;
+        self.queue.available.ring[available_idx as usize % VIRTIO_QUEUE_SIZE] = head;
+        // El dispositivo tiene que ver la cadena antes que el nuevo índice
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(&mut self.queue.available.idx, available_idx.wrapping_add(1)) };
+        Some(head)
+    }
+
+    /// Saca el próximo elemento del anillo `used` y libera su cadena de
+    /// descriptores. Devuelve `None` si el dispositivo no completó más pedidos
+    pub fn pop_used(&mut self) -> Option<UsedElem> {
+        let device_idx = unsafe { read_volatile(&self.queue.used.idx) };
+        if self.used_idx == device_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let position = self.used_idx as usize % VIRTIO_QUEUE_SIZE;
+        let used = unsafe { read_volatile(&self.queue.used.ring[position]) };
+        self.used_idx = self.used_idx.wrapping_add(1);
+        self.free_chain(used.id as u16);
+        Some(used)
+    }
+
+    /// Devuelve a la lista de libres la cadena que empieza en `head`
+    fn free_chain(&mut self, head: u16) {
+        let mut idx = head;
+        loop {
+            self.free_descriptors.push(idx);
+            let desc = &self.queue.descriptor_table[idx as usize];
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            idx = desc.next;
+        }
+    }
+}
+
+impl<const RING_SIZE: usize> Default for QueueAvailable<RING_SIZE> {
+    fn default() -> Self {
+        Self {
+            flags: 0,
+            event: 0,
+            idx: 0,
+            ring: [0; RING_SIZE],
+        }
+    }
+}
+
+impl<const RING_SIZE: usize> Default for QueueUsed<RING_SIZE> {
+    fn default() -> Self {
+        Self {
+            flags: 0,
+            event: 0,
+            idx: 0,
+            ring: [UsedElem::default(); RING_SIZE],
+        }
+    }
+}
+
+impl<const RING_SIZE: usize> Default for SplitQueue<RING_SIZE>
+where
+    [(); split_queue_padding::<RING_SIZE>()]: Sized,
+{
+    fn default() -> Self {
+        Self {
+            descriptor_table: [Descriptor::default(); RING_SIZE],
+            available: Default::default(),
+            padding: [0; split_queue_padding::<RING_SIZE>()],
+            used: Default::default(),
+        }
+    }
 }
 
 impl DeviceBuilder {
@@ -128,11 +474,13 @@ impl DeviceBuilder {
 
     pub fn valid(&self) -> bool {
         // 0x74726976 -> "triv" (virt little endian)
+        let version = self.address.read_register(VirtioMmioRegister::Version);
         self.address.read_register(VirtioMmioRegister::MagicValue) == 0x74726976
-            && self.address.read_register(VirtioMmioRegister::Version) == 0x1
+            && (version == VIRTIO_MMIO_LEGACY || version == VIRTIO_MMIO_MODERN)
     }
 
-    pub fn init_driver(self) -> Result<BlockDevice, DeviceError> {
+    pub fn init_driver(self) -> Result<VirtioDevice, DeviceError> {
+        let _tag = AllocTag::Virtio.enter();
         if !self.valid() {
             return Err(DeviceError::InvalidDevice);
         }
@@ -143,15 +491,104 @@ impl DeviceBuilder {
             VirtioDeviceStatus::Acknowledge as u32,
         );
         let device_id = self.address.read_register(VirtioMmioRegister::DeviceId);
-        match device_id {
-            BLOCK_DEVICE_ID => BlockDevice::new(self.address),
+        match DeviceType::from(device_id) {
+            DeviceType::Block => {
+                let device = BlockDevice::new(self.address)?;
+                Ok(VirtioDevice::Block(RefCell::new(device)))
+            }
+            DeviceType::Net => {
+                let device = NetDevice::new(self.address)?;
+                Ok(VirtioDevice::Net(RefCell::new(device)))
+            }
+            DeviceType::Console => {
+                let device = ConsoleDevice::new(self.address)?;
+                Ok(VirtioDevice::Console(RefCell::new(device)))
+            }
+            DeviceType::Rng => {
+                let device = RngDevice::new(self.address)?;
+                Ok(VirtioDevice::Rng(RefCell::new(device)))
+            }
+            DeviceType::Input => {
+                let device = InputDevice::new(self.address)?;
+                println!("Input device: {}", device.name());
+                Ok(VirtioDevice::Input(RefCell::new(device)))
+            }
             _ => Err(DeviceError::UnsupportedDevice(device_id)),
         }
     }
 }
 
+impl From<u32> for DeviceType {
+    fn from(device_id: u32) -> Self {
+        match device_id {
+            NET_DEVICE_ID => DeviceType::Net,
+            BLOCK_DEVICE_ID => DeviceType::Block,
+            CONSOLE_DEVICE_ID => DeviceType::Console,
+            RNG_DEVICE_ID => DeviceType::Rng,
+            INPUT_DEVICE_ID => DeviceType::Input,
+            _ => DeviceType::Other(device_id),
+        }
+    }
+}
+
+/// Dispositivo VirtIO con su driver
+pub enum VirtioDevice {
+    Block(RefCell<BlockDevice>),
+    Net(RefCell<NetDevice>),
+    Console(RefCell<ConsoleDevice>),
+    Rng(RefCell<RngDevice>),
+    Input(RefCell<InputDevice>),
+}
+
+impl VirtioDevice {
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            VirtioDevice::Block(_) => DeviceType::Block,
+            VirtioDevice::Net(_) => DeviceType::Net,
+            VirtioDevice::Console(_) => DeviceType::Console,
+            VirtioDevice::Rng(_) => DeviceType::Rng,
+            VirtioDevice::Input(_) => DeviceType::Input,
+        }
+    }
+
+    /// Procesa los pedidos completados por el dispositivo
+    fn handle_interrupt(&self) {
+        match self {
+            VirtioDevice::Block(device) => {
+                // Si el kernel está usando el dispositivo, él mismo procesa
+                // los pedidos completados
+                if let Ok(mut device) = device.try_borrow_mut() {
+                    device.handle_interrupt();
+                }
+            }
+            VirtioDevice::Net(device) => {
+                if let Ok(mut device) = device.try_borrow_mut() {
+                    device.handle_interrupt();
+                }
+            }
+            VirtioDevice::Console(device) => {
+                if let Ok(mut device) = device.try_borrow_mut() {
+                    device.handle_interrupt();
+                }
+            }
+            VirtioDevice::Rng(device) => {
+                if let Ok(mut device) = device.try_borrow_mut() {
+                    device.handle_interrupt();
+                }
+            }
+            VirtioDevice::Input(device) => {
+                if let Ok(mut device) = device.try_borrow_mut() {
+                    device.handle_interrupt();
+                }
+            }
+        }
+    }
+}
+
+/// Dispositivos indexados por su ranura MMIO, que usamos como `DeviceId`: el id
+/// no cambia entre arranques mientras no cambie la línea de comandos de QEMU
 struct DeviceList {
-    devices: [Option<RefCell<BlockDevice>>; 8],
+    devices: [Option<VirtioDevice>; MMIO_VIRTIO_DEVICES],
 }
 
 pub struct DeviceManager {
@@ -162,26 +599,41 @@ unsafe impl Sync for DeviceManager {}
 
 static DEVICE_MANAGER: DeviceManager = DeviceManager::empty();
 
+const NO_DEVICE: Option<VirtioDevice> = None;
+
 impl DeviceManager {
     const fn empty() -> Self {
         let device_list = DeviceList {
-            devices: [None, None, None, None, None, None, None, None],
+            devices: [NO_DEVICE; MMIO_VIRTIO_DEVICES],
         };
         let device_list = UnsafeCell::new(device_list);
         Self { device_list }
     }
 
+    fn device_list() -> &'static DeviceList {
+        unsafe { &*DEVICE_MANAGER.device_list.get() }
+    }
+
     /// Sondeo de dispositivos en la memoria Virtio
     pub fn init() {
-        let mut devices = [None, None, None, None, None, None, None, None];
-        #[allow(clippy::needless_range_loop)]
-        for i in 0..MMIO_VIRTIO_DEVICES {
+        let mut devices = [NO_DEVICE; MMIO_VIRTIO_DEVICES];
+        for (i, slot) in devices.iter_mut().enumerate() {
             let address = MMIO_VIRTIO_START + i * MMIO_VIRTIO_STRIDE;
             let builder = DeviceBuilder::new(address);
-            if let Ok(device) = builder.init_driver() {
-                println!("VirtIO device found at 0x{:x}", address);
-                println!("Device type: {}", device.get_id());
-                devices[i] = Some(RefCell::new(device));
+            match builder.init_driver() {
+                Ok(device) => {
+                    println!("VirtIO device found at 0x{:x}", address);
+                    println!("Device {}: {:?}", i, device.device_type());
+                    *slot = Some(device);
+                }
+                Err(DeviceError::UnsupportedDevice(device_id)) => {
+                    println!(
+                        "VirtIO device at 0x{:x} has no driver: {:?}",
+                        address,
+                        DeviceType::from(device_id)
+                    );
+                }
+                Err(_) => {}
             }
         }
         let list = DEVICE_MANAGER.device_list.get();
@@ -190,10 +642,63 @@ impl DeviceManager {
         }
     }
 
-    /// TODO: How do I identify my devices?
-    pub fn get_device(_device: DeviceId) -> Option<&'static RefCell<BlockDevice>> {
-        let mgr = unsafe { &*DEVICE_MANAGER.device_list.get() };
-        let device = mgr.devices.iter().flatten().next();
-        device
+    /// Atiende la interrupción de un dispositivo. En QEMU el dispositivo de la
+    /// ranura `i` usa la interrupción `i + 1`
+    pub fn handle_interrupt(interrupt: u32) {
+        let _tag = AllocTag::Virtio.enter();
+        let slot = (interrupt as usize).wrapping_sub(1) as DeviceId;
+        if let Some(device) = DeviceManager::get_device(slot) {
+            device.handle_interrupt();
+        }
+    }
+
+    pub fn get_device(device_id: DeviceId) -> Option<&'static VirtioDevice> {
+        DeviceManager::device_list()
+            .devices
+            .get(device_id as usize)
+            .and_then(Option::as_ref)
+    }
+
+    /// Id del `index`-ésimo dispositivo de tipo `device_type`, en orden de ranura
+    pub fn find(device_type: DeviceType, index: usize) -> Option<DeviceId> {
+        DeviceManager::device_list()
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(_, device)| {
+                device
+                    .as_ref()
+                    .is_some_and(|device| device.device_type() == device_type)
+            })
+            .nth(index)
+            .map(|(slot, _)| slot as DeviceId)
+    }
+
+    pub fn get_block_device(device_id: DeviceId) -> Option<&'static RefCell<BlockDevice>> {
+        match DeviceManager::get_device(device_id)? {
+            VirtioDevice::Block(device) => Some(device),
+            _ => None,
+        }
+    }
+
+    pub fn get_net_device(device_id: DeviceId) -> Option<&'static RefCell<NetDevice>> {
+        match DeviceManager::get_device(device_id)? {
+            VirtioDevice::Net(device) => Some(device),
+            _ => None,
+        }
+    }
+
+    pub fn get_console_device(device_id: DeviceId) -> Option<&'static RefCell<ConsoleDevice>> {
+        match DeviceManager::get_device(device_id)? {
+            VirtioDevice::Console(device) => Some(device),
+            _ => None,
+        }
+    }
+
+    pub fn get_rng_device(device_id: DeviceId) -> Option<&'static RefCell<RngDevice>> {
+        match DeviceManager::get_device(device_id)? {
+            VirtioDevice::Rng(device) => Some(device),
+            _ => None,
+        }
     }
 }
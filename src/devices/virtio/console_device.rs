@@ -0,0 +1,399 @@
+//! # Driver de consola VirtIO
+//! Cada puerto de la consola tiene una cola de recepción, en la que dejamos
+//! buffers vacíos que el dispositivo llena con lo que escribe el host, y una de
+//! transmisión. Si el dispositivo ofrece `VIRTIO_CONSOLE_F_MULTIPORT`, el host
+//! agrega y nombra los puertos con mensajes en un par de colas de control.
+use crate::devices::virtio::common::*;
+use crate::system::process::Pid;
+use crate::system::process_table::ProcessTable;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+/// Cantidad máxima de puertos que atendemos, aunque el dispositivo ofrezca más
+const MAX_PORTS: usize = 4;
+/// Buffers de recepción que le dejamos al dispositivo en cada cola
+const RX_BUFFERS: usize = 8;
+const RX_BUFFER_SIZE: usize = 512;
+/// Máximo de bytes por descriptor al escribir
+const TX_CHUNK_SIZE: usize = 4096;
+/// Bytes recibidos que guardamos por puerto hasta que alguien los lea
+const MAX_INPUT_SIZE: usize = 0x4000;
+/// Offset de `max_nr_ports` en el espacio de configuración
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+const CONTROL_RECEIVE_QUEUE: u32 = 2;
+const CONTROL_TRANSMIT_QUEUE: u32 = 3;
+/// Tamaño de `struct virtio_console_control`: id (u32), evento y valor (u16)
+const CONTROL_MESSAGE_SIZE: usize = 8;
+
+// Eventos de los mensajes de control
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+struct ControlMessage {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+impl ControlMessage {
+    fn parse(data: &[u8]) -> Option<ControlMessage> {
+        if data.len() < CONTROL_MESSAGE_SIZE {
+            return None;
+        }
+        Some(ControlMessage {
+            id: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            event: u16::from_le_bytes([data[4], data[5]]),
+            value: u16::from_le_bytes([data[6], data[7]]),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(CONTROL_MESSAGE_SIZE);
+        data.extend_from_slice(&self.id.to_le_bytes());
+        data.extend_from_slice(&self.event.to_le_bytes());
+        data.extend_from_slice(&self.value.to_le_bytes());
+        data
+    }
+}
+
+/// Cola de recepción con sus buffers en poder del dispositivo
+struct ReceiveQueue {
+    index: u32,
+    queue: VirtQueue,
+    buffers: BTreeMap<u16, Vec<u8>>,
+}
+
+impl ReceiveQueue {
+    fn new(address: &DeviceAddress, index: u32) -> Result<Self, DeviceError> {
+        let queue = VirtQueue::new(address, index)?;
+        Ok(Self {
+            index,
+            queue,
+            buffers: BTreeMap::new(),
+        })
+    }
+
+    fn post_buffers(&mut self) {
+        while self.buffers.len() < RX_BUFFERS {
+            let buffer = vec![0; RX_BUFFER_SIZE];
+            let desc = Descriptor {
+                addr: buffer.as_ptr() as u64,
+                len: buffer.len() as u32,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            };
+            let Some(head) = self.queue.push_chain(&[desc]) else {
+                break;
+            };
+            self.buffers.insert(head, buffer);
+        }
+    }
+
+    /// Saca los buffers que llenó el dispositivo, recortados a lo recibido
+    fn pop_received(&mut self) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        while let Some(used) = self.queue.pop_used() {
+            if let Some(mut buffer) = self.buffers.remove(&(used.id as u16)) {
+                buffer.truncate(used.len as usize);
+                received.push(buffer);
+            }
+        }
+        received
+    }
+}
+
+/// Cola de transmisión con los buffers que el dispositivo todavía no liberó
+struct TransmitQueue {
+    index: u32,
+    queue: VirtQueue,
+    buffers: BTreeMap<u16, Vec<u8>>,
+}
+
+impl TransmitQueue {
+    fn new(address: &DeviceAddress, index: u32) -> Result<Self, DeviceError> {
+        let queue = VirtQueue::new(address, index)?;
+        Ok(Self {
+            index,
+            queue,
+            buffers: BTreeMap::new(),
+        })
+    }
+
+    /// Encola el buffer sin esperar a que el dispositivo lo consuma
+    fn push(&mut self, buffer: Vec<u8>) -> Result<(), DeviceError> {
+        self.reap();
+        let desc = Descriptor {
+            addr: buffer.as_ptr() as u64,
+            len: buffer.len() as u32,
+            flags: 0,
+            next: 0,
+        };
+        let head = self
+            .queue
+            .push_chain(&[desc])
+            .ok_or(DeviceError::BufferError)?;
+        self.buffers.insert(head, buffer);
+        Ok(())
+    }
+
+    fn reap(&mut self) {
+        while let Some(used) = self.queue.pop_used() {
+            self.buffers.remove(&(used.id as u16));
+        }
+    }
+}
+
+struct ConsolePort {
+    receive: ReceiveQueue,
+    transmit: TransmitQueue,
+    /// El host agregó el puerto. Sin multiport sólo existe el puerto 0
+    added: bool,
+    /// Hay un programa conectado del lado del host
+    host_connected: bool,
+    name: Option<String>,
+    input: VecDeque<u8>,
+    /// Procesos esperando datos en el puerto
+    waiters: Vec<Pid>,
+}
+
+pub struct ConsoleDevice {
+    address: DeviceAddress,
+    ports: Vec<ConsolePort>,
+    control_rx: Option<ReceiveQueue>,
+    control_tx: Option<TransmitQueue>,
+}
+
+impl ConsoleDevice {
+    pub fn new(address: DeviceAddress) -> Result<ConsoleDevice, DeviceError> {
+        let mut status = VirtioDeviceStatus::Acknowledge as u32 | VirtioDeviceStatus::Driver as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        let guest_features = address.negotiate_features(VIRTIO_CONSOLE_F_MULTIPORT)?;
+        status |= VirtioDeviceStatus::FeaturesOk as u32;
+
+        let multiport = guest_features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        let port_count = if multiport {
+            let max_nr_ports = u32::from_le_bytes([
+                address.read_config(CONFIG_MAX_NR_PORTS),
+                address.read_config(CONFIG_MAX_NR_PORTS + 1),
+                address.read_config(CONFIG_MAX_NR_PORTS + 2),
+                address.read_config(CONFIG_MAX_NR_PORTS + 3),
+            ]);
+            min(max_nr_ports as usize, MAX_PORTS)
+        } else {
+            1
+        };
+        let mut ports = Vec::with_capacity(port_count);
+        for port in 0..port_count as u32 {
+            // Las colas de control ocupan los lugares 2 y 3, entre las del
+            // puerto 0 y las del puerto 1
+            let receive_index = if port == 0 { 0 } else { 2 + port * 2 };
+            ports.push(ConsolePort {
+                receive: ReceiveQueue::new(&address, receive_index)?,
+                transmit: TransmitQueue::new(&address, receive_index + 1)?,
+                added: !multiport,
+                host_connected: !multiport,
+                name: None,
+                input: VecDeque::new(),
+                waiters: Vec::new(),
+            });
+        }
+        let (control_rx, control_tx) = if multiport {
+            let control_rx = ReceiveQueue::new(&address, CONTROL_RECEIVE_QUEUE)?;
+            let control_tx = TransmitQueue::new(&address, CONTROL_TRANSMIT_QUEUE)?;
+            (Some(control_rx), Some(control_tx))
+        } else {
+            (None, None)
+        };
+
+        status |= VirtioDeviceStatus::DriverOk as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        let mut console = ConsoleDevice {
+            address,
+            ports,
+            control_rx,
+            control_tx,
+        };
+        for port in 0..console.ports.len() {
+            console.ports[port].receive.post_buffers();
+            console.notify(console.ports[port].receive.index);
+        }
+        if let Some(control_rx) = console.control_rx.as_mut() {
+            control_rx.post_buffers();
+            let index = control_rx.index;
+            console.notify(index);
+        }
+        // Con la cola de control lista, el host empieza a anunciar los puertos
+        console.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        Ok(console)
+    }
+
+    pub fn port_count(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// El puerto existe y el host ya lo agregó
+    pub fn is_port_ready(&self, port: usize) -> bool {
+        self.ports.get(port).is_some_and(|port| port.added)
+    }
+
+    /// Nombre que le asignó el host al puerto, si tiene
+    pub fn port_name(&self, port: usize) -> Option<&str> {
+        self.ports.get(port)?.name.as_deref()
+    }
+
+    /// Encola los datos para enviar al host, sin esperar a que el dispositivo
+    /// los consuma. Devuelve la cantidad de bytes encolados
+    pub fn write(&mut self, port: usize, data: &[u8]) -> Result<usize, DeviceError> {
+        let console_port = self
+            .ports
+            .get_mut(port)
+            .filter(|port| port.added)
+            .ok_or(DeviceError::InvalidDevice)?;
+        let mut written = 0;
+        for chunk in data.chunks(TX_CHUNK_SIZE) {
+            if console_port.transmit.push(chunk.to_vec()).is_err() {
+                break;
+            }
+            written += chunk.len();
+        }
+        if written == 0 && !data.is_empty() {
+            return Err(DeviceError::BufferError);
+        }
+        let index = console_port.transmit.index;
+        self.notify(index);
+        Ok(written)
+    }
+
+    /// Lee los datos recibidos en el puerto. Si no hay ninguno y hay un
+    /// proceso esperando, éste queda registrado para despertar cuando lleguen
+    /// y devuelve `DeviceError::WouldBlock`; sin proceso devuelve 0
+    pub fn read(
+        &mut self,
+        port: usize,
+        buf: &mut [u8],
+        waiter: Option<Pid>,
+    ) -> Result<usize, DeviceError> {
+        let console_port = self
+            .ports
+            .get_mut(port)
+            .filter(|port| port.added)
+            .ok_or(DeviceError::InvalidDevice)?;
+        if console_port.input.is_empty() {
+            if let Some(pid) = waiter {
+                if !console_port.waiters.contains(&pid) {
+                    console_port.waiters.push(pid);
+                }
+                return Err(DeviceError::WouldBlock);
+            }
+            return Ok(0);
+        }
+        let read = min(buf.len(), console_port.input.len());
+        for (dst, src) in buf.iter_mut().zip(console_port.input.drain(..read)) {
+            *dst = src;
+        }
+        Ok(read)
+    }
+
+    /// Guarda los datos recibidos en cada puerto, atiende los mensajes de
+    /// control y libera los buffers ya transmitidos
+    pub fn handle_interrupt(&mut self) {
+        self.address.ack_interrupt();
+        let messages = match self.control_rx.as_mut() {
+            Some(control_rx) => control_rx.pop_received(),
+            None => Vec::new(),
+        };
+        for data in &messages {
+            if let Some(message) = ControlMessage::parse(data) {
+                self.handle_control(message, &data[CONTROL_MESSAGE_SIZE..]);
+            }
+        }
+        if let Some(control_rx) = self.control_rx.as_mut() {
+            if !messages.is_empty() {
+                control_rx.post_buffers();
+                let index = control_rx.index;
+                self.notify(index);
+            }
+        }
+        if let Some(control_tx) = self.control_tx.as_mut() {
+            control_tx.reap();
+        }
+        for port in 0..self.ports.len() {
+            let console_port = &mut self.ports[port];
+            let received = console_port.receive.pop_received();
+            if received.is_empty() {
+                console_port.transmit.reap();
+                continue;
+            }
+            for data in received {
+                console_port.input.extend(data);
+            }
+            // Si nadie lee el puerto descartamos los datos más viejos
+            let excess = console_port.input.len().saturating_sub(MAX_INPUT_SIZE);
+            console_port.input.drain(..excess);
+            for pid in console_port.waiters.drain(..) {
+                ProcessTable::wake(pid);
+            }
+            console_port.receive.post_buffers();
+            console_port.transmit.reap();
+            let index = console_port.receive.index;
+            self.notify(index);
+        }
+    }
+
+    fn handle_control(&mut self, message: ControlMessage, payload: &[u8]) {
+        let id = message.id as usize;
+        let Some(port) = self.ports.get_mut(id) else {
+            return;
+        };
+        match message.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                port.added = true;
+                self.send_control(message.id, VIRTIO_CONSOLE_PORT_READY, 1);
+                // No distinguimos entre puertos abiertos y cerrados: los
+                // abrimos todos apenas existen
+                self.send_control(message.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                port.added = false;
+                port.host_connected = false;
+                for pid in port.waiters.drain(..) {
+                    ProcessTable::wake(pid);
+                }
+            }
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                self.send_control(message.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => port.host_connected = message.value != 0,
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let name = payload.split(|c| *c == 0).next().unwrap_or_default();
+                port.name = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            _ => {}
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let Some(control_tx) = self.control_tx.as_mut() else {
+            return;
+        };
+        let message = ControlMessage { id, event, value };
+        if control_tx.push(message.to_bytes()).is_ok() {
+            let index = control_tx.index;
+            self.notify(index);
+        }
+    }
+
+    fn notify(&self, queue: u32) {
+        self.address
+            .write_register(VirtioMmioRegister::QueueNotify, queue);
+    }
+}
@@ -0,0 +1,131 @@
+//! # Driver de entrada VirtIO
+//! El dispositivo publica eventos de entrada con el formato de `evdev` de
+//! Linux en la cola de eventos, donde le dejamos buffers vacíos. Los eventos
+//! de teclado se traducen con el `Keymap` y llegan a la consola como si se
+//! hubieran escrito por el UART.
+use crate::devices::console::Console;
+use crate::devices::keymap::Keymap;
+use crate::devices::virtio::common::*;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const EVENT_QUEUE: u32 = 0;
+/// Buffers de eventos que le dejamos al dispositivo
+const EVENT_BUFFERS: usize = 64;
+/// Tamaño de `struct virtio_input_event`: tipo, código (u16) y valor (u32)
+const EVENT_SIZE: usize = 8;
+
+/// Offsets del espacio de configuración
+const CONFIG_SELECT: usize = 0;
+const CONFIG_SUBSEL: usize = 1;
+const CONFIG_SIZE: usize = 2;
+const CONFIG_DATA: usize = 8;
+/// Valor de `select` para leer el nombre del dispositivo
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 1;
+
+/// Tipos de evento de `evdev`
+const EV_KEY: u16 = 1;
+
+struct InputEvent {
+    event_type: u16,
+    code: u16,
+    value: u32,
+}
+
+impl InputEvent {
+    fn parse(data: &[u8]) -> Option<InputEvent> {
+        if data.len() < EVENT_SIZE {
+            return None;
+        }
+        Some(InputEvent {
+            event_type: u16::from_le_bytes([data[0], data[1]]),
+            code: u16::from_le_bytes([data[2], data[3]]),
+            value: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+        })
+    }
+}
+
+pub struct InputDevice {
+    address: DeviceAddress,
+    queue: VirtQueue,
+    /// Buffers de eventos en poder del dispositivo, por descriptor
+    buffers: BTreeMap<u16, Vec<u8>>,
+    keymap: Keymap,
+}
+
+impl InputDevice {
+    pub fn new(address: DeviceAddress) -> Result<InputDevice, DeviceError> {
+        let mut status = VirtioDeviceStatus::Acknowledge as u32 | VirtioDeviceStatus::Driver as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        address.negotiate_features(0)?;
+        status |= VirtioDeviceStatus::FeaturesOk as u32;
+        let queue = VirtQueue::new(&address, EVENT_QUEUE)?;
+        status |= VirtioDeviceStatus::DriverOk as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        let mut input_device = InputDevice {
+            address,
+            queue,
+            buffers: BTreeMap::new(),
+            keymap: Keymap::new(),
+        };
+        input_device.post_buffers();
+        Ok(input_device)
+    }
+
+    /// Nombre del dispositivo, como "QEMU Virtio Keyboard"
+    pub fn name(&self) -> String {
+        self.address
+            .write_config(CONFIG_SELECT, VIRTIO_INPUT_CFG_ID_NAME);
+        self.address.write_config(CONFIG_SUBSEL, 0);
+        let size = self.address.read_config(CONFIG_SIZE) as usize;
+        let name = (0..size)
+            .map(|i| self.address.read_config(CONFIG_DATA + i))
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&name).into_owned()
+    }
+
+    /// Procesa los eventos recibidos y le devuelve los buffers al dispositivo
+    pub fn handle_interrupt(&mut self) {
+        self.address.ack_interrupt();
+        while let Some(used) = self.queue.pop_used() {
+            let Some(buffer) = self.buffers.remove(&(used.id as u16)) else {
+                continue;
+            };
+            let Some(event) = InputEvent::parse(&buffer) else {
+                continue;
+            };
+            if event.event_type != EV_KEY {
+                continue;
+            }
+            if let Some(c) = self.keymap.key_event(event.code, event.value) {
+                Console::push_input(Console::echo(c));
+            }
+        }
+        self.post_buffers();
+    }
+
+    /// Completa los buffers de eventos del dispositivo
+    fn post_buffers(&mut self) {
+        let mut posted = false;
+        while self.buffers.len() < EVENT_BUFFERS {
+            let buffer = vec![0; EVENT_SIZE];
+            let desc = Descriptor {
+                addr: buffer.as_ptr() as u64,
+                len: buffer.len() as u32,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            };
+            let Some(head) = self.queue.push_chain(&[desc]) else {
+                break;
+            };
+            self.buffers.insert(head, buffer);
+            posted = true;
+        }
+        if posted {
+            self.address
+                .write_register(VirtioMmioRegister::QueueNotify, EVENT_QUEUE);
+        }
+    }
+}
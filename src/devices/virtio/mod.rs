@@ -1,4 +1,8 @@
 pub mod block_device;
 pub mod common;
+pub mod console_device;
+pub mod input_device;
+pub mod net_device;
+pub mod rng_device;
 
 pub use common::DeviceError;
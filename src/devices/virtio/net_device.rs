@@ -0,0 +1,168 @@
+//! # Driver de red VirtIO
+//! Usa dos colas: en la de recepción dejamos buffers vacíos que el
+//! dispositivo llena con las tramas que llegan, y en la de transmisión
+//! encolamos las tramas a enviar. Cada trama va precedida por un
+//! `virtio_net_hdr`, que dejamos en cero ya que no negociamos *offloads*.
+use crate::devices::virtio::common::*;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+/// Cantidad de buffers de recepción que le dejamos al dispositivo
+const RX_BUFFERS: usize = 16;
+/// Header más una trama Ethernet completa, sin FCS
+const RX_BUFFER_SIZE: usize = 2048;
+/// Tramas recibidas que guardamos hasta que alguien las consuma
+const MAX_RECEIVED_FRAMES: usize = 64;
+/// Tamaño máximo de una trama Ethernet, sin FCS
+pub const MAX_FRAME_SIZE: usize = 1514;
+/// Largo del `virtio_net_hdr` sin `num_buffers`, que sólo existe en la
+/// interfaz moderna o si se negocia `VIRTIO_NET_F_MRG_RXBUF`
+const NET_HEADER_LEGACY_SIZE: usize = 10;
+const NET_HEADER_SIZE: usize = 12;
+const MAC_ADDRESS_SIZE: usize = 6;
+
+pub type MacAddress = [u8; MAC_ADDRESS_SIZE];
+
+pub struct NetDevice {
+    address: DeviceAddress,
+    rx_queue: VirtQueue,
+    tx_queue: VirtQueue,
+    /// Buffers de recepción en poder del dispositivo, por descriptor
+    rx_buffers: BTreeMap<u16, Vec<u8>>,
+    /// Tramas enviadas que el dispositivo todavía no liberó, por descriptor
+    tx_buffers: BTreeMap<u16, Vec<u8>>,
+    /// Tramas recibidas, de la más vieja a la más nueva
+    received: VecDeque<Vec<u8>>,
+    mac: MacAddress,
+    header_size: usize,
+}
+
+impl NetDevice {
+    pub fn new(address: DeviceAddress) -> Result<NetDevice, DeviceError> {
+        let mut status = VirtioDeviceStatus::Acknowledge as u32 | VirtioDeviceStatus::Driver as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        let guest_features = address.negotiate_features(VIRTIO_NET_F_MAC)?;
+        status |= VirtioDeviceStatus::FeaturesOk as u32;
+
+        let rx_queue = VirtQueue::new(&address, RECEIVE_QUEUE)?;
+        let tx_queue = VirtQueue::new(&address, TRANSMIT_QUEUE)?;
+
+        let mut mac = [0; MAC_ADDRESS_SIZE];
+        if guest_features & VIRTIO_NET_F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = address.read_config(i);
+            }
+        }
+        let header_size = if address.is_legacy() {
+            NET_HEADER_LEGACY_SIZE
+        } else {
+            NET_HEADER_SIZE
+        };
+
+        status |= VirtioDeviceStatus::DriverOk as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        let mut net_device = NetDevice {
+            address,
+            rx_queue,
+            tx_queue,
+            rx_buffers: BTreeMap::new(),
+            tx_buffers: BTreeMap::new(),
+            received: VecDeque::new(),
+            mac,
+            header_size,
+        };
+        for _ in 0..RX_BUFFERS {
+            net_device.post_rx_buffer();
+        }
+        net_device
+            .address
+            .write_register(VirtioMmioRegister::QueueNotify, RECEIVE_QUEUE);
+        Ok(net_device)
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// Encola una trama Ethernet para enviar. No espera a que el dispositivo
+    /// la transmita
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), DeviceError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(DeviceError::BufferError);
+        }
+        self.reap_transmitted();
+        let mut buffer = vec![0; self.header_size + frame.len()];
+        buffer[self.header_size..].copy_from_slice(frame);
+        let desc = Descriptor {
+            addr: buffer.as_ptr() as u64,
+            len: buffer.len() as u32,
+            flags: 0,
+            next: 0,
+        };
+        let head = self
+            .tx_queue
+            .push_chain(&[desc])
+            .ok_or(DeviceError::BufferError)?;
+        self.tx_buffers.insert(head, buffer);
+        self.address
+            .write_register(VirtioMmioRegister::QueueNotify, TRANSMIT_QUEUE);
+        Ok(())
+    }
+
+    /// Saca la trama recibida más vieja, sin el header de VirtIO
+    pub fn receive(&mut self) -> Option<Vec<u8>> {
+        self.received.pop_front()
+    }
+
+    /// Guarda las tramas recibidas, devuelve sus buffers al dispositivo y
+    /// libera las tramas ya transmitidas
+    pub fn handle_interrupt(&mut self) {
+        self.address.ack_interrupt();
+        let mut reposted = false;
+        while let Some(used) = self.rx_queue.pop_used() {
+            let Some(mut buffer) = self.rx_buffers.remove(&(used.id as u16)) else {
+                continue;
+            };
+            let len = core::cmp::min(used.len as usize, buffer.len());
+            if len > self.header_size {
+                buffer.truncate(len);
+                buffer.drain(..self.header_size);
+                if self.received.len() == MAX_RECEIVED_FRAMES {
+                    // Si nadie consume las tramas descartamos las más viejas
+                    self.received.pop_front();
+                }
+                self.received.push_back(buffer);
+            }
+            self.post_rx_buffer();
+            reposted = true;
+        }
+        if reposted {
+            self.address
+                .write_register(VirtioMmioRegister::QueueNotify, RECEIVE_QUEUE);
+        }
+        self.reap_transmitted();
+    }
+
+    /// Le deja al dispositivo un buffer vacío para una trama entrante
+    fn post_rx_buffer(&mut self) {
+        let buffer = vec![0; RX_BUFFER_SIZE];
+        let desc = Descriptor {
+            addr: buffer.as_ptr() as u64,
+            len: buffer.len() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        if let Some(head) = self.rx_queue.push_chain(&[desc]) {
+            self.rx_buffers.insert(head, buffer);
+        }
+    }
+
+    fn reap_transmitted(&mut self) {
+        while let Some(used) = self.tx_queue.pop_used() {
+            self.tx_buffers.remove(&(used.id as u16));
+        }
+    }
+}
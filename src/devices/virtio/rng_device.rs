@@ -0,0 +1,78 @@
+//! # Driver de entropía VirtIO
+//! El dispositivo tiene una sola cola, en la que dejamos buffers vacíos que
+//! llena con bytes aleatorios del host. Lo recibido alimenta el `EntropyPool`.
+use crate::assembly::riscv64::wfi;
+use crate::devices::virtio::common::*;
+use crate::system::random::EntropyPool;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const REQUEST_QUEUE: u32 = 0;
+/// Bytes que pedimos en cada pedido
+const ENTROPY_REQUEST_SIZE: usize = 32;
+
+pub struct RngDevice {
+    address: DeviceAddress,
+    queue: VirtQueue,
+    /// Buffers que el dispositivo todavía no llenó, por descriptor
+    pending: BTreeMap<u16, Vec<u8>>,
+}
+
+impl RngDevice {
+    pub fn new(address: DeviceAddress) -> Result<RngDevice, DeviceError> {
+        let mut status = VirtioDeviceStatus::Acknowledge as u32 | VirtioDeviceStatus::Driver as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        address.negotiate_features(0)?;
+        status |= VirtioDeviceStatus::FeaturesOk as u32;
+        let queue = VirtQueue::new(&address, REQUEST_QUEUE)?;
+        status |= VirtioDeviceStatus::DriverOk as u32;
+        address.write_register(VirtioMmioRegister::Status, status);
+        Ok(RngDevice {
+            address,
+            queue,
+            pending: BTreeMap::new(),
+        })
+    }
+
+    /// Pide bytes aleatorios al dispositivo, sin esperarlos. Si ya hay un
+    /// pedido en curso no hace nada
+    pub fn request(&mut self) {
+        if !self.pending.is_empty() {
+            return;
+        }
+        let buffer = vec![0; ENTROPY_REQUEST_SIZE];
+        let desc = Descriptor {
+            addr: buffer.as_ptr() as u64,
+            len: buffer.len() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        if let Some(head) = self.queue.push_chain(&[desc]) {
+            self.pending.insert(head, buffer);
+            self.address
+                .write_register(VirtioMmioRegister::QueueNotify, REQUEST_QUEUE);
+        }
+    }
+
+    /// Pide bytes aleatorios y espera a recibirlos. Se usa al arrancar, antes
+    /// de que haya procesos
+    pub fn request_sync(&mut self) {
+        self.request();
+        while !self.pending.is_empty() {
+            unsafe { wfi() };
+            self.handle_interrupt();
+        }
+    }
+
+    /// Agrega los bytes recibidos al pool de entropía
+    pub fn handle_interrupt(&mut self) {
+        self.address.ack_interrupt();
+        while let Some(used) = self.queue.pop_used() {
+            if let Some(buffer) = self.pending.remove(&(used.id as u16)) {
+                let len = core::cmp::min(used.len as usize, buffer.len());
+                EntropyPool::add_entropy(&buffer[..len]);
+            }
+        }
+    }
+}
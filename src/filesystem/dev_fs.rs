@@ -0,0 +1,201 @@
+//! # Sistema de archivos de dispositivos
+//! Expone los dispositivos de caracteres como archivos en `/dev`. Los nodos no
+//! se guardan en ningún lado: se arman a partir de los dispositivos que
+//! encontró el `DeviceManager`.
+use crate::devices::virtio::common::{DeviceManager, DeviceType};
+use crate::devices::virtio::console_device::ConsoleDevice;
+use crate::devices::virtio::DeviceError;
+use crate::filesystem::linux::S_IFDIR;
+use crate::filesystem::virtual_fs::{
+    DirEntry, FileDescriptor, FileStat, FileType, FilesystemDriver,
+};
+use crate::system::process::Pid;
+use crate::system::random::EntropyPool;
+use crate::utils::error::{IoError, IoResult};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// Punto de montaje del sistema de archivos
+pub const DEV_PATH: &str = "/dev";
+const S_IFCHR: u16 = 0x2000;
+/// Permisos de los nodos: `rw-rw-rw-`
+const DEVICE_PERMISSIONS: u16 = 0o666;
+/// Permisos del directorio: `rwxr-xr-x`
+const DIRECTORY_PERMISSIONS: u16 = 0o755;
+/// Inodo del directorio, los nodos se numeran a continuación
+const ROOT_INODE: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DeviceNode {
+    /// El propio directorio `/dev`
+    Root,
+    /// Puerto de la primera consola VirtIO, como `/dev/hvcN`
+    Console(usize),
+    /// Bytes del generador de números aleatorios
+    Urandom,
+}
+
+impl DeviceNode {
+    /// Nodos de los dispositivos disponibles, con su nombre
+    fn list() -> Vec<(String, DeviceNode)> {
+        let mut nodes = vec![("urandom".to_string(), DeviceNode::Urandom)];
+        if let Some(console) = console_device() {
+            let console = console.borrow();
+            for port in 0..console.port_count() {
+                if console.is_port_ready(port) {
+                    nodes.push((format!("hvc{}", port), DeviceNode::Console(port)));
+                }
+            }
+        }
+        nodes
+    }
+
+    fn lookup(path: &str) -> Option<DeviceNode> {
+        let name = path.strip_prefix(DEV_PATH)?.trim_matches('/');
+        if name.is_empty() {
+            return Some(DeviceNode::Root);
+        }
+        DeviceNode::list()
+            .into_iter()
+            .find(|(node_name, _)| node_name == name)
+            .map(|(_, node)| node)
+    }
+
+    fn inode(&self) -> u64 {
+        match self {
+            DeviceNode::Root => ROOT_INODE,
+            DeviceNode::Urandom => ROOT_INODE + 1,
+            DeviceNode::Console(port) => ROOT_INODE + 2 + *port as u64,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            DeviceNode::Root => FileType::Directory,
+            DeviceNode::Console(_) | DeviceNode::Urandom => FileType::CharDevice,
+        }
+    }
+}
+
+fn console_device() -> Option<&'static RefCell<ConsoleDevice>> {
+    let device_id = DeviceManager::find(DeviceType::Console, 0)?;
+    DeviceManager::get_console_device(device_id)
+}
+
+pub struct DeviceFsDriver {
+    /// Proceso que espera si una lectura no tiene datos disponibles
+    waiter: Option<Pid>,
+}
+
+impl DeviceFsDriver {
+    pub fn new(waiter: Option<Pid>) -> Self {
+        Self { waiter }
+    }
+
+    fn node(fd: &FileDescriptor) -> IoResult<DeviceNode> {
+        DeviceNode::lookup(&fd.path).ok_or(IoError::FileNotExists)
+    }
+}
+
+impl FilesystemDriver for DeviceFsDriver {
+    fn open(&self, path: &str) -> IoResult<FileDescriptor> {
+        DeviceNode::lookup(path).ok_or(IoError::FileNotExists)?;
+        Ok(FileDescriptor {
+            path: path.to_string(),
+            file_pos: 0,
+            eof_flag: false,
+            ext2: None,
+        })
+    }
+
+    /// Los dispositivos de caracteres ignoran el offset
+    fn read(&self, fd: &FileDescriptor, buf: &mut [u8], _offset: u64) -> IoResult<usize> {
+        match DeviceFsDriver::node(fd)? {
+            DeviceNode::Root => Err(IoError::IsADirectory),
+            DeviceNode::Console(port) => {
+                let console = console_device().ok_or(IoError::FileNotExists)?;
+                let read = console.borrow_mut().read(port, buf, self.waiter)?;
+                Ok(read)
+            }
+            DeviceNode::Urandom => {
+                EntropyPool::fill(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn stat(&self, fd: &FileDescriptor) -> IoResult<FileStat> {
+        let node = DeviceFsDriver::node(fd)?;
+        let mode = match node {
+            DeviceNode::Root => S_IFDIR | DIRECTORY_PERMISSIONS,
+            DeviceNode::Console(_) | DeviceNode::Urandom => S_IFCHR | DEVICE_PERMISSIONS,
+        };
+        Ok(FileStat {
+            inode: node.inode(),
+            file_type: node.file_type(),
+            mode,
+            links: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+        })
+    }
+
+    fn readdir(&self, fd: &FileDescriptor) -> IoResult<Vec<DirEntry>> {
+        if DeviceFsDriver::node(fd)? != DeviceNode::Root {
+            return Err(IoError::NotADirectory);
+        }
+        let entries = DeviceNode::list()
+            .into_iter()
+            .map(|(name, node)| DirEntry {
+                inode: node.inode(),
+                name,
+                file_type: node.file_type(),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn close(&self, _fd: FileDescriptor) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn write(&self, fd: &FileDescriptor, buf: &[u8], _offset: u64) -> IoResult<usize> {
+        match DeviceFsDriver::node(fd)? {
+            DeviceNode::Root => Err(IoError::IsADirectory),
+            DeviceNode::Console(port) => {
+                let console = console_device().ok_or(IoError::FileNotExists)?;
+                let written = console.borrow_mut().write(port, buf)?;
+                Ok(written)
+            }
+            // Como en Linux, lo que se escribe se mezcla en el generador
+            DeviceNode::Urandom => {
+                EntropyPool::add_entropy(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Los nodos dependen de los dispositivos, no se pueden crear ni borrar
+    fn create(&self, _path: &str) -> IoResult<FileDescriptor> {
+        Err(IoError::DeviceError(DeviceError::ReadOnly))
+    }
+
+    /// Truncar un dispositivo de caracteres no tiene efecto, como con `O_TRUNC`
+    fn truncate(&self, fd: &FileDescriptor, _size: u64) -> IoResult<()> {
+        match DeviceFsDriver::node(fd)? {
+            DeviceNode::Root => Err(IoError::IsADirectory),
+            _ => Ok(()),
+        }
+    }
+
+    fn unlink(&self, _path: &str) -> IoResult<()> {
+        Err(IoError::DeviceError(DeviceError::ReadOnly))
+    }
+}
@@ -1,14 +1,37 @@
 use crate::devices::virtio::block_device::BlockDevice;
 use crate::devices::virtio::DeviceError;
-use crate::filesystem::linux::{Inode, LinuxPartition};
+use crate::filesystem::linux::{
+    Ext2Filetype, Inode, LinuxPartition, PartitionHandle, ROOT_INODE, S_IFDIR, S_IFREG,
+};
 use crate::filesystem::partition::{PartitionTable, PartitionType};
-use crate::filesystem::virtual_fs::{FileDescriptor, FilesystemDriver};
-use crate::print;
+use crate::filesystem::virtual_fs::{
+    DirEntry, FileDescriptor, FileStat, FileType, FilesystemDriver,
+};
 use crate::utils::error::{IoError, IoResult};
 use alloc::string::ToString;
+use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::cmp::{max, min};
 
 const MAX_PARTITIONS: u8 = 4;
+/// Máscara y valores del tipo de archivo en `i_mode`
+const S_IFMT: u16 = 0xF000;
+const S_IFSOCK: u16 = 0xC000;
+const S_IFLNK: u16 = 0xA000;
+const S_IFBLK: u16 = 0x6000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFIFO: u16 = 0x1000;
+/// Permisos de los archivos nuevos: `rw-r--r--`
+const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
+
+/// Lo que el descriptor recuerda del archivo abierto, para no leer la tabla
+/// de particiones y el superbloque ni recorrer el camino en cada operación.
+/// El inodo en sí se vuelve a leer, ya que otro descriptor puede cambiarlo
+#[derive(Clone, Copy, Debug)]
+pub struct Ext2File {
+    partition: PartitionHandle,
+    inode_id: u32,
+}
 
 pub struct Ext2FilesystemDriver<'a> {
     device: &'a RefCell<BlockDevice>,
@@ -25,7 +48,7 @@ impl<'a> Ext2FilesystemDriver<'a> {
 
     fn get_partition(&self) -> IoResult<LinuxPartition<'_>> {
         let mut buffer = [0u8; 512];
-        self.device.borrow_mut().read_sync(&mut buffer, 0)?;
+        self.device.borrow_mut().read(&mut buffer, 0)?;
         let table = PartitionTable::new(buffer);
         // TODO: don't hardcode first partition
         let mut root_partition = None;
@@ -45,47 +68,298 @@ impl<'a> Ext2FilesystemDriver<'a> {
         }
     }
 
-    fn get_inode(&self, path: &str) -> IoResult<Inode> {
-        let partition = self.get_partition().unwrap();
+    /// Recorre el camino desde la raíz, devuelve el número de inodo y el inodo
+    fn lookup(&self, path: &str) -> IoResult<(u32, Inode)> {
+        Self::lookup_in(&self.get_partition()?, path)
+    }
+
+    fn lookup_in(partition: &LinuxPartition, path: &str) -> IoResult<(u32, Inode)> {
+        let mut current_id = ROOT_INODE;
         let mut current_inode = partition.read_root()?;
-        let path_iter = path.split("/").skip(1);
+        let path_iter = path.split("/").skip(1).filter(|entry| !entry.is_empty());
         for entry in path_iter {
-            current_inode = partition
-                .get_inode_block_iterator(current_inode)
-                .and_then(|root_it| {
-                    root_it
-                        .get_entry_with_name(entry)
-                        .ok_or(DeviceError::EntryNotFound)
-                })
-                .and_then(|file_entry| partition.get_inode_for_entry(file_entry))?;
-        }
-        Ok(current_inode)
-    }
-
-    fn print_file_contents(&self, inode: Inode) {
-        let partition = self.get_partition().unwrap();
-        let mut remaining_data = inode.i_size as u64;
-        let file_block_iterator = partition.get_inode_block_iterator(inode).unwrap();
-        let block_size = partition.get_block_size();
-        for block in file_block_iterator {
-            let block_data_size = core::cmp::min(remaining_data, block_size);
-            for c in &block.data[0..block_data_size as usize] {
-                print!("{}", *c as char);
-            }
-            remaining_data -= block_data_size;
+            let file_entry = partition
+                .get_inode_block_iterator(current_inode)?
+                .get_entry_with_name(entry)?
+                .ok_or(DeviceError::EntryNotFound)?;
+            current_id = file_entry.get_inode_id();
+            current_inode = partition.get_inode_for_entry(file_entry)?;
+        }
+        Ok((current_id, current_inode))
+    }
+
+    /// Número de inodo e inodo del archivo abierto en `fd`
+    fn file_inode(
+        &self,
+        partition: &LinuxPartition,
+        fd: &FileDescriptor,
+    ) -> IoResult<(u32, Inode)> {
+        match fd.ext2 {
+            Some(file) => Ok((file.inode_id, partition.read_inode(file.inode_id as u64)?)),
+            None => Self::lookup_in(partition, &fd.path),
+        }
+    }
+
+    /// Partición del archivo abierto en `fd`. La copia del superbloque que
+    /// guarda el descriptor no sirve para reservar bloques ni inodos
+    fn read_partition(&self, fd: &FileDescriptor) -> IoResult<LinuxPartition<'_>> {
+        match fd.ext2 {
+            Some(file) => Ok(LinuxPartition::from_handle(self.device, file.partition)),
+            None => self.get_partition(),
+        }
+    }
+}
+
+/// Separa el camino en el directorio padre y el nombre del archivo
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    let (parent, name) = path.trim_end_matches('/').rsplit_once('/')?;
+    if name.is_empty() {
+        return None;
+    }
+    let parent = if parent.is_empty() { "/" } else { parent };
+    Some((parent, name))
+}
+
+/// Tipo de archivo según los bits altos de `i_mode`
+fn mode_file_type(mode: u16) -> FileType {
+    match mode & S_IFMT {
+        S_IFREG => FileType::Regular,
+        S_IFDIR => FileType::Directory,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::Fifo,
+        S_IFSOCK => FileType::Socket,
+        S_IFLNK => FileType::Symlink,
+        _ => FileType::Unknown,
+    }
+}
+
+impl From<Ext2Filetype> for FileType {
+    fn from(value: Ext2Filetype) -> Self {
+        match value {
+            Ext2Filetype::RegFile => FileType::Regular,
+            Ext2Filetype::Dir => FileType::Directory,
+            Ext2Filetype::Chrdev => FileType::CharDevice,
+            Ext2Filetype::Blkdev => FileType::BlockDevice,
+            Ext2Filetype::Fifo => FileType::Fifo,
+            Ext2Filetype::Sock => FileType::Socket,
+            Ext2Filetype::Symlink => FileType::Symlink,
+            Ext2Filetype::Unknown => FileType::Unknown,
         }
     }
 }
 
 impl FilesystemDriver for Ext2FilesystemDriver<'_> {
     fn open(&self, path: &str) -> IoResult<FileDescriptor> {
-        let inode = self.get_inode(path);
-        self.print_file_contents(inode.unwrap());
+        let partition = self.get_partition()?;
+        let (inode_id, _) = Self::lookup_in(&partition, path)?;
         let fd = FileDescriptor {
             path: path.to_string(),
             file_pos: 0,
             eof_flag: false,
+            ext2: Some(Ext2File {
+                partition: partition.handle(),
+                inode_id,
+            }),
         };
         Ok(fd)
     }
+
+    fn read(&self, fd: &FileDescriptor, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+        let mut partition = self.read_partition(fd)?;
+        let (_, mut inode) = self.file_inode(&partition, fd)?;
+        let file_size = inode.i_size as u64;
+        if offset >= file_size {
+            return Ok(0);
+        }
+        let to_read = min(buf.len() as u64, file_size - offset) as usize;
+        let block_size = partition.get_block_size() as usize;
+        let mut block_no = (offset / block_size as u64) as usize;
+        let mut block_offset = (offset % block_size as u64) as usize;
+        let mut read = 0;
+        while read < to_read {
+            let chunk = min(to_read - read, block_size - block_offset);
+            match partition.map_file_block(&mut inode, block_no, false)? {
+                Some(block_id) => {
+                    let block = partition.read_datablock(block_id as u64)?;
+                    buf[read..read + chunk]
+                        .copy_from_slice(&block.data[block_offset..block_offset + chunk]);
+                }
+                // Los bloques que no están reservados se leen como ceros
+                None => buf[read..read + chunk].fill(0),
+            }
+            read += chunk;
+            block_offset = 0;
+            block_no += 1;
+        }
+        Ok(read)
+    }
+
+    fn stat(&self, fd: &FileDescriptor) -> IoResult<FileStat> {
+        let partition = self.read_partition(fd)?;
+        let (inode_id, inode) = self.file_inode(&partition, fd)?;
+        let stat = FileStat {
+            inode: inode_id as u64,
+            file_type: mode_file_type(inode.i_mode),
+            mode: inode.i_mode,
+            links: inode.i_links_count,
+            uid: inode.i_uid,
+            gid: inode.i_gid,
+            size: inode.i_size as u64,
+            atime: inode.i_atime,
+            mtime: inode.i_mtime,
+            ctime: inode.i_ctime,
+        };
+        Ok(stat)
+    }
+
+    fn readdir(&self, fd: &FileDescriptor) -> IoResult<Vec<DirEntry>> {
+        let partition = self.read_partition(fd)?;
+        let (_, inode) = self.file_inode(&partition, fd)?;
+        if mode_file_type(inode.i_mode) != FileType::Directory {
+            return Err(IoError::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        for block in partition.get_inode_block_iterator(inode)? {
+            let block = block?;
+            // Las entradas con inodo 0 están libres
+            let used_entries = block
+                .iter_directories()
+                .filter(|(entry, _)| entry.get_inode_id() != 0);
+            for (entry, name) in used_entries {
+                entries.push(DirEntry {
+                    inode: entry.get_inode_id() as u64,
+                    name: name.to_string(),
+                    file_type: entry.get_file_type().into(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Ext2 no guarda estado por archivo abierto, no hay nada que liberar
+    fn close(&self, _fd: FileDescriptor) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn write(&self, fd: &FileDescriptor, buf: &[u8], offset: u64) -> IoResult<usize> {
+        let mut partition = self.get_partition()?;
+        let (inode_id, mut inode) = self.file_inode(&partition, fd)?;
+        let block_size = partition.get_block_size();
+        // No soportamos archivos dispersos: si escribimos más allá del final
+        // reservamos también los bloques intermedios, que quedan en cero
+        let current_blocks = (inode.i_size as u64).div_ceil(block_size) as usize;
+        for block_no in current_blocks..(offset / block_size) as usize {
+            partition.map_file_block(&mut inode, block_no, true)?;
+        }
+        let mut written = 0;
+        while written < buf.len() {
+            let position = offset + written as u64;
+            let block_no = (position / block_size) as usize;
+            let block_offset = (position % block_size) as usize;
+            let chunk = min(buf.len() - written, block_size as usize - block_offset);
+            let block_id = partition
+                .map_file_block(&mut inode, block_no, true)?
+                .ok_or(DeviceError::NoSpace)? as u64;
+            let mut block = partition.read_datablock(block_id)?;
+            block.data[block_offset..block_offset + chunk]
+                .copy_from_slice(&buf[written..written + chunk]);
+            partition.write_datablock(block_id, &block)?;
+            written += chunk;
+        }
+        inode.i_size = max(inode.i_size as u64, offset + written as u64) as u32;
+        let now = partition.current_time();
+        inode.i_mtime = now;
+        inode.i_ctime = now;
+        partition.write_inode(inode_id as u64, &inode)?;
+        partition.flush()?;
+        Ok(written)
+    }
+
+    fn create(&self, path: &str) -> IoResult<FileDescriptor> {
+        let (parent_path, name) = split_path(path).ok_or(IoError::FileNotExists)?;
+        let (parent_id, mut parent) = self.lookup(parent_path)?;
+        if mode_file_type(parent.i_mode) != FileType::Directory {
+            return Err(IoError::NotADirectory);
+        }
+        if self.lookup(path).is_ok() {
+            return Err(DeviceError::EntryExists.into());
+        }
+        let mut partition = self.get_partition()?;
+        let inode_id = partition.allocate_inode(false)?;
+        let now = partition.current_time();
+        let mut inode = Inode::new(S_IFREG | DEFAULT_FILE_PERMISSIONS);
+        inode.i_atime = now;
+        inode.i_ctime = now;
+        inode.i_mtime = now;
+        partition.write_inode(inode_id as u64, &inode)?;
+        partition.add_dir_entry(
+            parent_id,
+            &mut parent,
+            name,
+            inode_id,
+            Ext2Filetype::RegFile,
+        )?;
+        partition.flush()?;
+        self.open(path)
+    }
+
+    fn truncate(&self, fd: &FileDescriptor, size: u64) -> IoResult<()> {
+        let mut partition = self.get_partition()?;
+        let (inode_id, mut inode) = self.file_inode(&partition, fd)?;
+        let block_size = partition.get_block_size();
+        let current_blocks = (inode.i_size as u64).div_ceil(block_size) as usize;
+        let new_blocks = size.div_ceil(block_size) as usize;
+        if new_blocks < current_blocks {
+            partition.free_file_blocks(&mut inode, new_blocks)?;
+        }
+        for block_no in current_blocks..new_blocks {
+            partition.map_file_block(&mut inode, block_no, true)?;
+        }
+        // Limpiamos el final del último bloque, para que si el archivo vuelve
+        // a crecer se lean ceros
+        let tail_offset = (size % block_size) as usize;
+        if size < inode.i_size as u64 && tail_offset != 0 {
+            let last_block = (size / block_size) as usize;
+            if let Some(block_id) = partition.map_file_block(&mut inode, last_block, false)? {
+                let mut block = partition.read_datablock(block_id as u64)?;
+                block.data[tail_offset..].fill(0);
+                partition.write_datablock(block_id as u64, &block)?;
+            }
+        }
+        inode.i_size = size as u32;
+        partition.write_inode(inode_id as u64, &inode)?;
+        partition.flush()?;
+        Ok(())
+    }
+
+    fn unlink(&self, path: &str) -> IoResult<()> {
+        let (parent_path, name) = split_path(path).ok_or(IoError::FileNotExists)?;
+        let (_, mut parent) = self.lookup(parent_path)?;
+        let (inode_id, mut inode) = self.lookup(path)?;
+        if mode_file_type(inode.i_mode) == FileType::Directory {
+            return Err(IoError::IsADirectory);
+        }
+        // Un inodo con una entrada pero sin links está corrupto: no lo tocamos
+        let links_count = inode
+            .i_links_count
+            .checked_sub(1)
+            .ok_or(IoError::CorruptedFilesystem)?;
+        let mut partition = self.get_partition()?;
+        partition.remove_dir_entry(&mut parent, name)?;
+        inode.i_links_count = links_count;
+        let now = partition.current_time();
+        inode.i_ctime = now;
+        if inode.i_links_count == 0 {
+            partition.free_file_blocks(&mut inode, 0)?;
+            inode.i_size = 0;
+            inode.set_deletion_time(now);
+            partition.write_inode(inode_id as u64, &inode)?;
+            partition.free_inode(inode_id, false)?;
+        } else {
+            partition.write_inode(inode_id as u64, &inode)?;
+        }
+        partition.flush()?;
+        Ok(())
+    }
 }
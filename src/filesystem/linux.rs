@@ -1,3 +1,4 @@
+use crate::cpu::riscv64::trap::{read_mtime, MSECS_CYCLES};
 use crate::devices::virtio::block_device::BlockDevice;
 use crate::devices::virtio::DeviceError;
 use crate::filesystem::SECTOR_SIZE;
@@ -11,6 +12,20 @@ const MAX_BLOCK_SIZE: usize = 8_192;
 const INODE_SINGLE_INDIRECT: usize = 12;
 const INODE_DOUBLE_INDIRECT: usize = 13;
 const INODE_TRIPLE_INDIRECT: usize = 14;
+/// The root directory is always inode 2
+pub const ROOT_INODE: u32 = 2;
+/// The superblock starts 1024 bytes after the beginning of the partition
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// Revision 0 filesystems have fixed inode size and first usable inode
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+/// `i_blocks` counts 512 byte sectors, not filesystem blocks
+const I_BLOCKS_SECTOR_SIZE: u64 = 512;
+/// Directory entries are 4 byte aligned
+const DIR_ENTRY_ALIGN: usize = 4;
+/// File type bits of `i_mode`
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFDIR: u16 = 0x4000;
 
 pub struct LinuxPartition<'a> {
     device: &'a RefCell<BlockDevice>,
@@ -18,8 +33,17 @@ pub struct LinuxPartition<'a> {
     superblock: Superblock,
 }
 
+/// Where a partition starts and a copy of its superblock, enough to open it
+/// again without reading the disk. The copy goes stale when blocks or inodes
+/// are allocated, so it is only good for reading
+#[derive(Clone, Copy, Debug)]
+pub struct PartitionHandle {
+    first_sector: u64,
+    superblock: Superblock,
+}
+
 #[repr(u8)]
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub enum Ext2Filetype {
     /// Unknown File Type
     #[default]
@@ -54,7 +78,7 @@ pub struct BlockGroup {
 }
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct Superblock {
     /// Inodes count
     s_inodes_count: i32,
@@ -117,24 +141,41 @@ pub struct Superblock {
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct Inode {
-    i_mode: u16,        /* File type and access rights */
-    i_uid: u16,         /* Low 16 bits of Owner Uid */
-    pub i_size: u32,    /* Size in bytes */
-    i_atime: u32,       /* Access time */
-    i_ctime: u32,       /* Creation time */
-    i_mtime: u32,       /* Modification time */
-    i_dtime: u32,       /* Deletion Time */
-    i_gid: u16,         /* Low 16 bits of Group Id */
-    i_links_count: u16, /* Links count */
-    i_blocks: u32,      /* Blocks count */
-    i_flags: u32,       /* File flags */
-    i_osd1: u32,        /* OS Dependant flags */
-    i_block: [u32; 15], /* Block pointers */
-    i_generation: u32,  /* File version (NFS) */
-    i_file_acl: u32,    /* Extended attributes */
-    i_dir_acl: u32,     /* High 32 bits of 64bit file size */
-    i_faddr: u32,       /* File fragment location */
-    i_osd2: [u8; 12],   /* OS dependants bits */
+    pub i_mode: u16,        /* File type and access rights */
+    pub i_uid: u16,         /* Low 16 bits of Owner Uid */
+    pub i_size: u32,        /* Size in bytes */
+    pub i_atime: u32,       /* Access time */
+    pub i_ctime: u32,       /* Creation time */
+    pub i_mtime: u32,       /* Modification time */
+    i_dtime: u32,           /* Deletion Time */
+    pub i_gid: u16,         /* Low 16 bits of Group Id */
+    pub i_links_count: u16, /* Links count */
+    i_blocks: u32,          /* Blocks count */
+    i_flags: u32,           /* File flags */
+    i_osd1: u32,            /* OS Dependant flags */
+    i_block: [u32; 15],     /* Block pointers */
+    i_generation: u32,      /* File version (NFS) */
+    i_file_acl: u32,        /* Extended attributes */
+    i_dir_acl: u32,         /* High 32 bits of 64bit file size */
+    i_faddr: u32,           /* File fragment location */
+    i_osd2: [u8; 12],       /* OS dependants bits */
+}
+
+impl Inode {
+    /// New inode with one link, owned by root
+    pub fn new(mode: u16) -> Self {
+        Self {
+            i_mode: mode,
+            i_links_count: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Marks the inode as deleted. `e2fsck` expects freed inodes to have a
+    /// non zero `i_dtime`
+    pub fn set_deletion_time(&mut self, time: u32) {
+        self.i_dtime = time;
+    }
 }
 
 impl<'a> LinuxPartition<'a> {
@@ -148,34 +189,96 @@ impl<'a> LinuxPartition<'a> {
         })
     }
 
+    pub fn from_handle(device: &'a RefCell<BlockDevice>, handle: PartitionHandle) -> Self {
+        Self {
+            device,
+            first_sector: handle.first_sector,
+            superblock: handle.superblock,
+        }
+    }
+
+    pub fn handle(&self) -> PartitionHandle {
+        PartitionHandle {
+            first_sector: self.first_sector,
+            superblock: self.superblock,
+        }
+    }
+
     pub fn get_block_size(&self) -> u64 {
         self.superblock.get_block_size()
     }
 
+    /// Current time for inode timestamps. There is no real time clock, so we
+    /// count the seconds since boot from the last time the filesystem was
+    /// mounted or written
+    pub fn current_time(&self) -> u32 {
+        let last_use = self.superblock.s_wtime.max(self.superblock.s_mtime) as u32;
+        let uptime = read_mtime() / (MSECS_CYCLES * 1000);
+        last_use.max(1) + uptime as u32
+    }
+
     pub fn read_root(&self) -> Result<Inode, DeviceError> {
-        self.read_inode(2)
+        self.read_inode(ROOT_INODE as u64)
     }
 
     pub fn get_inode_for_entry(&self, entry: DirectoryEntry) -> Result<Inode, DeviceError> {
         self.read_inode(entry.inode as u64)
     }
 
-    fn read_inode(&self, inode_id: u64) -> Result<Inode, DeviceError> {
-        let inode_id = inode_id - 1;
-        let block_size = self.superblock.get_block_size();
-        let block_id = inode_id / self.superblock.s_inodes_per_group as u64 + 2;
-        let block = self.read_from_block::<BlockGroup>(block_id)?;
+    pub fn read_inode(&self, inode_id: u64) -> Result<Inode, DeviceError> {
+        let inode_offset = self.get_inode_offset(inode_id)?;
+        self.read_from_offset(inode_offset)
+    }
 
-        let inode_table_offset = block.bg_inode_table as u64 * block_size;
-        let inode_offset = inode_table_offset + inode_id * self.superblock.s_inode_size as u64;
-        let inode = self.read_from_offset(inode_offset)?;
-        Ok(inode)
+    pub fn write_inode(&self, inode_id: u64, inode: &Inode) -> Result<(), DeviceError> {
+        let inode_offset = self.get_inode_offset(inode_id)?;
+        self.write_to_offset(inode_offset, inode)
     }
 
-    fn read_from_block<T>(&self, block_id: u64) -> Result<T, DeviceError> {
-        let block_id = block_id - 1;
-        let block_start = core::cmp::max(2048, self.superblock.get_block_size() * block_id);
-        self.read_from_offset(block_start)
+    /// Inodes are numbered from 1, and each group holds `s_inodes_per_group`
+    /// of them in its inode table
+    fn get_inode_offset(&self, inode_id: u64) -> Result<u64, DeviceError> {
+        let index = inode_id - 1;
+        let inodes_per_group = self.superblock.s_inodes_per_group as u64;
+        let group = self.read_group(index / inodes_per_group)?;
+        let inode_table_offset = group.bg_inode_table as u64 * self.get_block_size();
+        Ok(inode_table_offset + (index % inodes_per_group) * self.get_inode_size())
+    }
+
+    fn get_inode_size(&self) -> u64 {
+        if self.superblock.s_rev_level == 0 {
+            GOOD_OLD_INODE_SIZE
+        } else {
+            self.superblock.s_inode_size as u64
+        }
+    }
+
+    fn get_first_inode(&self) -> u32 {
+        if self.superblock.s_rev_level == 0 {
+            GOOD_OLD_FIRST_INO
+        } else {
+            self.superblock.s_first_ino as u32
+        }
+    }
+
+    /// The group descriptor table starts at the block after the superblock
+    fn get_group_offset(&self, group: u64) -> u64 {
+        let table_block = self.superblock.s_first_data_block as u64 + 1;
+        table_block * self.get_block_size() + group * size_of::<BlockGroup>() as u64
+    }
+
+    fn read_group(&self, group: u64) -> Result<BlockGroup, DeviceError> {
+        self.read_from_offset(self.get_group_offset(group))
+    }
+
+    fn write_group(&self, group: u64, block_group: &BlockGroup) -> Result<(), DeviceError> {
+        self.write_to_offset(self.get_group_offset(group), block_group)
+    }
+
+    fn get_groups_count(&self) -> u64 {
+        let data_blocks =
+            self.superblock.s_blocks_count_lo as u64 - self.superblock.s_first_data_block as u64;
+        data_blocks.div_ceil(self.superblock.s_blocks_per_group as u64)
     }
 
     fn read_from_offset<T>(&self, offset: u64) -> Result<T, DeviceError> {
@@ -186,7 +289,7 @@ impl<'a> LinuxPartition<'a> {
         let dest =
             unsafe { core::slice::from_raw_parts_mut(&mut item as *mut _ as *mut u8, data_size) };
         let mut buffer = [0u8; SECTOR_SIZE];
-        self.device.borrow_mut().read_sync(&mut buffer, offset)?;
+        self.device.borrow_mut().read(&mut buffer, offset)?;
 
         let buffer_offset = offset as usize % SECTOR_SIZE;
         dest.copy_from_slice(&buffer[buffer_offset..buffer_offset + size_of::<T>()]);
@@ -194,19 +297,46 @@ impl<'a> LinuxPartition<'a> {
         Ok(item)
     }
 
+    /// Writes `item` at `offset` (relative to the partition start). The item
+    /// must not cross a sector boundary, the rest of the sector is preserved.
+    fn write_to_offset<T>(&self, offset: u64, item: &T) -> Result<(), DeviceError> {
+        let partition_offset = self.first_sector * SECTOR_SIZE as u64;
+        let offset = offset + partition_offset;
+        let sector_offset = offset - offset % SECTOR_SIZE as u64;
+        let src =
+            unsafe { core::slice::from_raw_parts(item as *const T as *const u8, size_of::<T>()) };
+        let mut buffer = [0u8; SECTOR_SIZE];
+        let mut device = self.device.borrow_mut();
+        device.read_sync(&mut buffer, sector_offset)?;
+        let buffer_offset = offset as usize % SECTOR_SIZE;
+        buffer[buffer_offset..buffer_offset + size_of::<T>()].copy_from_slice(src);
+        device.write_sync(&buffer, sector_offset)
+    }
+
     pub fn read_datablock(&self, block_id: u64) -> Result<DataBlock, DeviceError> {
         let mut data = vec![0u8; self.superblock.get_block_size() as usize];
         let partition_offset = self.first_sector * SECTOR_SIZE as u64;
         let block_start = core::cmp::max(2048, self.superblock.get_block_size() * block_id);
         let offset = partition_offset + block_start;
-        self.device.borrow_mut().read_sync(&mut data, offset)?;
+        self.device.borrow_mut().read(&mut data, offset)?;
         let block = DataBlock { data };
         Ok(block)
     }
 
+    pub fn write_datablock(&self, block_id: u64, block: &DataBlock) -> Result<(), DeviceError> {
+        let partition_offset = self.first_sector * SECTOR_SIZE as u64;
+        let offset = partition_offset + self.get_block_size() * block_id;
+        self.device.borrow_mut().write_sync(&block.data, offset)
+    }
+
+    /// Persiste en el disco las escrituras que el dispositivo tenga en caché
+    pub fn flush(&self) -> Result<(), DeviceError> {
+        self.device.borrow_mut().flush()
+    }
+
     fn read_superblock(device: &mut BlockDevice, offset: u64) -> Result<Superblock, DeviceError> {
         let mut superblock_data = [0u8; SECTOR_SIZE];
-        device.read_sync(&mut superblock_data, offset)?;
+        device.read(&mut superblock_data, offset)?;
         let mut superblock = Superblock::default();
         unsafe {
             core::ptr::copy_nonoverlapping(
@@ -230,6 +360,389 @@ impl<'a> LinuxPartition<'a> {
     }
 }
 
+/// Allocation, directories and file growth
+impl LinuxPartition<'_> {
+    /// Allocates a zeroed block, searching the block bitmaps group by group
+    pub fn allocate_block(&mut self) -> Result<u32, DeviceError> {
+        let blocks_per_group = self.superblock.s_blocks_per_group as u64;
+        let first_data_block = self.superblock.s_first_data_block as u64;
+        for group_no in 0..self.get_groups_count() {
+            let mut group = self.read_group(group_no)?;
+            if group.bg_free_blocks_count == 0 {
+                continue;
+            }
+            let group_start = group_no * blocks_per_group;
+            let blocks_in_group = core::cmp::min(
+                blocks_per_group,
+                self.superblock.s_blocks_count_lo as u64 - first_data_block - group_start,
+            );
+            let bitmap_block = group.bg_block_bitmap as u64;
+            if let Some(index) = self.allocate_bit(bitmap_block, 0, blocks_in_group as usize)? {
+                group.bg_free_blocks_count -= 1;
+                self.write_group(group_no, &group)?;
+                self.superblock.s_free_blocks_count_lo -= 1;
+                self.write_superblock()?;
+                let block_id = first_data_block + group_start + index as u64;
+                let zeroes = DataBlock {
+                    data: vec![0; self.get_block_size() as usize],
+                };
+                self.write_datablock(block_id, &zeroes)?;
+                return Ok(block_id as u32);
+            }
+        }
+        Err(DeviceError::NoSpace)
+    }
+
+    pub fn free_block(&mut self, block_id: u32) -> Result<(), DeviceError> {
+        let blocks_per_group = self.superblock.s_blocks_per_group as u64;
+        let relative_id = block_id as u64 - self.superblock.s_first_data_block as u64;
+        let group_no = relative_id / blocks_per_group;
+        let mut group = self.read_group(group_no)?;
+        let index = (relative_id % blocks_per_group) as usize;
+        if self.free_bit(group.bg_block_bitmap as u64, index)? {
+            group.bg_free_blocks_count += 1;
+            self.write_group(group_no, &group)?;
+            self.superblock.s_free_blocks_count_lo += 1;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Allocates an inode, searching the inode bitmaps group by group.
+    /// Reserved inodes (below `s_first_ino`) are never returned.
+    pub fn allocate_inode(&mut self, is_dir: bool) -> Result<u32, DeviceError> {
+        let inodes_per_group = self.superblock.s_inodes_per_group as u64;
+        let first_inode = self.get_first_inode() as u64;
+        for group_no in 0..self.get_groups_count() {
+            let mut group = self.read_group(group_no)?;
+            if group.bg_free_inodes_count == 0 {
+                continue;
+            }
+            let bitmap_block = group.bg_inode_bitmap as u64;
+            let group_start = group_no * inodes_per_group;
+            let first_index = (first_inode - 1).saturating_sub(group_start) as usize;
+            let limit = inodes_per_group as usize;
+            if let Some(index) = self.allocate_bit(bitmap_block, first_index, limit)? {
+                group.bg_free_inodes_count -= 1;
+                if is_dir {
+                    group.bg_used_dirs_count += 1;
+                }
+                self.write_group(group_no, &group)?;
+                self.superblock.s_free_inodes_count -= 1;
+                self.write_superblock()?;
+                return Ok((group_no * inodes_per_group + index as u64 + 1) as u32);
+            }
+        }
+        Err(DeviceError::NoSpace)
+    }
+
+    pub fn free_inode(&mut self, inode_id: u32, is_dir: bool) -> Result<(), DeviceError> {
+        let inodes_per_group = self.superblock.s_inodes_per_group as u64;
+        let index = inode_id as u64 - 1;
+        let group_no = index / inodes_per_group;
+        let mut group = self.read_group(group_no)?;
+        let bit = (index % inodes_per_group) as usize;
+        if self.free_bit(group.bg_inode_bitmap as u64, bit)? {
+            group.bg_free_inodes_count += 1;
+            if is_dir {
+                group.bg_used_dirs_count -= 1;
+            }
+            self.write_group(group_no, &group)?;
+            self.superblock.s_free_inodes_count += 1;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Finds the first clear bit in `[first, limit)` of the bitmap block,
+    /// sets it and returns its index
+    fn allocate_bit(
+        &self,
+        bitmap_block: u64,
+        first: usize,
+        limit: usize,
+    ) -> Result<Option<usize>, DeviceError> {
+        let mut bitmap = self.read_datablock(bitmap_block)?;
+        let limit = core::cmp::min(limit, bitmap.data.len() * 8);
+        let Some(index) = (first..limit).find(|i| bitmap.data[i / 8] & (1 << (i % 8)) == 0) else {
+            return Ok(None);
+        };
+        bitmap.data[index / 8] |= 1 << (index % 8);
+        self.write_datablock(bitmap_block, &bitmap)?;
+        Ok(Some(index))
+    }
+
+    /// Clears a bit of the bitmap block, returns false if it was already clear
+    fn free_bit(&self, bitmap_block: u64, index: usize) -> Result<bool, DeviceError> {
+        let mut bitmap = self.read_datablock(bitmap_block)?;
+        let mask = 1 << (index % 8);
+        if bitmap.data[index / 8] & mask == 0 {
+            return Ok(false);
+        }
+        bitmap.data[index / 8] &= !mask;
+        self.write_datablock(bitmap_block, &bitmap)?;
+        Ok(true)
+    }
+
+    fn write_superblock(&self) -> Result<(), DeviceError> {
+        self.write_to_offset(SUPERBLOCK_OFFSET, &self.superblock)
+    }
+
+    /// Number of block ids that fit in an indirect block
+    fn get_pointers_per_block(&self) -> usize {
+        self.get_block_size() as usize / size_of::<u32>()
+    }
+
+    /// Splits a file block number into the `i_block` slot and the indexes
+    /// inside each level of indirect blocks (see `get_block_id`)
+    fn get_block_path(&self, block_no: usize) -> (usize, Vec<usize>) {
+        let entries = self.get_pointers_per_block();
+        if block_no < INODE_SINGLE_INDIRECT {
+            return (block_no, Vec::new());
+        }
+        let block_no = block_no - INODE_SINGLE_INDIRECT;
+        if block_no < entries {
+            return (INODE_SINGLE_INDIRECT, vec![block_no]);
+        }
+        let block_no = block_no - entries;
+        if block_no < entries * entries {
+            return (
+                INODE_DOUBLE_INDIRECT,
+                vec![block_no / entries, block_no % entries],
+            );
+        }
+        let block_no = block_no - entries * entries;
+        (
+            INODE_TRIPLE_INDIRECT,
+            vec![
+                block_no / (entries * entries),
+                (block_no / entries) % entries,
+                block_no % entries,
+            ],
+        )
+    }
+
+    /// Returns the block id holding the `block_no`th block of the file. If
+    /// `allocate` is set, missing data and indirect blocks are allocated and
+    /// accounted in `i_blocks`, otherwise missing blocks return `None`.
+    pub fn map_file_block(
+        &mut self,
+        inode: &mut Inode,
+        block_no: usize,
+        allocate: bool,
+    ) -> Result<Option<u32>, DeviceError> {
+        let sectors_per_block = (self.get_block_size() / I_BLOCKS_SECTOR_SIZE) as u32;
+        let (slot, indexes) = self.get_block_path(block_no);
+        let mut block_id = inode.i_block[slot];
+        if block_id == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            block_id = self.allocate_block()?;
+            inode.i_block[slot] = block_id;
+            inode.i_blocks += sectors_per_block;
+        }
+        for index in indexes {
+            let mut table = self.read_datablock(block_id as u64)?;
+            let offset = index * size_of::<u32>();
+            let mut next_id = unsafe { table.read::<u32>(offset) }.unwrap_or(0);
+            if next_id == 0 {
+                if !allocate {
+                    return Ok(None);
+                }
+                next_id = self.allocate_block()?;
+                unsafe { table.write(offset, &next_id) };
+                self.write_datablock(block_id as u64, &table)?;
+                inode.i_blocks += sectors_per_block;
+            }
+            block_id = next_id;
+        }
+        Ok(Some(block_id))
+    }
+
+    /// Frees every block of the file from `first_block` onwards, including
+    /// indirect blocks that become empty
+    pub fn free_file_blocks(
+        &mut self,
+        inode: &mut Inode,
+        first_block: usize,
+    ) -> Result<(), DeviceError> {
+        let sectors_per_block = (self.get_block_size() / I_BLOCKS_SECTOR_SIZE) as u32;
+        let entries = self.get_pointers_per_block();
+        let mut freed = 0;
+        for slot in first_block..INODE_SINGLE_INDIRECT {
+            if inode.i_block[slot] != 0 {
+                self.free_block(inode.i_block[slot])?;
+                inode.i_block[slot] = 0;
+                freed += 1;
+            }
+        }
+        let mut tree_start = INODE_SINGLE_INDIRECT;
+        let mut tree_span = entries;
+        let indirect_slots = [
+            INODE_SINGLE_INDIRECT,
+            INODE_DOUBLE_INDIRECT,
+            INODE_TRIPLE_INDIRECT,
+        ];
+        for (level, &slot) in indirect_slots.iter().enumerate() {
+            let root = inode.i_block[slot];
+            if root != 0 && first_block < tree_start + tree_span {
+                let first = first_block.saturating_sub(tree_start);
+                if self.free_subtree(root, level + 1, first, &mut freed)? {
+                    inode.i_block[slot] = 0;
+                }
+            }
+            tree_start += tree_span;
+            tree_span *= entries;
+        }
+        inode.i_blocks -= freed * sectors_per_block;
+        Ok(())
+    }
+
+    /// Frees the blocks below the indirect block `block_id` whose index
+    /// (relative to this subtree) is at least `first`. Returns true when the
+    /// whole subtree, including `block_id`, was freed.
+    fn free_subtree(
+        &mut self,
+        block_id: u32,
+        level: usize,
+        first: usize,
+        freed: &mut u32,
+    ) -> Result<bool, DeviceError> {
+        if level == 0 {
+            if first > 0 {
+                return Ok(false);
+            }
+            self.free_block(block_id)?;
+            *freed += 1;
+            return Ok(true);
+        }
+        let entries = self.get_pointers_per_block();
+        let child_span = entries.pow(level as u32 - 1);
+        let mut table = self.read_datablock(block_id as u64)?;
+        let mut modified = false;
+        for index in 0..entries {
+            let offset = index * size_of::<u32>();
+            let child_id = unsafe { table.read::<u32>(offset) }.unwrap_or(0);
+            let child_start = index * child_span;
+            if child_id == 0 || child_start + child_span <= first {
+                continue;
+            }
+            let child_first = first.saturating_sub(child_start);
+            if self.free_subtree(child_id, level - 1, child_first, freed)? {
+                unsafe { table.write(offset, &0u32) };
+                modified = true;
+            }
+        }
+        if first == 0 {
+            self.free_block(block_id)?;
+            *freed += 1;
+            return Ok(true);
+        }
+        if modified {
+            self.write_datablock(block_id as u64, &table)?;
+        }
+        Ok(false)
+    }
+
+    /// Adds an entry named `name` to the directory `dir`, reusing the slack
+    /// space of an existing entry or growing the directory by one block.
+    /// Assumes the `filetype` feature, which `mke2fs` enables by default.
+    pub fn add_dir_entry(
+        &mut self,
+        dir_id: u32,
+        dir: &mut Inode,
+        name: &str,
+        inode_id: u32,
+        file_type: Ext2Filetype,
+    ) -> Result<(), DeviceError> {
+        let needed = dir_entry_size(name.len());
+        let block_size = self.get_block_size() as usize;
+        let mut block_no = 0;
+        while let Some(block_id) = self.map_file_block(dir, block_no, false)? {
+            let mut block = self.read_datablock(block_id as u64)?;
+            let mut offset = 0;
+            while let Some(entry) = unsafe { block.read::<DirectoryEntry>(offset) } {
+                if entry.rec_len == 0 {
+                    break;
+                }
+                let rec_len = entry.rec_len as usize;
+                let used = if entry.inode == 0 {
+                    0
+                } else {
+                    dir_entry_size(entry.name_len as usize)
+                };
+                if rec_len - used >= needed {
+                    let new_offset = if used == 0 {
+                        offset
+                    } else {
+                        let mut shrunk = entry;
+                        shrunk.rec_len = used as u16;
+                        unsafe { block.write(offset, &shrunk) };
+                        offset + used
+                    };
+                    let new_rec_len = rec_len - used;
+                    block.write_dir_entry(new_offset, new_rec_len, name, inode_id, file_type);
+                    return self.write_datablock(block_id as u64, &block);
+                }
+                offset += rec_len;
+            }
+            block_no += 1;
+        }
+        let block_id = self.map_file_block(dir, block_no, true)?.unwrap();
+        let mut block = self.read_datablock(block_id as u64)?;
+        block.write_dir_entry(0, block_size, name, inode_id, file_type);
+        self.write_datablock(block_id as u64, &block)?;
+        dir.i_size += block_size as u32;
+        self.write_inode(dir_id as u64, dir)
+    }
+
+    /// Removes the entry named `name` from the directory and returns its
+    /// inode. The space is merged into the previous entry of the block, or
+    /// marked as unused if it is the first one.
+    pub fn remove_dir_entry(&mut self, dir: &mut Inode, name: &str) -> Result<u32, DeviceError> {
+        let mut block_no = 0;
+        while let Some(block_id) = self.map_file_block(dir, block_no, false)? {
+            let mut block = self.read_datablock(block_id as u64)?;
+            let mut offset = 0;
+            let mut previous: Option<(usize, DirectoryEntry)> = None;
+            while let Some(entry) = unsafe { block.read::<DirectoryEntry>(offset) } {
+                if entry.rec_len == 0 {
+                    break;
+                }
+                let name_offset = offset + size_of::<DirectoryEntry>();
+                let entry_name = &block.data[name_offset..name_offset + entry.name_len as usize];
+                if entry.inode != 0 && entry_name == name.as_bytes() {
+                    let inode_id = entry.inode;
+                    match previous {
+                        Some((previous_offset, mut previous_entry)) => {
+                            previous_entry.rec_len += entry.rec_len;
+                            unsafe { block.write(previous_offset, &previous_entry) };
+                        }
+                        None => {
+                            let mut unused = entry;
+                            unused.inode = 0;
+                            unsafe { block.write(offset, &unused) };
+                        }
+                    }
+                    self.write_datablock(block_id as u64, &block)?;
+                    return Ok(inode_id);
+                }
+                let rec_len = entry.rec_len as usize;
+                previous = Some((offset, entry));
+                offset += rec_len;
+            }
+            block_no += 1;
+        }
+        Err(DeviceError::EntryNotFound)
+    }
+}
+
+/// Size of a directory entry with a name of `name_len` bytes
+fn dir_entry_size(name_len: usize) -> usize {
+    (size_of::<DirectoryEntry>() + name_len).next_multiple_of(DIR_ENTRY_ALIGN)
+}
+
 impl Superblock {
     fn get_block_size(&self) -> u64 {
         1024 << self.s_log_block_size
@@ -257,13 +770,25 @@ pub struct DirectoryIterator<'a> {
 }
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct DirectoryEntry {
     inode: u32,
     rec_len: u16,
     name_len: u8,
     file_type: Ext2Filetype,
 }
+
+impl DirectoryEntry {
+    /// Inode referenced by this entry, 0 if the entry is unused
+    pub fn get_inode_id(&self) -> u32 {
+        self.inode
+    }
+
+    pub fn get_file_type(&self) -> Ext2Filetype {
+        self.file_type
+    }
+}
+
 impl DataBlock {
     pub fn iter_directories(&self) -> DirectoryIterator<'_> {
         DirectoryIterator {
@@ -286,6 +811,39 @@ impl DataBlock {
         let item = item.assume_init();
         Some(item)
     }
+
+    /// Writes `item` at `offset`, ignoring writes past the end of the block
+    pub unsafe fn write<T>(&mut self, offset: usize, item: &T) {
+        let item_size = size_of::<T>();
+        if offset + item_size > self.data.len() {
+            return;
+        }
+        core::ptr::copy_nonoverlapping(
+            item as *const T as *const u8,
+            self.data.as_mut_ptr().add(offset),
+            item_size,
+        );
+    }
+
+    /// Writes a directory entry header followed by its name
+    fn write_dir_entry(
+        &mut self,
+        offset: usize,
+        rec_len: usize,
+        name: &str,
+        inode: u32,
+        file_type: Ext2Filetype,
+    ) {
+        let entry = DirectoryEntry {
+            inode,
+            rec_len: rec_len as u16,
+            name_len: name.len() as u8,
+            file_type,
+        };
+        unsafe { self.write(offset, &entry) };
+        let name_offset = offset + size_of::<DirectoryEntry>();
+        self.data[name_offset..name_offset + name.len()].copy_from_slice(name.as_bytes());
+    }
 }
 
 pub struct InodeBlockIterator<'a> {
@@ -295,16 +853,19 @@ pub struct InodeBlockIterator<'a> {
 }
 
 impl InodeBlockIterator<'_> {
-    pub fn get_entry_with_name(self, entry_name: &str) -> Option<DirectoryEntry> {
+    pub fn get_entry_with_name(
+        self,
+        entry_name: &str,
+    ) -> Result<Option<DirectoryEntry>, DeviceError> {
         for block in self {
-            if let Some((entry, _)) = block
+            if let Some((entry, _)) = block?
                 .iter_directories()
-                .find(|(_, name)| *name == entry_name)
+                .find(|(entry, name)| entry.inode != 0 && *name == entry_name)
             {
-                return Some(entry);
+                return Ok(Some(entry));
             }
         }
-        None
+        Ok(None)
     }
 
     /// Returns the nth block_id.
@@ -338,7 +899,10 @@ impl InodeBlockIterator<'_> {
     ///   `i_block[TRIPLE_INDIRECT][0][j][i]` is number `SINGLE_INDIRECT + entries * entries + entries * (j + 1) + i`)
     ///   `i_block[TRIPLE_INDIRECT][k][j][i]` is number `SINGLE_INDIRECT + entries * entries * k + entries * entries + entries * (1 + j) + i`)
     ///   which equals to `SINGLE_INDIRECT + entries * entries * (k + 1) + entries * (j + 1) + i`
-    fn get_block_id(&mut self, block_no: usize) -> Option<u32> {
+    ///
+    /// A block id of 0 means the block is not allocated. Read errors, like
+    /// `DeviceError::WouldBlock`, are returned to the caller
+    fn get_block_id(&mut self, block_no: usize) -> Result<u32, DeviceError> {
         let entries = self.partition.superblock.get_block_size() as usize / size_of::<u32>(); // each entry is 4 bytes
 
         let first_double_indirect_entry = INODE_SINGLE_INDIRECT + entries;
@@ -348,43 +912,47 @@ impl InodeBlockIterator<'_> {
             self.inode.i_block[self.current_idx]
         } else if block_no < first_double_indirect_entry {
             let indirect_block_id = self.inode.i_block[INODE_SINGLE_INDIRECT];
-            let data_block = self
-                .partition
-                .read_datablock(indirect_block_id as u64)
-                .ok()?;
+            if indirect_block_id == 0 {
+                return Ok(0);
+            }
+            let data_block = self.partition.read_datablock(indirect_block_id as u64)?;
             let offset = (self.current_idx - INODE_SINGLE_INDIRECT) * size_of::<u32>();
-            unsafe { data_block.read::<u32>(offset)? }
+            unsafe { data_block.read::<u32>(offset) }.unwrap_or(0)
         } else if block_no < first_triple_indirect_entry {
             let indirect_block_id = self.inode.i_block[INODE_DOUBLE_INDIRECT];
+            if indirect_block_id == 0 {
+                return Ok(0);
+            }
             let pointer_offset = (block_no - INODE_SINGLE_INDIRECT - entries) / entries;
-            let pointer_block = self
-                .partition
-                .read_datablock(indirect_block_id as u64)
-                .ok()?;
+            let pointer_block = self.partition.read_datablock(indirect_block_id as u64)?;
             let pointer_block_id =
-                unsafe { pointer_block.read::<u32>(pointer_offset * size_of::<u32>())? };
+                unsafe { pointer_block.read::<u32>(pointer_offset * size_of::<u32>()) }
+                    .unwrap_or(0);
+            if pointer_block_id == 0 {
+                return Ok(0);
+            }
 
             let pointer_offset = (block_no - INODE_SINGLE_INDIRECT) % entries;
-            let pointer_block = self
-                .partition
-                .read_datablock(pointer_block_id as u64)
-                .ok()?;
-            unsafe { pointer_block.read::<u32>(pointer_offset * size_of::<u32>())? }
+            let pointer_block = self.partition.read_datablock(pointer_block_id as u64)?;
+            unsafe { pointer_block.read::<u32>(pointer_offset * size_of::<u32>()) }.unwrap_or(0)
         } else {
             unimplemented!()
         };
-        Some(block_id)
+        Ok(block_id)
     }
 }
 
 impl Iterator for InodeBlockIterator<'_> {
-    type Item = DataBlock;
+    type Item = Result<DataBlock, DeviceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let block_id = self.get_block_id(self.current_idx)?;
+        let block_id = match self.get_block_id(self.current_idx) {
+            Ok(block_id) => block_id,
+            Err(error) => return Some(Err(error)),
+        };
         self.current_idx += 1;
         if block_id != 0 {
-            self.partition.read_datablock(block_id as u64).ok()
+            Some(self.partition.read_datablock(block_id as u64))
         } else {
             // TODO: support sparse files
             None
@@ -1,3 +1,4 @@
+pub mod dev_fs;
 mod ext2_fs_driver;
 pub mod linux;
 pub mod partition;
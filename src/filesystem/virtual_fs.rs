@@ -1,13 +1,80 @@
+use crate::devices::virtio::block_device::{BlockDevice, READ_CACHE_SIZE};
 use crate::devices::virtio::common::DeviceManager;
 use crate::devices::DeviceId;
-use crate::filesystem::ext2_fs_driver::Ext2FilesystemDriver;
+use crate::filesystem::dev_fs::DeviceFsDriver;
+use crate::filesystem::ext2_fs_driver::{Ext2File, Ext2FilesystemDriver};
+use crate::system::process::Pid;
 use crate::utils::error::IoResult;
+use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use core::cell::UnsafeCell;
+use core::cell::{RefCell, UnsafeCell};
+
+/// Tamaño de los bloques que se usan para leer un archivo completo
+const READ_CHUNK_SIZE: usize = 0x10000;
+/// Máximo de bytes de una lectura que puede bloquear al proceso. La syscall
+/// se reintenta desde el principio, y termina recién cuando todos los bloques
+/// que toca están en la caché del disco al mismo tiempo. Con un cuarto de la
+/// caché en bloques de 1 KiB (los más chicos de ext2) queda lugar para los
+/// bloques de índices y para las lecturas de otros procesos
+const NONBLOCKING_READ_SIZE: usize = READ_CACHE_SIZE / 4 * 1024;
 
 pub trait FilesystemDriver {
     fn open(&self, path: &str) -> IoResult<FileDescriptor>;
+    /// Lee el archivo a partir de `offset`, devuelve la cantidad de bytes leídos
+    fn read(&self, fd: &FileDescriptor, buf: &mut [u8], offset: u64) -> IoResult<usize>;
+    /// Metadatos del archivo
+    fn stat(&self, fd: &FileDescriptor) -> IoResult<FileStat>;
+    /// Entradas del directorio abierto en `fd`
+    fn readdir(&self, fd: &FileDescriptor) -> IoResult<Vec<DirEntry>>;
+    /// Libera los recursos asociados al descriptor
+    fn close(&self, fd: FileDescriptor) -> IoResult<()>;
+    /// Escribe el archivo a partir de `offset`, creciendo si hace falta.
+    /// Devuelve la cantidad de bytes escritos
+    fn write(&self, fd: &FileDescriptor, buf: &[u8], offset: u64) -> IoResult<usize>;
+    /// Crea un archivo regular vacío y lo abre
+    fn create(&self, path: &str) -> IoResult<FileDescriptor>;
+    /// Cambia el tamaño del archivo, liberando o reservando bloques
+    fn truncate(&self, fd: &FileDescriptor, size: u64) -> IoResult<()>;
+    /// Borra la entrada del directorio, y el archivo si no quedan enlaces
+    fn unlink(&self, path: &str) -> IoResult<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Symlink,
+    Unknown,
+}
+
+/// Metadatos de un archivo, similar a `struct stat`
+#[derive(Debug)]
+pub struct FileStat {
+    pub inode: u64,
+    pub file_type: FileType,
+    /// Permisos y tipo de archivo, como en `st_mode`
+    pub mode: u16,
+    pub links: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u64,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+}
+
+/// Entrada de un directorio
+#[derive(Debug)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub name: String,
+    pub file_type: FileType,
 }
 
 #[derive(Debug, Default)]
@@ -17,6 +84,8 @@ pub enum FilesystemType {
         device_id: DeviceId,
         partition_id: u8,
     },
+    /// Nodos de los dispositivos de caracteres
+    Devices,
     #[default]
     Unknown,
 }
@@ -33,11 +102,14 @@ struct VirtualFilesystem {
     null_mountpoint: MountPoint,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FileDescriptor {
     pub path: String,
     pub file_pos: usize,
     pub eof_flag: bool,
+    /// Archivo abierto en una partición ext2, `None` en los demás sistemas
+    /// de archivos
+    pub ext2: Option<Ext2File>,
 }
 
 static VIRTUAL_FILESYSTEM: VirtualFsManager = VirtualFsManager::empty();
@@ -74,6 +146,113 @@ impl VirtualFsManager {
         let driver = virtfs.get_driver(&mount_point.fs_type);
         driver.open(path)
     }
+
+    pub fn read(fd: &FileDescriptor, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.read(fd, buf, offset)
+    }
+
+    /// Lee el archivo en nombre del proceso `pid`. Si hay que esperar al
+    /// disco, devuelve `DeviceError::WouldBlock` en lugar de bloquear la CPU y
+    /// el proceso se despierta cuando los datos están disponibles. Puede leer
+    /// menos de lo pedido (ver `NONBLOCKING_READ_SIZE`)
+    pub fn read_nonblocking(
+        fd: &FileDescriptor,
+        buf: &mut [u8],
+        offset: u64,
+        pid: Pid,
+    ) -> IoResult<usize> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        if let FilesystemType::Devices = mount_point.fs_type {
+            return DeviceFsDriver::new(Some(pid)).read(fd, buf, offset);
+        }
+        let Some(device) = virtfs.get_device(&mount_point.fs_type) else {
+            return VirtualFsManager::read(fd, buf, offset);
+        };
+        device.borrow_mut().set_waiter(Some(pid));
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        let len = buf.len().min(NONBLOCKING_READ_SIZE);
+        let result = driver.read(fd, &mut buf[..len], offset);
+        device.borrow_mut().set_waiter(None);
+        result
+    }
+
+    pub fn stat(fd: &FileDescriptor) -> IoResult<FileStat> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.stat(fd)
+    }
+
+    pub fn readdir(fd: &FileDescriptor) -> IoResult<Vec<DirEntry>> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.readdir(fd)
+    }
+
+    pub fn close(fd: FileDescriptor) -> IoResult<()> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.close(fd)
+    }
+
+    pub fn write(fd: &FileDescriptor, buf: &[u8], offset: u64) -> IoResult<usize> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.write(fd, buf, offset)
+    }
+
+    pub fn create(path: &str) -> IoResult<FileDescriptor> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.create(path)
+    }
+
+    pub fn truncate(fd: &FileDescriptor, size: u64) -> IoResult<()> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(&fd.path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.truncate(fd, size)
+    }
+
+    pub fn unlink(path: &str) -> IoResult<()> {
+        let vfs_ptr = VIRTUAL_FILESYSTEM.virtual_fs.get();
+        let virtfs = unsafe { (*vfs_ptr).as_mut().unwrap() };
+        let mount_point = virtfs.get_mount_point(path);
+        let driver = virtfs.get_driver(&mount_point.fs_type);
+        driver.unlink(path)
+    }
+
+    /// Lee el contenido completo de un archivo
+    pub fn read_to_end(path: &str) -> IoResult<Vec<u8>> {
+        let fd = VirtualFsManager::open(path)?;
+        let mut data = Vec::new();
+        let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+        loop {
+            let read = VirtualFsManager::read(&fd, &mut chunk, data.len() as u64)?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&chunk[..read]);
+        }
+        VirtualFsManager::close(fd)?;
+        Ok(data)
+    }
 }
 
 unsafe impl Sync for VirtualFsManager {}
@@ -83,23 +262,40 @@ impl VirtualFilesystem {
         let res = self
             .mount_points
             .iter()
-            .rfind(|&mp| path.starts_with(&mp.path));
+            .rfind(|&mp| is_under(path, &mp.path));
         res.unwrap_or(&self.null_mountpoint)
     }
 
-    fn get_driver(&self, fs_type: &FilesystemType) -> impl FilesystemDriver {
+    fn get_device(&self, fs_type: &FilesystemType) -> Option<&'static RefCell<BlockDevice>> {
+        match fs_type {
+            FilesystemType::Ext3 { device_id, .. } => DeviceManager::get_block_device(*device_id),
+            _ => None,
+        }
+    }
+
+    fn get_driver(&self, fs_type: &FilesystemType) -> Box<dyn FilesystemDriver> {
         match fs_type {
             FilesystemType::Ext3 {
                 device_id,
                 partition_id,
             } => {
-                if let Some(device) = DeviceManager::get_device(*device_id) {
-                    Ext2FilesystemDriver::new(device, *partition_id)
+                if let Some(device) = DeviceManager::get_block_device(*device_id) {
+                    Box::new(Ext2FilesystemDriver::new(device, *partition_id))
                 } else {
                     unimplemented!("Device not found")
                 }
             }
+            FilesystemType::Devices => Box::new(DeviceFsDriver::new(None)),
             _ => unimplemented!(),
         }
     }
 }
+
+/// `path` es `mount_path` o está dentro de él: `/dev/hvc0` está en `/dev`,
+/// pero `/devices` no
+fn is_under(path: &str, mount_path: &str) -> bool {
+    match path.strip_prefix(mount_path) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || mount_path.ends_with('/'),
+        None => false,
+    }
+}
@@ -1,11 +1,16 @@
 use crate::assembly::riscv64;
+use crate::boot::mount_root;
+use crate::cpu::riscv64::plic;
 use crate::cpu::riscv64::trap::TrapFrame;
 use crate::devices::dtb::DtbReader;
 use crate::devices::uart_16550::Uart;
+use crate::devices::virtio::common::DeviceManager;
 use crate::mmu::map_table::MapTable;
 use crate::mmu::riscv64::{PageTable, GLOBAL_PAGE_TABLE};
 use crate::mmu::{HEAP_SIZE, HEAP_START};
+use crate::net::NetworkStack;
 use crate::system::process;
+use crate::system::random::EntropyPool;
 use crate::{kmain, mmu};
 use crate::{print, println};
 use alloc::boxed::Box;
@@ -21,6 +26,11 @@ extern "C" {
 
 pub static mut DTB_ADDRESS: *const u8 = null();
 
+/// Ejecutable que se usa como proceso `init` si los `bootargs` no indican otro
+const DEFAULT_INIT_PATH: &str = "/sbin/init";
+/// Bit `MEIE` de `mie`: interrupciones externas de máquina
+const MIE_MEIE: usize = 1 << 11;
+
 #[no_mangle]
 unsafe extern "C" fn supervisor_mode_init() -> ! {
     // armo el registro `mstatus` para volver en modo usuario
@@ -56,7 +66,8 @@ unsafe extern "C" fn supervisor_mode_init() -> ! {
 pub unsafe extern "C" fn user_mode_init(process_pc: usize, sp: usize) -> ! {
     // bits[11::12] = 0 -> Usermode
     let status = (1 << 7) | (1 << 5) | (0b01 << 13);
-    let interrupts = 0xa0a;
+    // 1 << 7: timer interrupts de máquina, que disparan el planificador
+    let interrupts = 0xa0a | (1 << 7);
     let delegate_mask = (1 << 1) | (1 << 5) | (1 << 9);
     riscv64::mstatus_write(status);
     riscv64::mepc_write(process_pc);
@@ -102,7 +113,7 @@ pub extern "C" fn kinit() {
     mmu::print_mem_info();
     println!("\x1b[1m<Finish>\x1b[0m");
     #[cfg(test)]
-    test_main();
+    crate::test_main();
     let mut map_table;
     let page_table = GLOBAL_PAGE_TABLE.get_root();
     map_table = Box::new(MapTable::new(page_table));
@@ -116,5 +127,25 @@ pub extern "C" fn kinit() {
     };
     mmu::print_mem_info();
     println!("\x1b[1m<Finish>\x1b[0m");
-    process::init(page_table)
+    println!("Setting up interrupts and PLIC...");
+    plic::init();
+    // Las interrupciones siguen deshabilitadas en `mstatus`, pero con `MEIE`
+    // el `wfi` de las lecturas del disco despierta cuando termina el pedido
+    unsafe { riscv64::mie_write(MIE_MEIE) };
+    DeviceManager::init();
+    EntropyPool::init();
+    mount_root();
+    NetworkStack::init();
+    let init_path = dtb
+        .get_bootargs()
+        .and_then(get_init_path)
+        .unwrap_or(DEFAULT_INIT_PATH);
+    process::init(page_table, init_path)
+}
+
+/// Busca el argumento `init=<path>` entre los `bootargs`
+fn get_init_path(bootargs: &str) -> Option<&str> {
+    bootargs
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("init="))
 }
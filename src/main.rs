@@ -26,6 +26,7 @@ mod devices;
 mod filesystem;
 mod init;
 mod mmu;
+mod net;
 mod system;
 
 #[cfg(test)]
@@ -50,16 +51,7 @@ extern "C" fn kmain() {
     //mscratch_read(); //-> tira instruction fault
     //sscratch_read(); // OK porque estoy en supervisor
     println!("Setting up interrupts and PLIC...");
-    // We lower the threshold wall so our interrupts can jump over it.
-    plic::set_threshold(0);
-    // VIRTIO = [1..8]
-    // UART0 = 10
-    // PCIE = [32..35]
-    // Enable the UART interrupt.
-    for i in 1..=10 {
-        plic::enable(i);
-        plic::set_priority(i, 1);
-    }
+    plic::init();
     DeviceManager::init();
     load_disk().unwrap();
     println!("Press any key...");
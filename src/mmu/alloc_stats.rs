@@ -0,0 +1,180 @@
+//! # Estadísticas de memoria
+//! La tabla de páginas cuenta las páginas reservadas, el máximo alcanzado y
+//! cuántas pertenecen a cada subsistema. Cada reserva se etiqueta con el
+//! subsistema activo (ver `AllocTag::enter`), y la etiqueta se guarda en los
+//! bits altos de la primera página para descontarla al liberar.
+//!
+//! En modo debug (`PageTable::track_call_sites`) además se guarda en qué
+//! línea del código se hizo cada reserva explícita de páginas, para encontrar
+//! las pérdidas de memoria. Las páginas del heap (`Box`, `Vec` y los slabs)
+//! quedan sin lugar, porque ahí quien reserva es siempre el allocator.
+use core::fmt;
+use core::panic::Location;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Subsistema al que se le cuentan las páginas reservadas. Los objetos chicos
+/// también se cuentan por subsistema, ya que cada uno tiene sus propios slabs
+/// (ver `SlabCaches`), pero se cuentan las páginas de sus slabs y no los bytes
+/// de cada objeto
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocTag {
+    Kernel = 0,
+    Vfs,
+    Virtio,
+    Process,
+    Net,
+}
+
+pub const ALLOC_TAGS: usize = 5;
+
+/// Etiqueta de las reservas actuales. Las reservas se hacen con las
+/// interrupciones deshabilitadas, así que alcanza con una sola
+static CURRENT_TAG: AtomicU8 = AtomicU8::new(AllocTag::Kernel as u8);
+
+impl AllocTag {
+    pub const ALL: [AllocTag; ALLOC_TAGS] = [
+        AllocTag::Kernel,
+        AllocTag::Vfs,
+        AllocTag::Virtio,
+        AllocTag::Process,
+        AllocTag::Net,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AllocTag::Kernel => "kernel",
+            AllocTag::Vfs => "vfs",
+            AllocTag::Virtio => "virtio",
+            AllocTag::Process => "process",
+            AllocTag::Net => "net",
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        AllocTag::ALL
+            .get(bits as usize)
+            .copied()
+            .unwrap_or(AllocTag::Kernel)
+    }
+
+    /// Etiqueta con la que se cuentan las reservas actuales
+    pub fn current() -> Self {
+        AllocTag::from_bits(CURRENT_TAG.load(Ordering::Relaxed))
+    }
+
+    /// Cuenta las reservas a nombre de este subsistema hasta que se destruya
+    /// el guard, que restaura la etiqueta anterior
+    pub fn enter(self) -> TagGuard {
+        let previous = CURRENT_TAG.swap(self as u8, Ordering::Relaxed);
+        TagGuard { previous }
+    }
+}
+
+pub struct TagGuard {
+    previous: u8,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// Contadores de páginas de una tabla. También sirven de *snapshot*: con
+/// `diff` se obtiene lo que se reservó y no se liberó entre dos de ellos
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Páginas que se pueden reservar
+    pub total: usize,
+    /// Páginas reservadas
+    pub allocated: usize,
+    /// Máximo de páginas reservadas al mismo tiempo
+    pub peak: usize,
+    /// Páginas reservadas de cada subsistema, indexadas por `AllocTag`
+    pub by_tag: [usize; ALLOC_TAGS],
+    /// Llamadas a `alloc` y `dealloc`
+    pub allocs: usize,
+    pub deallocs: usize,
+}
+
+impl AllocStats {
+    pub fn free(&self) -> usize {
+        self.total - self.allocated
+    }
+
+    pub fn tag_pages(&self, tag: AllocTag) -> usize {
+        self.by_tag[tag as usize]
+    }
+
+    pub(crate) fn record_alloc(&mut self, pages: usize, tag: AllocTag) {
+        self.allocated += pages;
+        self.peak = self.peak.max(self.allocated);
+        self.by_tag[tag as usize] += pages;
+        self.allocs += 1;
+    }
+
+    pub(crate) fn record_dealloc(&mut self, pages: usize, tag: AllocTag) {
+        self.allocated -= pages;
+        self.by_tag[tag as usize] -= pages;
+        self.deallocs += 1;
+    }
+
+    /// Cambios desde el snapshot `before`
+    pub fn diff(&self, before: &AllocStats) -> AllocDiff {
+        let mut by_tag = [0; ALLOC_TAGS];
+        for (i, pages) in by_tag.iter_mut().enumerate() {
+            *pages = self.by_tag[i] as isize - before.by_tag[i] as isize;
+        }
+        AllocDiff {
+            pages: self.allocated as isize - before.allocated as isize,
+            by_tag,
+            allocs: self.allocs - before.allocs,
+            deallocs: self.deallocs - before.deallocs,
+        }
+    }
+}
+
+/// Diferencia entre dos snapshots
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocDiff {
+    /// Páginas reservadas de más (o liberadas, si es negativo)
+    pub pages: isize,
+    pub by_tag: [isize; ALLOC_TAGS],
+    pub allocs: usize,
+    pub deallocs: usize,
+}
+
+impl AllocDiff {
+    /// Verdadero si se liberaron todas las páginas reservadas en el medio
+    pub fn is_balanced(&self) -> bool {
+        self.by_tag.iter().all(|pages| *pages == 0)
+    }
+}
+
+impl fmt::Display for AllocDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:+} pages ({} allocs, {} deallocs)",
+            self.pages, self.allocs, self.deallocs
+        )?;
+        for tag in AllocTag::ALL {
+            let pages = self.by_tag[tag as usize];
+            if pages != 0 {
+                write!(f, " {}: {:+}", tag.name(), pages)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Una reserva viva de la tabla de páginas
+pub struct Allocation {
+    pub address: usize,
+    pub pages: usize,
+    pub tag: AllocTag,
+    /// Dónde se reservó, si estaba activo el modo debug y no es una página
+    /// del heap
+    pub call_site: Option<&'static Location<'static>>,
+}
@@ -4,10 +4,13 @@ use crate::{print, println};
 use core::fmt::{Debug, Formatter};
 
 use crate::assembly::riscv64;
+use alloc::vec::Vec;
 use core::ptr::NonNull;
-use core::slice::from_raw_parts_mut;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
 
 const SATP_MODE_SV39: usize = 8 << 60;
+/// Bits de una entrada que no forman parte de la dirección
+const ENTRY_FLAGS_MASK: i64 = 0x3ff;
 
 #[repr(i64)]
 #[derive(Copy, Clone, Debug)]
@@ -59,6 +62,10 @@ impl Entry {
             != 0
     }
 
+    pub fn is_writable(&self) -> bool {
+        self.get_entry() & EntryBits::Write.val() != 0
+    }
+
     pub fn set_entry(&mut self, entry: i64) {
         self.entry = entry;
     }
@@ -66,6 +73,11 @@ impl Entry {
     pub fn get_entry(&self) -> i64 {
         self.entry
     }
+
+    /// Dirección física a la que apunta la entrada (página o tabla hija)
+    pub fn get_address(&self) -> usize {
+        ((self.get_entry() & !0x3ff) << 2) as usize
+    }
 }
 
 impl Default for Entry {
@@ -161,8 +173,52 @@ impl<'a> MapTable<'a> {
         }
     }
 
+    /// Recorre todas las hojas válidas de la tabla. Para cada una llama a `f`
+    /// con la dirección virtual que mapea, la entrada y el nivel de la página.
+    ///
+    /// Las direcciones virtuales no se extienden en signo, por lo que sólo son
+    /// correctas para la mitad inferior del espacio de direcciones (usuario).
+    pub fn for_each_leaf<F: FnMut(usize, &Entry, usize)>(&self, mut f: F) {
+        MapTable::walk_entries(&self.entries, 2, 0, &mut f);
+    }
+
+    /// Version recursiva
+    fn walk_entries<F: FnMut(usize, &Entry, usize)>(
+        entries: &[Entry],
+        level: usize,
+        vaddr_base: usize,
+        f: &mut F,
+    ) {
+        for (idx, cur_entry) in entries.iter().enumerate() {
+            if !cur_entry.is_valid() {
+                continue;
+            }
+            let vaddr = vaddr_base | idx << (12 + level * 9);
+            if cur_entry.is_leaf() {
+                f(vaddr, cur_entry, level);
+            } else if level > 0 {
+                let child_entries =
+                    unsafe { from_raw_parts(cur_entry.get_address() as *const Entry, 512) };
+                MapTable::walk_entries(child_entries, level - 1, vaddr, f);
+            }
+        }
+    }
+
     /// Convierte una dirección virtual en una física.
     pub fn virt_to_phys(&self, vaddr: usize) -> Option<usize> {
+        let (entry, level) = self.find_leaf(vaddr)?;
+        // Depende el nivel voy a tener un tamaño de offset
+        // Por ejemplo, el nivel 0 tiene 12 bits de offset relativos
+        // a la página.
+        // En el nivel 1 las páginas tienen 21 bits de offset, etc
+        let offset_mask = (1 << (12 + level * 9)) - 1;
+        let page_offset = vaddr & offset_mask;
+        let phys_addr = ((entry.get_entry() << 2) as usize) & !offset_mask;
+        Some(phys_addr | page_offset)
+    }
+
+    /// Entrada hoja que mapea `vaddr`, junto con su nivel
+    pub fn find_leaf(&self, vaddr: usize) -> Option<(Entry, usize)> {
         // Desarmo la dirección virtual
         let vpn = [
             // VPN[0] = vaddr[20:12]
@@ -179,25 +235,46 @@ impl<'a> MapTable<'a> {
                 break;
             }
             if cur_table.is_leaf() {
-                // Depende el nivel voy a tener un tamaño de offset
-                // Por ejemplo, el nivel 0 tiene 12 bits de offset relativos
-                // a la página.
-                // En el nivel 1 las páginas tienen 21 bits de offset, etc
-                let offset_mask = (1 << (12 + i * 9)) - 1;
-                let page_offset = vaddr & offset_mask;
-                let phys_addr = ((cur_table.get_entry() << 2) as usize) & !offset_mask;
-                return Some(phys_addr | page_offset);
-            } else {
-                // Si no es hoja, ingresamos a la rama como en `map`
-                let entry = ((cur_table.get_entry() & !0x3ff) << 2) as *mut Entry;
-                let entry_child = vpn[i - 1];
-                // cur_table ahora es la tabla en la dirección de memoria de la tabla anterior[vpn[i]]
-                cur_table = unsafe { entry.add(entry_child).as_mut().unwrap() };
+                return Some((*cur_table, i));
             }
+            if i == 0 {
+                // Una rama en el último nivel es una tabla mal armada
+                break;
+            }
+            // Si no es hoja, ingresamos a la rama como en `map`
+            let entry = ((cur_table.get_entry() & !0x3ff) << 2) as *mut Entry;
+            let entry_child = vpn[i - 1];
+            // cur_table ahora es la tabla en la dirección de memoria de la tabla anterior[vpn[i]]
+            cur_table = unsafe { entry.add(entry_child).as_mut().unwrap() };
         }
         None
     }
 
+    /// Copia los mapeos de usuario en `child` sin copiar la memoria: las dos
+    /// tablas apuntan a las mismas páginas físicas, que suman una referencia
+    /// (ver `PageTable::share`). Las páginas con permiso de escritura quedan
+    /// de sólo lectura en ambas tablas, y recién se copian cuando alguna de
+    /// las dos escribe (*copy-on-write*).
+    ///
+    /// Como cambian los permisos de esta tabla, hay que sincronizar su TLB.
+    pub fn clone_cow(&mut self, child: &mut MapTable) {
+        let mut leaves = Vec::new();
+        self.for_each_leaf(|vaddr, entry, level| {
+            if entry.get_entry() & EntryBits::User.val() != 0 {
+                leaves.push((vaddr, entry.get_address(), entry.get_entry(), level));
+            }
+        });
+        for (vaddr, paddr, entry, level) in leaves {
+            let bits = entry & ENTRY_FLAGS_MASK & !EntryBits::Write.val();
+            if entry & EntryBits::Write.val() != 0 {
+                self.map(vaddr, paddr, bits, level);
+            }
+            child.map(vaddr, paddr, bits, level);
+            self.page_table
+                .share(unsafe { NonNull::new_unchecked(paddr as *mut u8) });
+        }
+    }
+
     /// Mapea direcciones virtuales a una física del mismo valor
     /// Utiliza páginas de 4KB
     pub fn range_map(&mut self, start: usize, end: usize, bits: i64) {
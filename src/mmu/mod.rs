@@ -4,8 +4,11 @@
 use crate::{print, println};
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+pub mod alloc_stats;
 pub mod map_table;
 pub mod riscv64;
+pub mod slab;
+pub mod vma;
 
 extern "C" {
     pub(crate) static TEXT_START: usize;
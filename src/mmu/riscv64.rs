@@ -1,6 +1,10 @@
+use crate::mmu::alloc_stats::{AllocStats, AllocTag, Allocation};
+use crate::mmu::slab::SlabCaches;
 use crate::{print, println};
 use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::panic::Location;
 use core::ptr::{null_mut, NonNull};
 use core::slice::from_raw_parts_mut;
 
@@ -15,7 +19,24 @@ pub const PAGE_SIZE: usize = 1 << PAGE_ORDER;
 /// de igual tamaño y luego utilizamos M páginas para guardar información
 /// de las mismas
 /// N = Tam Heap / Tam Página (usamos páginas de 4096 bytes)
-/// M = N / Tam Página
+/// M = (4 * N + listas libres + estadísticas) / Tam Página
+///
+/// Las páginas se reparten con un *buddy system*: los bloques libres tienen
+/// 2^k páginas, están alineados a su tamaño y se guardan en una lista por
+/// orden. Para reservar partimos el bloque libre más chico que alcance, y al
+/// liberar unimos cada bloque con su "buddy" (el bloque vecino del mismo
+/// orden) mientras éste también esté libre. Los pedidos que no son potencia de
+/// 2 devuelven las páginas sobrantes a las listas.
+///
+/// Las páginas de estado tienen, en orden:
+/// * Un byte por página con los `PageBits` de las páginas reservadas
+/// * Un byte por página con el orden + 1 de los bloques libres que empiezan
+///   en ella, o 0 si no empieza ningún bloque libre
+/// * Dos bytes por página con las referencias extra a la reserva que empieza
+///   en ella (ver `share`)
+/// * La primera dirección de cada lista de bloques libres. Cada bloque libre
+///   guarda en sus primeros bytes las direcciones del siguiente y el anterior
+/// * Las estadísticas de la tabla (ver `alloc_stats`)
 #[derive(Clone, Debug)]
 pub struct PageTable {
     heap_start: usize,
@@ -25,7 +46,8 @@ pub struct PageTable {
 
 /// Bits con descripción de las páginas de memoria.
 /// Se utilizan 2 de los 8 bits para marcar si la página está libre o no
-/// y si es la última en la cadena de allocs.
+/// y si es la última en la cadena de allocs. La primera página de cada
+/// reserva guarda en los bits altos su `AllocTag`.
 #[repr(u8)]
 pub enum PageBits {
     Empty = 0,
@@ -43,6 +65,29 @@ struct Page {
     bits: u8,
 }
 
+const PAGE_FLAGS_MASK: u8 = 0x3;
+const PAGE_TAG_SHIFT: u8 = 2;
+
+/// Lugar donde se hizo cada reserva, indexado por su primera página
+type CallSite = Option<&'static Location<'static>>;
+
+/// Estado de la tabla que no depende de la cantidad de páginas
+struct Accounting {
+    stats: AllocStats,
+    /// Arreglo de `CallSite` por página, o null si el modo debug está apagado
+    call_sites: *mut CallSite,
+}
+
+/// Orden máximo de los bloques: 2^20 páginas (4 GiB)
+const MAX_ORDER: usize = 20;
+const FREE_LISTS: usize = MAX_ORDER + 1;
+
+/// Enlaces de la lista guardados al comienzo de cada bloque libre
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
 impl PageTable {
     /// Constructor
     pub const fn new(heap_start: usize, heap_size: usize) -> Self {
@@ -57,60 +102,102 @@ impl PageTable {
     /// Inicializamos la tabla de páginas, calculando cuál es la cantidad de
     /// páginas necesaria para cubrir todo el heap.
     ///
-    /// Usamos 1 bit para indicar si la página está reservada o no, por lo que reservamos una
-    /// página cada PAGE_SIZE páginas
+    /// Usamos 4 bytes por página y las cabezas de las listas libres, y el
+    /// resto del heap se reparte en los bloques libres más grandes posibles
     pub fn init(&mut self) {
         // Cantidad de páginas en la que divido mi heap (incluyendo páginas de estado)
         let num_pages = self.heap_size / PAGE_SIZE;
-        // Cantidad de páginas que necesito para guardar información de las páginas
-        let reserved_pages = num_pages / (PAGE_SIZE + 1) + 1;
-        // Donde comienza la primera página "usable"
-        let start_page = self.heap_start as *mut Page;
         for i in 0..num_pages {
             unsafe {
-                (*start_page.add(i)).clear();
+                (*self.page(i)).clear();
+                *self.free_orders().add(i) = 0;
+                *self.refs().add(i) = 0;
             }
         }
-        self.heap_alloc_start = round_up(self.heap_start + reserved_pages * PAGE_SIZE, PAGE_ORDER);
+        for order in 0..FREE_LISTS {
+            unsafe { *self.free_lists().add(order) = 0 };
+        }
+        // Páginas que necesito para guardar información de las páginas
+        let metadata_end = self.accounting() as usize + size_of::<Accounting>();
+        self.heap_alloc_start = round_up(metadata_end, PAGE_ORDER);
+        let usable_pages = self.usable_pages();
+        let stats = AllocStats {
+            total: usable_pages,
+            ..Default::default()
+        };
+        unsafe {
+            self.accounting().write(Accounting {
+                stats,
+                call_sites: null_mut(),
+            })
+        };
+        let mut page = 0;
+        while page < usable_pages {
+            let order = block_order(page, usable_pages - page);
+            self.push_free(page, order);
+            page += 1 << order;
+        }
     }
 
-    /// Reserva N páginas continuas
+    /// Reserva N páginas continuas. Se cuentan a nombre de `AllocTag::current`
+    #[track_caller]
     pub fn alloc(&self, pages: usize) -> Option<NonNull<u8>> {
+        self.alloc_at(pages, Some(Location::caller()))
+    }
+
+    /// Reserva páginas para el heap del kernel (slabs y `GlobalAlloc`). No
+    /// guarda dónde se hizo la reserva: `#[track_caller]` no atraviesa
+    /// `GlobalAlloc`, así que el lugar sería siempre el allocator y no el
+    /// código que creó el `Box` o el `Vec`
+    pub(crate) fn alloc_for_heap(&self, pages: usize) -> Option<NonNull<u8>> {
+        self.alloc_at(pages, None)
+    }
+
+    fn alloc_at(&self, pages: usize, call_site: CallSite) -> Option<NonNull<u8>> {
         assert!(pages > 0);
-        let num_pages = self.heap_size / PAGE_SIZE;
-        // Comienzo del heap, inclueyendo páginas que describen el estado de los allocs
-        let heap_start_page = self.heap_start as *mut Page;
-        let mut free_pages = 0;
-        for cur_page_idx in 0..num_pages {
-            let cur_page = unsafe { &(*heap_start_page.add(cur_page_idx)) };
-            if !cur_page.is_used() {
-                // Si está libre, lo cuento
-                free_pages += 1;
-            } else {
-                // Si no está libre reseteo la cantidad de páginas encontradas
-                free_pages = 0;
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+        // Bloque libre más chico que alcanza
+        let mut block_order = (order..FREE_LISTS).find(|order| self.free_list_head(*order) != 0)?;
+        let first_page = self.page_index(self.free_list_head(block_order));
+        self.remove_free(first_page, block_order);
+        // Lo parto a la mitad hasta llegar al orden pedido, liberando las mitades superiores
+        while block_order > order {
+            block_order -= 1;
+            self.push_free(first_page + (1 << block_order), block_order);
+        }
+        // Devuelvo las páginas que sobran del bloque
+        let mut excess = first_page + pages;
+        let block_end = first_page + (1 << order);
+        while excess < block_end {
+            let excess_order = block_order_limit(excess, block_end - excess);
+            self.push_free(excess, excess_order);
+            excess += 1 << excess_order;
+        }
+        // Voy reservando memoria
+        unsafe {
+            for offset in first_page..first_page + pages {
+                (*self.page(offset)).set_flag(PageBits::Used);
             }
-            // Encontré N paginas libres consecutivas?
-            if free_pages == pages {
-                let first_page = cur_page_idx + 1 - pages;
-                // Voy reservando memoria
-                unsafe {
-                    for offset in first_page..cur_page_idx {
-                        (*heap_start_page.add(offset)).set_flag(PageBits::Used);
-                    }
-                    (*heap_start_page.add(cur_page_idx)).set_flag(PageBits::Used);
-                    (*heap_start_page.add(cur_page_idx)).set_flag(PageBits::Last);
-                }
-                // Devuelvo la página inicial
-                // HEAP_ALLOC_START es el heap _luego_ de las páginas reservadas
-                // Asumimos que nunca puede ser 0
-                let addr = self.heap_alloc_start + PAGE_SIZE * first_page;
-                return unsafe { Some(NonNull::new_unchecked(addr as *mut u8)) };
+            (*self.page(first_page + pages - 1)).set_flag(PageBits::Last);
+            let tag = AllocTag::current();
+            (*self.page(first_page)).set_tag(tag);
+            let accounting = &mut *self.accounting();
+            accounting.stats.record_alloc(pages, tag);
+            if !accounting.call_sites.is_null() {
+                *accounting.call_sites.add(first_page) = call_site;
             }
         }
-        None
+        // Devuelvo la página inicial
+        // HEAP_ALLOC_START es el heap _luego_ de las páginas reservadas
+        // Asumimos que nunca puede ser 0
+        let addr = self.page_address(first_page);
+        unsafe { Some(NonNull::new_unchecked(addr as *mut u8)) }
     }
 
+    #[track_caller]
     pub fn zalloc(&self, pages: usize) -> Option<NonNull<u8>> {
         let allocated = self.alloc(pages);
         if let Some(data) = allocated {
@@ -126,14 +213,21 @@ impl PageTable {
 
     /// Libera páginas reservadas
     pub fn dealloc(&self, ptr: NonNull<u8>) {
-        let bits_id = (ptr.as_ptr() as usize - self.heap_alloc_start) / PAGE_SIZE;
-        assert!(bits_id < self.heap_size);
-        let bits_address = self.heap_start + bits_id;
+        let first_page = self.page_index(ptr.as_ptr() as usize);
+        assert!(first_page < self.usable_pages());
+        let mut last_page = first_page;
+        let tag = unsafe { (*self.page(first_page)).tag() };
+        assert_eq!(
+            unsafe { *self.refs().add(first_page) },
+            0,
+            "Freeing a shared allocation, use release"
+        );
         unsafe {
-            let mut cur_page = bits_address as *mut Page;
+            let mut cur_page = self.page(first_page);
             while (*cur_page).is_used() && !(*cur_page).is_last() {
                 (*cur_page).clear();
                 cur_page = cur_page.add(1);
+                last_page += 1;
             }
             // Verificación mínima de double free
             assert!(
@@ -143,7 +237,222 @@ impl PageTable {
             // If we get here, we've taken care of all previous pages and
             // we are on the last page.
             (*cur_page).clear();
+            let accounting = &mut *self.accounting();
+            accounting
+                .stats
+                .record_dealloc(last_page + 1 - first_page, tag);
+            if !accounting.call_sites.is_null() {
+                *accounting.call_sites.add(first_page) = None;
+            }
+        }
+        // Devuelvo el rango en bloques alineados, que se unen con sus buddies
+        let mut page = first_page;
+        while page <= last_page {
+            let order = block_order_limit(page, last_page + 1 - page);
+            self.free_block(page, order);
+            page += 1 << order;
+        }
+    }
+
+    /// Agrega una referencia a la reserva que empieza en `ptr`, que se libera
+    /// recién cuando se sueltan todas con `release`. Así varios procesos
+    /// pueden compartir una misma página física
+    pub fn share(&self, ptr: NonNull<u8>) {
+        let page = self.page_index(ptr.as_ptr() as usize);
+        assert!(unsafe { (*self.page(page)).is_used() });
+        unsafe {
+            let refs = self.refs().add(page);
+            *refs = (*refs)
+                .checked_add(1)
+                .expect("Too many references to a page");
+        }
+    }
+
+    /// Suelta una referencia a la reserva que empieza en `ptr`, liberándola
+    /// si era la última. Devuelve `true` si se liberó
+    pub fn release(&self, ptr: NonNull<u8>) -> bool {
+        let refs = unsafe { self.refs().add(self.page_index(ptr.as_ptr() as usize)) };
+        unsafe {
+            if *refs > 0 {
+                *refs -= 1;
+                return false;
+            }
         }
+        self.dealloc(ptr);
+        true
+    }
+
+    /// Cantidad de referencias a la reserva que empieza en `ptr`
+    pub fn ref_count(&self, ptr: NonNull<u8>) -> usize {
+        let page = self.page_index(ptr.as_ptr() as usize);
+        if unsafe { !(*self.page(page)).is_used() } {
+            return 0;
+        }
+        unsafe { *self.refs().add(page) as usize + 1 }
+    }
+
+    /// Agrega el bloque a su lista, uniéndolo antes con su buddy mientras
+    /// éste esté libre
+    fn free_block(&self, mut page: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = page ^ (1 << order);
+            if buddy + (1 << order) > self.usable_pages() || self.free_order(buddy) != Some(order) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            page = page.min(buddy);
+            order += 1;
+        }
+        self.push_free(page, order);
+    }
+
+    fn push_free(&self, page: usize, order: usize) {
+        let address = self.page_address(page);
+        let head = self.free_list_head(order);
+        unsafe {
+            (address as *mut FreeBlock).write(FreeBlock {
+                next: head,
+                prev: 0,
+            });
+            if head != 0 {
+                (*(head as *mut FreeBlock)).prev = address;
+            }
+            *self.free_lists().add(order) = address;
+            *self.free_orders().add(page) = order as u8 + 1;
+        }
+    }
+
+    fn remove_free(&self, page: usize, order: usize) {
+        unsafe {
+            let block = &*(self.page_address(page) as *const FreeBlock);
+            if block.prev != 0 {
+                (*(block.prev as *mut FreeBlock)).next = block.next;
+            } else {
+                *self.free_lists().add(order) = block.next;
+            }
+            if block.next != 0 {
+                (*(block.next as *mut FreeBlock)).prev = block.prev;
+            }
+            *self.free_orders().add(page) = 0;
+        }
+    }
+
+    /// Orden del bloque libre que empieza en `page`, si hay uno
+    fn free_order(&self, page: usize) -> Option<usize> {
+        match unsafe { *self.free_orders().add(page) } {
+            0 => None,
+            order => Some(order as usize - 1),
+        }
+    }
+
+    fn free_list_head(&self, order: usize) -> usize {
+        unsafe { *self.free_lists().add(order) }
+    }
+
+    /// `PageBits` de la página que contiene `address`
+    pub fn page_bits(&self, address: usize) -> u8 {
+        unsafe { (*self.page(self.page_index(address))).bits & PAGE_FLAGS_MASK }
+    }
+
+    /// Contadores de páginas, que sirven de snapshot para buscar pérdidas
+    pub fn stats(&self) -> AllocStats {
+        unsafe { (*self.accounting()).stats }
+    }
+
+    /// Activa el modo debug: desde ahora cada reserva guarda dónde se hizo.
+    /// El arreglo con los lugares se reserva de la misma tabla. Devuelve
+    /// falso si no hay memoria para el arreglo
+    #[track_caller]
+    pub fn track_call_sites(&self) -> bool {
+        let accounting = self.accounting();
+        if unsafe { !(*accounting).call_sites.is_null() } {
+            return true;
+        }
+        let size = self.usable_pages() * size_of::<CallSite>();
+        let Some(call_sites) = self.zalloc(size.div_ceil(PAGE_SIZE)) else {
+            return false;
+        };
+        let first_page = self.page_index(call_sites.as_ptr() as usize);
+        unsafe {
+            (*accounting).call_sites = call_sites.as_ptr() as *mut CallSite;
+            *(*accounting).call_sites.add(first_page) = Some(Location::caller());
+        }
+        true
+    }
+
+    /// Recorre las reservas vivas, en orden de dirección
+    pub fn for_each_allocation<F: FnMut(Allocation)>(&self, mut f: F) {
+        let call_sites = unsafe { (*self.accounting()).call_sites };
+        let mut first_page = 0;
+        for i in 0..self.usable_pages() {
+            let page = unsafe { &*self.page(i) };
+            if !page.is_used() {
+                first_page = i + 1;
+                continue;
+            }
+            if page.is_last() {
+                let call_site = if call_sites.is_null() {
+                    None
+                } else {
+                    unsafe { *call_sites.add(first_page) }
+                };
+                f(Allocation {
+                    address: self.page_address(first_page),
+                    pages: i + 1 - first_page,
+                    tag: unsafe { (*self.page(first_page)).tag() },
+                    call_site,
+                });
+                first_page = i + 1;
+            }
+        }
+    }
+
+    /// Cantidad de bloques libres de cada orden
+    pub fn free_blocks(&self) -> [usize; FREE_LISTS] {
+        let mut blocks = [0; FREE_LISTS];
+        for (order, count) in blocks.iter_mut().enumerate() {
+            let mut block = self.free_list_head(order);
+            while block != 0 {
+                *count += 1;
+                block = unsafe { (*(block as *const FreeBlock)).next };
+            }
+        }
+        blocks
+    }
+
+    fn page(&self, page: usize) -> *mut Page {
+        (self.heap_start + page) as *mut Page
+    }
+
+    fn free_orders(&self) -> *mut u8 {
+        (self.heap_start + self.heap_size / PAGE_SIZE) as *mut u8
+    }
+
+    fn refs(&self) -> *mut u16 {
+        round_up(self.free_orders() as usize + self.heap_size / PAGE_SIZE, 1) as *mut u16
+    }
+
+    fn free_lists(&self) -> *mut usize {
+        let refs_end = self.refs() as usize + self.heap_size / PAGE_SIZE * size_of::<u16>();
+        round_up(refs_end, 3) as *mut usize
+    }
+
+    fn accounting(&self) -> *mut Accounting {
+        let free_lists_end = self.free_lists() as usize + FREE_LISTS * size_of::<usize>();
+        round_up(free_lists_end, 3) as *mut Accounting
+    }
+
+    fn page_address(&self, page: usize) -> usize {
+        self.heap_alloc_start + page * PAGE_SIZE
+    }
+
+    fn page_index(&self, address: usize) -> usize {
+        (address - self.heap_alloc_start) / PAGE_SIZE
+    }
+
+    /// Páginas que se pueden reservar, sin contar las de estado
+    fn usable_pages(&self) -> usize {
+        (self.heap_start + self.heap_size).saturating_sub(self.heap_alloc_start) / PAGE_SIZE
     }
 
     /// Devuelve la cantidad de páginas necesarias para cubrir ese rango de memoria
@@ -156,58 +465,55 @@ impl PageTable {
         self.heap_size / PAGE_SIZE
     }
 
+    /// Imprime las reservas vivas con su subsistema (y el lugar donde se
+    /// hicieron en modo debug), y los contadores de la tabla
     pub fn print_allocations(&self) {
-        unsafe {
-            let num_pages = self.heap_size / (PAGE_SIZE + 1);
-            let heap_table_beg = self.heap_start as *const Page;
-            let heap_table_end = heap_table_beg.add(num_pages);
-            let alloc_beg = self.heap_alloc_start;
-            let alloc_end = self.heap_alloc_start + num_pages * PAGE_SIZE;
-            println!("\x1b[1m[Page Allocation Table]\x1b[0m");
-            println!(
-                "\x1b[1m\x1b[30mHEAP\x1b[0m: {:p} -> {:p}",
-                heap_table_beg, heap_table_end
+        let stats = self.stats();
+        let heap_table_beg = self.heap_start as *const Page;
+        let alloc_beg = self.heap_alloc_start;
+        let alloc_end = self.heap_alloc_start + stats.total * PAGE_SIZE;
+        println!("\x1b[1m[Page Allocation Table]\x1b[0m");
+        println!(
+            "\x1b[1m\x1b[30mHEAP\x1b[0m: {:p} -> {:#x}",
+            heap_table_beg, self.heap_alloc_start
+        );
+        println!(
+            "\x1b[1m\x1b[30mPHYS\x1b[0m: {:#x} -> {:#x}",
+            alloc_beg, alloc_end
+        );
+        println!("-----------------------");
+        self.for_each_allocation(|allocation| {
+            let end_addr = allocation.address + allocation.pages * PAGE_SIZE - 1;
+            print!(
+                "Alloc: {:#x} -> {:#x}: {:>3} pages [{}]",
+                allocation.address,
+                end_addr,
+                allocation.pages,
+                allocation.tag.name()
             );
-            println!(
-                "\x1b[1m\x1b[30mPHYS\x1b[0m: {:#x} -> {:#x}",
-                alloc_beg, alloc_end
-            );
-            println!("-----------------------");
-            let mut total_taken = 0;
-            let mut cur_taken = 0;
-            let mut cur_start_page = self.heap_alloc_start;
-            let mut is_begin = true;
-            for i in 0..num_pages {
-                if (*heap_table_beg.add(i)).is_used() {
-                    if is_begin {
-                        cur_start_page = self.heap_alloc_start + PAGE_SIZE * i;
-                        is_begin = false;
-                    }
-                    cur_taken += 1;
-                    total_taken += 1;
-                    if (*heap_table_beg.add(i)).is_last() {
-                        let end_addr = self.heap_alloc_start + PAGE_SIZE * (i + 1) - 1;
-                        println!(
-                            "Alloc: {:#x} -> {:#x}: {:>3} pages",
-                            cur_start_page, end_addr, cur_taken
-                        );
-                        cur_taken = 0;
-                        is_begin = true;
-                    }
-                }
+            match allocation.call_site {
+                Some(location) => println!(" at {}", location),
+                None => println!(),
             }
-            println!("-----------------------");
-            println!(
-                "\x1b[1m\x1b[30mAllocated\x1b[0m: {:>6} pages ({:>10} bytes)",
-                total_taken,
-                total_taken * PAGE_SIZE
-            );
-            let total_free = num_pages - total_taken;
-            println!(
-                "\x1b[1m\x1b[30mFree     \x1b[0m: {:>6} pages ({:>10} bytes)",
-                total_free,
-                total_free * PAGE_SIZE
-            );
+        });
+        println!("-----------------------");
+        println!(
+            "\x1b[1m\x1b[30mAllocated\x1b[0m: {:>6} pages ({:>10} bytes)",
+            stats.allocated,
+            stats.allocated * PAGE_SIZE
+        );
+        println!(
+            "\x1b[1m\x1b[30mFree     \x1b[0m: {:>6} pages ({:>10} bytes)",
+            stats.free(),
+            stats.free() * PAGE_SIZE
+        );
+        println!(
+            "\x1b[1m\x1b[30mPeak     \x1b[0m: {:>6} pages ({:>10} bytes)",
+            stats.peak,
+            stats.peak * PAGE_SIZE
+        );
+        for tag in AllocTag::ALL {
+            println!("  {:<9}: {:>6} pages", tag.name(), stats.tag_pages(tag));
         }
     }
 }
@@ -229,6 +535,14 @@ impl Page {
     pub fn clear(&mut self) {
         self.bits = PageBits::Empty.val();
     }
+
+    pub fn tag(&self) -> AllocTag {
+        AllocTag::from_bits(self.bits >> PAGE_TAG_SHIFT)
+    }
+
+    pub fn set_tag(&mut self, tag: AllocTag) {
+        self.bits = (self.bits & PAGE_FLAGS_MASK) | ((tag as u8) << PAGE_TAG_SHIFT);
+    }
 }
 
 fn round_down(val: usize, order: usize) -> usize {
@@ -241,14 +555,45 @@ fn round_up(val: usize, order: usize) -> usize {
     (val + mask) & !mask
 }
 
+/// Orden del bloque más grande que empieza en `page`, alineado a su tamaño y
+/// de a lo sumo `pages` páginas
+fn block_order_limit(page: usize, pages: usize) -> usize {
+    let alignment = if page == 0 {
+        usize::BITS as usize
+    } else {
+        page.trailing_zeros() as usize
+    };
+    alignment.min(pages.ilog2() as usize)
+}
+
+/// Como `block_order_limit`, pero sin pasar el orden máximo
+fn block_order(page: usize, pages: usize) -> usize {
+    block_order_limit(page, pages).min(MAX_ORDER)
+}
+
+/// Reserva páginas enteras. Es el allocator de los pedidos grandes, y se puede
+/// usar directamente con la api de *allocators*
 #[derive(Copy, Clone)]
 pub struct PageAllocator;
 
+impl PageAllocator {
+    /// Páginas necesarias para `layout`. Las páginas sólo están alineadas a
+    /// `PAGE_SIZE`, así que no podemos cumplir alineaciones mayores
+    fn pages_needed(layout: Layout) -> Option<usize> {
+        if layout.align() > PAGE_SIZE {
+            return None;
+        }
+        Some(layout.size().div_ceil(PAGE_SIZE).max(1))
+    }
+}
+
 unsafe impl GlobalAlloc for PageAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let page_table = GLOBAL_PAGE_TABLE.get_root();
-        let pages_needed = (layout.size() >> PAGE_ORDER) + 1;
-        if let Some(address) = page_table.alloc(pages_needed) {
+        let Some(pages_needed) = PageAllocator::pages_needed(layout) else {
+            return null_mut();
+        };
+        if let Some(address) = page_table.alloc_for_heap(pages_needed) {
             return address.as_ptr();
         }
         null_mut()
@@ -265,8 +610,8 @@ unsafe impl GlobalAlloc for PageAllocator {
 unsafe impl Allocator for PageAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let page_table = GLOBAL_PAGE_TABLE.get_root();
-        let pages_needed = (layout.size() >> PAGE_ORDER) + 1;
-        if let Some(ptr) = page_table.alloc(pages_needed) {
+        let pages_needed = PageAllocator::pages_needed(layout).ok_or(AllocError)?;
+        if let Some(ptr) = page_table.alloc_for_heap(pages_needed) {
             let fat_ptr;
             unsafe {
                 let array = from_raw_parts_mut(ptr.as_ptr(), layout.size());
@@ -284,6 +629,47 @@ unsafe impl Allocator for PageAllocator {
     }
 }
 
+/// Allocator global: los objetos chicos van a los slabs y el resto a páginas
+pub struct KernelAllocator {
+    slabs: UnsafeCell<SlabCaches>,
+}
+
+impl KernelAllocator {
+    const fn new() -> Self {
+        let slabs = UnsafeCell::new(SlabCaches::new());
+        Self { slabs }
+    }
+
+    /// Los slabs sólo se modifican con las interrupciones deshabilitadas,
+    /// igual que la tabla de páginas
+    #[allow(clippy::mut_from_ref)]
+    fn slabs(&self) -> &mut SlabCaches {
+        unsafe { &mut *self.slabs.get() }
+    }
+}
+
+unsafe impl Sync for KernelAllocator {}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if SlabCaches::handles(layout) {
+            let page_table = GLOBAL_PAGE_TABLE.get_root();
+            self.slabs().alloc(page_table, layout)
+        } else {
+            PAGE_ALLOCATOR.alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if SlabCaches::handles(layout) {
+            let page_table = GLOBAL_PAGE_TABLE.get_root();
+            self.slabs().dealloc(page_table, ptr, layout);
+        } else {
+            PAGE_ALLOCATOR.dealloc(ptr, layout);
+        }
+    }
+}
+
 pub struct GlobalPageTable {
     root: UnsafeCell<PageTable>,
 }
@@ -306,5 +692,6 @@ impl GlobalPageTable {
 unsafe impl Sync for GlobalPageTable {}
 
 #[global_allocator]
+pub static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator::new();
 pub static PAGE_ALLOCATOR: PageAllocator = PageAllocator {};
 pub static GLOBAL_PAGE_TABLE: GlobalPageTable = GlobalPageTable::empty();
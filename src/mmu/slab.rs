@@ -0,0 +1,211 @@
+//! # Slab allocator
+//! Los objetos chicos (hasta `MAX_SLAB_OBJECT` bytes) se reservan en *slabs*:
+//! páginas divididas en objetos de un mismo tamaño, que es una potencia de 2.
+//! Cada tamaño tiene una lista de slabs con objetos libres, y cada slab guarda
+//! al comienzo de la página su header y una lista de sus objetos libres. Como
+//! la página está alineada y los objetos tienen tamaño potencia de 2, cada
+//! objeto queda alineado a su tamaño.
+//!
+//! Cada subsistema (`AllocTag`) tiene sus propios slabs, así las páginas de
+//! los objetos chicos se cuentan a nombre del subsistema que los reservó.
+use crate::mmu::alloc_stats::{AllocTag, ALLOC_TAGS};
+use crate::mmu::riscv64::{PageTable, PAGE_SIZE};
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{null_mut, NonNull};
+
+/// Tamaño del objeto más chico
+const MIN_SLAB_OBJECT_ORDER: usize = 4;
+/// Tamaño del objeto más grande. Los pedidos más grandes van directo a páginas
+const MAX_SLAB_OBJECT_ORDER: usize = 10;
+pub const MAX_SLAB_OBJECT: usize = 1 << MAX_SLAB_OBJECT_ORDER;
+const SIZE_CLASSES: usize = MAX_SLAB_OBJECT_ORDER - MIN_SLAB_OBJECT_ORDER + 1;
+
+/// Header de un slab, al comienzo de su página
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    /// Primer objeto libre
+    free: *mut FreeObject,
+    /// Objetos reservados
+    used: usize,
+    /// Subsistema dueño del slab, para devolver los objetos a su caché
+    tag: AllocTag,
+}
+
+/// Un objeto libre guarda la dirección del siguiente
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Slabs de un tamaño de objeto
+struct SlabCache {
+    object_size: usize,
+    /// Slabs con al menos un objeto libre. Los slabs llenos no están en
+    /// ninguna lista hasta que se libere alguno de sus objetos
+    partial: *mut Slab,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            partial: null_mut(),
+        }
+    }
+
+    fn alloc(&mut self, page_table: &PageTable) -> *mut u8 {
+        if self.partial.is_null() {
+            let Some(page) = page_table.alloc_for_heap(1) else {
+                return null_mut();
+            };
+            let slab = self.init_slab(page);
+            self.push_partial(slab);
+        }
+        unsafe {
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).used += 1;
+            if (*slab).free.is_null() {
+                self.remove_partial(slab);
+            }
+            object as *mut u8
+        }
+    }
+
+    /// Libera el objeto. Si el slab queda vacío, devuelve la página
+    fn dealloc(&mut self, page_table: &PageTable, ptr: *mut u8) {
+        let slab = (ptr as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+        unsafe {
+            let was_full = (*slab).free.is_null();
+            let object = ptr as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).used -= 1;
+            if (*slab).used == 0 {
+                if !was_full {
+                    self.remove_partial(slab);
+                }
+                page_table.dealloc(NonNull::new_unchecked(slab as *mut u8));
+            } else if was_full {
+                self.push_partial(slab);
+            }
+        }
+    }
+
+    /// Arma el header del slab y encadena todos sus objetos como libres
+    fn init_slab(&self, page: NonNull<u8>) -> *mut Slab {
+        let slab = page.as_ptr() as *mut Slab;
+        let first_object = size_of::<Slab>().next_multiple_of(self.object_size);
+        let mut free = null_mut();
+        for offset in (first_object..=PAGE_SIZE - self.object_size)
+            .rev()
+            .step_by(self.object_size)
+        {
+            let object = unsafe { page.as_ptr().add(offset) } as *mut FreeObject;
+            unsafe { (*object).next = free };
+            free = object;
+        }
+        unsafe {
+            slab.write(Slab {
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                used: 0,
+                tag: AllocTag::current(),
+            })
+        };
+        slab
+    }
+
+    fn push_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    fn remove_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            if (*slab).prev.is_null() {
+                self.partial = (*slab).next;
+            } else {
+                (*(*slab).prev).next = (*slab).next;
+            }
+            if !(*slab).next.is_null() {
+                (*(*slab).next).prev = (*slab).prev;
+            }
+        }
+    }
+}
+
+/// Un `SlabCache` por subsistema y tamaño de objeto, de
+/// `2^MIN_SLAB_OBJECT_ORDER` a `MAX_SLAB_OBJECT` bytes
+pub struct SlabCaches {
+    caches: [[SlabCache; SIZE_CLASSES]; ALLOC_TAGS],
+}
+
+impl SlabCaches {
+    pub const fn new() -> Self {
+        let mut caches = [const { [const { SlabCache::new(0) }; SIZE_CLASSES] }; ALLOC_TAGS];
+        let mut tag = 0;
+        while tag < ALLOC_TAGS {
+            let mut i = 0;
+            while i < SIZE_CLASSES {
+                caches[tag][i].object_size = 1 << (MIN_SLAB_OBJECT_ORDER + i);
+                i += 1;
+            }
+            tag += 1;
+        }
+        Self { caches }
+    }
+
+    /// Tamaño de objeto que corresponde al pedido, si entra en un slab
+    fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(1 << MIN_SLAB_OBJECT_ORDER)
+            .next_power_of_two();
+        if size > MAX_SLAB_OBJECT {
+            return None;
+        }
+        Some(size.trailing_zeros() as usize - MIN_SLAB_OBJECT_ORDER)
+    }
+
+    /// El pedido se atiende con slabs y no con páginas
+    pub fn handles(layout: Layout) -> bool {
+        SlabCaches::size_class(layout).is_some()
+    }
+
+    /// Reserva un objeto para `layout`, que tiene que cumplir `handles`, en
+    /// los slabs de `AllocTag::current`
+    pub fn alloc(&mut self, page_table: &PageTable, layout: Layout) -> *mut u8 {
+        match SlabCaches::size_class(layout) {
+            Some(class) => self.caches[AllocTag::current() as usize][class].alloc(page_table),
+            None => null_mut(),
+        }
+    }
+
+    /// Libera un objeto reservado con `alloc` y el mismo `layout`. Vuelve a
+    /// los slabs en que se reservó, sin importar la etiqueta actual
+    pub fn dealloc(&mut self, page_table: &PageTable, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = SlabCaches::size_class(layout) {
+            let slab = (ptr as usize & !(PAGE_SIZE - 1)) as *const Slab;
+            let tag = unsafe { (*slab).tag };
+            self.caches[tag as usize][class].dealloc(page_table, ptr);
+        }
+    }
+}
+
+impl Default for SlabCaches {
+    fn default() -> Self {
+        Self::new()
+    }
+}
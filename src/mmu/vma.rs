@@ -0,0 +1,111 @@
+//! # Áreas de memoria virtual
+//! Cada proceso describe su espacio de direcciones con una lista de áreas
+//! (VMAs): rangos de páginas virtuales con sus permisos, que pueden estar
+//! mapeadas o no. Las páginas de un área válida que todavía no están
+//! mapeadas se reservan cuando el proceso las toca por primera vez (ver
+//! `Process::handle_page_fault`).
+use crate::mmu::map_table::EntryBits;
+use crate::mmu::riscv64::PAGE_SIZE;
+use alloc::vec::Vec;
+
+/// Para qué usa el proceso el área
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Segmentos del ejecutable
+    Elf,
+    /// Memoria que reserva el proceso con `brk`, a continuación del ejecutable
+    Heap,
+    /// Stack de usuario, que crece hacia abajo
+    Stack,
+}
+
+/// Tipo de acceso que produjo un page fault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Load,
+    Store,
+    Execute,
+}
+
+/// Área de memoria virtual: páginas `start..end` con los permisos `bits`
+/// (`EntryBits`)
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub bits: i64,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn new(start: usize, end: usize, bits: i64, kind: VmaKind) -> Self {
+        assert!(start.is_multiple_of(PAGE_SIZE) && end.is_multiple_of(PAGE_SIZE) && start < end);
+        Self {
+            start,
+            end,
+            bits,
+            kind,
+        }
+    }
+
+    pub fn contains(&self, vaddr: usize) -> bool {
+        (self.start..self.end).contains(&vaddr)
+    }
+
+    /// Indica si los permisos del área admiten el acceso
+    pub fn allows(&self, access: Access) -> bool {
+        let bit = match access {
+            Access::Load => EntryBits::Read,
+            Access::Store => EntryBits::Write,
+            Access::Execute => EntryBits::Execute,
+        };
+        self.bits & bit.val() != 0
+    }
+
+    fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// Áreas de un proceso, ordenadas por dirección y sin superponerse
+#[derive(Clone, Debug, Default)]
+pub struct VmaList {
+    areas: Vec<Vma>,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self { areas: Vec::new() }
+    }
+
+    /// Agrega un área. Si es contigua a otra del mismo tipo y permisos se
+    /// unen. Devuelve `false` si se superpone con un área existente
+    pub fn insert(&mut self, vma: Vma) -> bool {
+        if self.areas.iter().any(|area| area.overlaps(&vma)) {
+            return false;
+        }
+        let index = self.areas.partition_point(|area| area.start < vma.start);
+        if let Some(previous) = index.checked_sub(1).map(|i| &mut self.areas[i]) {
+            if previous.end == vma.start && previous.bits == vma.bits && previous.kind == vma.kind {
+                previous.end = vma.end;
+                return true;
+            }
+        }
+        self.areas.insert(index, vma);
+        true
+    }
+
+    /// Área que contiene a `vaddr`
+    pub fn find(&self, vaddr: usize) -> Option<&Vma> {
+        self.areas.iter().find(|area| area.contains(vaddr))
+    }
+
+    /// Primera área del tipo `kind`
+    pub fn find_kind(&self, kind: VmaKind) -> Option<&Vma> {
+        self.areas.iter().find(|area| area.kind == kind)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter()
+    }
+}
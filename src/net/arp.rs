@@ -0,0 +1,57 @@
+//! # ARP
+//! Traduce direcciones IPv4 de la subred a direcciones MAC. Respondemos los
+//! pedidos por nuestra dirección y aprendemos la de quienes nos hablan.
+use crate::devices::virtio::net_device::MacAddress;
+use crate::net::ethernet::{BROADCAST_MAC, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use crate::net::{read_u16, Ipv4Addr, NetworkStack, SendError, LOCAL_ADDRESS};
+use alloc::vec::Vec;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+pub fn handle(packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || read_u16(packet, 0) != HARDWARE_ETHERNET
+        || read_u16(packet, 2) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let operation = read_u16(packet, 6);
+    let mut sender_mac = [0; 6];
+    let mut sender_address = [0; 4];
+    let mut target_address = [0; 4];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    sender_address.copy_from_slice(&packet[14..18]);
+    target_address.copy_from_slice(&packet[24..28]);
+    if target_address != LOCAL_ADDRESS {
+        return;
+    }
+    NetworkStack::learn(sender_address, sender_mac);
+    if operation == OPERATION_REQUEST {
+        let reply = build(OPERATION_REPLY, sender_mac, sender_address);
+        let _ = NetworkStack::send_frame(sender_mac, ETHERTYPE_ARP, &reply);
+    }
+}
+
+/// Pregunta por la dirección MAC de `address` a toda la subred
+pub fn request(address: Ipv4Addr) -> Result<(), SendError> {
+    let packet = build(OPERATION_REQUEST, [0; 6], address);
+    NetworkStack::send_frame(BROADCAST_MAC, ETHERTYPE_ARP, &packet)
+}
+
+fn build(operation: u16, target_mac: MacAddress, target_address: Ipv4Addr) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PACKET_SIZE);
+    packet.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&operation.to_be_bytes());
+    packet.extend_from_slice(&NetworkStack::mac_address());
+    packet.extend_from_slice(&LOCAL_ADDRESS);
+    packet.extend_from_slice(&target_mac);
+    packet.extend_from_slice(&target_address);
+    packet
+}
@@ -0,0 +1,64 @@
+//! # Ethernet
+//! Tramas Ethernet II: direcciones de destino y origen, y el tipo del
+//! protocolo que viaja en la trama.
+use crate::devices::virtio::net_device::MacAddress;
+use crate::net::{arp, ipv4, read_u16};
+use alloc::vec::Vec;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const BROADCAST_MAC: MacAddress = [0xff; 6];
+const HEADER_SIZE: usize = 14;
+/// Las tramas más cortas se completan con ceros
+const MIN_FRAME_SIZE: usize = 60;
+
+pub struct EthernetHeader {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+pub fn parse(frame: &[u8]) -> Option<(EthernetHeader, &[u8])> {
+    if frame.len() < HEADER_SIZE {
+        return None;
+    }
+    let mut destination = [0; 6];
+    let mut source = [0; 6];
+    destination.copy_from_slice(&frame[0..6]);
+    source.copy_from_slice(&frame[6..12]);
+    let header = EthernetHeader {
+        destination,
+        source,
+        ethertype: read_u16(frame, 12),
+    };
+    Some((header, &frame[HEADER_SIZE..]))
+}
+
+pub fn build(
+    destination: MacAddress,
+    source: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&source);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_FRAME_SIZE {
+        frame.resize(MIN_FRAME_SIZE, 0);
+    }
+    frame
+}
+
+/// Entrega el contenido de la trama al protocolo que corresponda
+pub fn handle(frame: &[u8]) {
+    let Some((header, payload)) = parse(frame) else {
+        return;
+    };
+    match header.ethertype {
+        ETHERTYPE_ARP => arp::handle(payload),
+        ETHERTYPE_IPV4 => ipv4::handle(payload),
+        _ => {}
+    }
+}
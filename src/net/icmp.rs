@@ -0,0 +1,25 @@
+//! # ICMP
+//! Sólo respondemos los *echo request*, para que la VM conteste `ping`.
+use crate::net::ipv4::{Ipv4Header, PROTOCOL_ICMP};
+use crate::net::{checksum, NetworkStack, LOCAL_ADDRESS};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_SIZE: usize = 8;
+
+pub fn handle(header: &Ipv4Header, message: &[u8]) {
+    if message.len() < HEADER_SIZE || checksum(message) != 0 {
+        return;
+    }
+    // No respondemos pings a direcciones de broadcast
+    if message[0] != TYPE_ECHO_REQUEST || header.destination != LOCAL_ADDRESS {
+        return;
+    }
+    // La respuesta lleva el mismo identificador, número de secuencia y datos
+    let mut reply = message.to_vec();
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let reply_checksum = checksum(&reply);
+    reply[2..4].copy_from_slice(&reply_checksum.to_be_bytes());
+    let _ = NetworkStack::send_ipv4(header.source, PROTOCOL_ICMP, &reply);
+}
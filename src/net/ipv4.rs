@@ -0,0 +1,81 @@
+//! # IPv4
+//! No soportamos opciones ni fragmentación: descartamos los fragmentos y
+//! enviamos todos los paquetes con *Don't Fragment*.
+use crate::net::{checksum, icmp, read_u16, tcp, udp, Ipv4Addr, LOCAL_ADDRESS, NETMASK};
+use alloc::vec::Vec;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+const HEADER_SIZE: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+/// Flag *More Fragments* y offset del fragmento
+const FRAGMENT_MASK: u16 = 0x3fff;
+
+pub struct Ipv4Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+}
+
+pub fn parse(packet: &[u8]) -> Option<(Ipv4Header, &[u8])> {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_size = (packet[0] & 0xf) as usize * 4;
+    let total_size = read_u16(packet, 2) as usize;
+    if header_size < HEADER_SIZE || total_size < header_size || total_size > packet.len() {
+        return None;
+    }
+    if checksum(&packet[..header_size]) != 0 || read_u16(packet, 6) & FRAGMENT_MASK != 0 {
+        return None;
+    }
+    let mut source = [0; 4];
+    let mut destination = [0; 4];
+    source.copy_from_slice(&packet[12..16]);
+    destination.copy_from_slice(&packet[16..20]);
+    let header = Ipv4Header {
+        source,
+        destination,
+        protocol: packet[9],
+    };
+    Some((header, &packet[header_size..total_size]))
+}
+
+pub fn build(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_size = (HEADER_SIZE + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total_size as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_size.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&source);
+    packet.extend_from_slice(&destination);
+    let header_checksum = checksum(&packet);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// `true` si el paquete es para nosotros, directamente o por broadcast
+fn is_for_us(destination: Ipv4Addr) -> bool {
+    let subnet_broadcast = (0..4).all(|i| destination[i] | NETMASK[i] == 0xff);
+    destination == LOCAL_ADDRESS || destination == [0xff; 4] || subnet_broadcast
+}
+
+pub fn handle(packet: &[u8]) {
+    let Some((header, payload)) = parse(packet) else {
+        return;
+    };
+    if !is_for_us(header.destination) {
+        return;
+    }
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(&header, payload),
+        PROTOCOL_UDP => udp::handle(&header, payload),
+        PROTOCOL_TCP => tcp::handle(&header, payload),
+        _ => {}
+    }
+}
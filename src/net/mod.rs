@@ -0,0 +1,252 @@
+//! # Red
+//! Pila TCP/IP mínima sobre el driver virtio-net: ARP, IPv4, ICMP (respuestas
+//! a `ping`), UDP y TCP. La configuración es fija y coincide con la red que
+//! arma el backend `user` de QEMU. Las tramas recibidas se procesan cuando
+//! interrumpe el dispositivo, y las syscalls de sockets se reintentan igual
+//! que las de la consola: si no hay datos el proceso queda esperando.
+use crate::devices::virtio::common::{DeviceManager, DeviceType};
+use crate::devices::virtio::net_device::{MacAddress, NetDevice, MAX_FRAME_SIZE};
+use crate::devices::DeviceId;
+use crate::mmu::alloc_stats::AllocTag;
+use crate::net::ethernet::ETHERTYPE_IPV4;
+use crate::{print, println};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::{RefCell, UnsafeCell};
+
+pub mod arp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+
+pub type Ipv4Addr = [u8; 4];
+
+/// Dirección que le asigna a la VM el backend `user` de QEMU
+pub const LOCAL_ADDRESS: Ipv4Addr = [10, 0, 2, 15];
+pub const NETMASK: Ipv4Addr = [255, 255, 255, 0];
+pub const GATEWAY: Ipv4Addr = [10, 0, 2, 2];
+/// Paquetes que pueden esperar la resolución ARP de su destino
+const MAX_PENDING_PACKETS: usize = 16;
+
+/// Dirección y puerto de un extremo de una conexión
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+/// Motivo por el que no se pudo enviar un paquete
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// No hay dispositivo de red
+    NetworkDown,
+    /// La cola de transmisión del dispositivo, o la de paquetes que esperan
+    /// la resolución ARP, está llena
+    QueueFull,
+    /// El paquete no entra en una trama
+    TooLarge,
+}
+
+struct Interface {
+    device_id: Option<DeviceId>,
+    mac: MacAddress,
+    arp_cache: BTreeMap<Ipv4Addr, MacAddress>,
+    /// Paquetes IPv4 que esperan la dirección MAC del siguiente salto
+    pending: Vec<(Ipv4Addr, Vec<u8>)>,
+}
+
+pub struct NetworkStack {
+    interface: UnsafeCell<Interface>,
+}
+
+unsafe impl Sync for NetworkStack {}
+
+static NETWORK_STACK: NetworkStack = NetworkStack::empty();
+
+impl NetworkStack {
+    const fn empty() -> Self {
+        let interface = Interface {
+            device_id: None,
+            mac: [0; 6],
+            arp_cache: BTreeMap::new(),
+            pending: Vec::new(),
+        };
+        let interface = UnsafeCell::new(interface);
+        Self { interface }
+    }
+
+    /// La interfaz sólo se modifica dentro de los traps, que se atienden con
+    /// las interrupciones deshabilitadas
+    fn interface() -> &'static mut Interface {
+        unsafe { &mut *NETWORK_STACK.interface.get() }
+    }
+
+    /// Usa el primer dispositivo de red, si hay alguno
+    pub fn init() {
+        let Some(device_id) = DeviceManager::find(DeviceType::Net, 0) else {
+            println!("No network device found");
+            return;
+        };
+        let interface = NetworkStack::interface();
+        interface.device_id = Some(device_id);
+        if let Some(device) = DeviceManager::get_net_device(device_id) {
+            interface.mac = device.borrow().mac_address();
+        }
+        println!("Network up: {:?} at {:02x?}", LOCAL_ADDRESS, interface.mac);
+    }
+
+    pub fn is_up() -> bool {
+        NetworkStack::device().is_some()
+    }
+
+    pub fn mac_address() -> MacAddress {
+        NetworkStack::interface().mac
+    }
+
+    fn device() -> Option<&'static RefCell<NetDevice>> {
+        NetworkStack::interface()
+            .device_id
+            .and_then(DeviceManager::get_net_device)
+    }
+
+    /// Procesa las tramas que recibió el dispositivo
+    pub fn poll() {
+        let _tag = AllocTag::Net.enter();
+        let Some(device) = NetworkStack::device() else {
+            return;
+        };
+        loop {
+            // Soltamos el dispositivo antes de procesar, ya que las respuestas
+            // se envían por el mismo
+            let frame = match device.try_borrow_mut() {
+                Ok(mut device) => device.receive(),
+                Err(_) => return,
+            };
+            let Some(frame) = frame else {
+                return;
+            };
+            ethernet::handle(&frame);
+        }
+    }
+
+    /// Envía una trama Ethernet con nuestra dirección como origen. Si el
+    /// dispositivo no la acepta la trama no se envía y devolvemos el error,
+    /// nadie la reintenta por nosotros
+    pub fn send_frame(
+        destination: MacAddress,
+        ethertype: u16,
+        payload: &[u8],
+    ) -> Result<(), SendError> {
+        let _tag = AllocTag::Net.enter();
+        let device = NetworkStack::device().ok_or(SendError::NetworkDown)?;
+        let frame = ethernet::build(destination, NetworkStack::mac_address(), ethertype, payload);
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(SendError::TooLarge);
+        }
+        let mut device = device.try_borrow_mut().map_err(|_| SendError::QueueFull)?;
+        device.send(&frame).map_err(|_| SendError::QueueFull)
+    }
+
+    /// Envía un paquete IPv4, resolviendo antes la dirección MAC del
+    /// siguiente salto si no la conocemos. En ese caso el paquete queda en
+    /// espera y se considera enviado
+    pub fn send_ipv4(destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), SendError> {
+        let packet = ipv4::build(LOCAL_ADDRESS, destination, protocol, payload);
+        let next_hop = if is_local(destination) {
+            destination
+        } else {
+            GATEWAY
+        };
+        let interface = NetworkStack::interface();
+        match interface.arp_cache.get(&next_hop) {
+            Some(mac) => NetworkStack::send_frame(*mac, ETHERTYPE_IPV4, &packet),
+            None => {
+                if interface.pending.len() >= MAX_PENDING_PACKETS {
+                    return Err(SendError::QueueFull);
+                }
+                interface.pending.push((next_hop, packet));
+                // Si la consulta no sale, la reenvía el próximo paquete a
+                // este destino
+                let _ = arp::request(next_hop);
+                Ok(())
+            }
+        }
+    }
+
+    /// Registra la dirección MAC de `address` y envía los paquetes que la
+    /// esperaban. Los que no entran en la cola del dispositivo se pierden
+    pub fn learn(address: Ipv4Addr, mac: MacAddress) {
+        let interface = NetworkStack::interface();
+        interface.arp_cache.insert(address, mac);
+        let (ready, pending) = interface
+            .pending
+            .drain(..)
+            .partition(|(next_hop, _)| *next_hop == address);
+        interface.pending = pending;
+        for (_, packet) in ready {
+            let _ = NetworkStack::send_frame(mac, ETHERTYPE_IPV4, &packet);
+        }
+    }
+}
+
+/// `true` si la dirección está en nuestra subred
+fn is_local(address: Ipv4Addr) -> bool {
+    (0..4).all(|i| address[i] & NETMASK[i] == LOCAL_ADDRESS[i] & NETMASK[i])
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Suma en complemento a uno de palabras de 16 bits, usada por los checksums
+/// de IPv4, ICMP, UDP y TCP
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Checksum de Internet. Al verificar un paquete que incluye su checksum el
+/// resultado es 0
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_fold(checksum_add(0, data))
+}
+
+/// Checksum de UDP y TCP, que incluye un pseudo header con las direcciones
+pub fn pseudo_header_checksum(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    segment: &[u8],
+) -> u16 {
+    let mut sum = checksum_add(0, &source);
+    sum = checksum_add(sum, &destination);
+    sum += protocol as u32;
+    sum += segment.len() as u32;
+    checksum_fold(checksum_add(sum, segment))
+}
@@ -0,0 +1,442 @@
+//! # Sockets
+//! Tabla global de sockets UDP y TCP. Los procesos los usan a través de sus
+//! descriptores de archivo, y un socket se cierra cuando se cierra el último
+//! descriptor que lo referencia. Las conexiones TCP siguen en la tabla hasta
+//! terminar de cerrarse, aunque ya ningún proceso las use.
+use crate::net::tcp::{TcpControlBlock, TcpState};
+use crate::net::{udp, Endpoint, NetworkStack, SendError};
+use crate::system::process::Pid;
+use crate::system::process_table::ProcessTable;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::cmp::min;
+
+pub type SocketId = usize;
+
+/// Primer puerto que asignamos a los sockets que no hicieron `bind`
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+/// Datagramas recibidos que guardamos por socket
+const MAX_DATAGRAMS: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketType {
+    Stream,
+    Datagram,
+}
+
+#[derive(Debug)]
+pub enum SocketError {
+    /// Todavía no hay datos o conexiones, el proceso se despierta cuando
+    /// cambie el estado del socket
+    WouldBlock,
+    InvalidSocket,
+    InvalidOperation,
+    AddressInUse,
+    NotConnected,
+    ConnectionRefused,
+    NetworkDown,
+    /// La cola de transmisión está llena y no se envió nada
+    NoBufferSpace,
+    /// El datagrama es más grande que lo que entra en una trama
+    MessageTooLong,
+}
+
+impl From<SendError> for SocketError {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::NetworkDown => SocketError::NetworkDown,
+            SendError::QueueFull => SocketError::NoBufferSpace,
+            SendError::TooLarge => SocketError::MessageTooLong,
+        }
+    }
+}
+
+pub struct Socket {
+    local_port: Option<u16>,
+    protocol: SocketProtocol,
+    /// Procesos esperando datos o un cambio de estado
+    waiters: Vec<Pid>,
+    /// Cantidad de descriptores de archivo que apuntan al socket
+    refs: usize,
+}
+
+enum SocketProtocol {
+    Udp(UdpSocket),
+    Tcp(TcpControlBlock),
+}
+
+struct UdpSocket {
+    /// Destino por defecto, elegido con `connect`
+    remote: Option<Endpoint>,
+    received: VecDeque<(Endpoint, Vec<u8>)>,
+}
+
+impl Socket {
+    fn new(socket_type: SocketType) -> Self {
+        let protocol = match socket_type {
+            SocketType::Stream => SocketProtocol::Tcp(TcpControlBlock::new()),
+            SocketType::Datagram => SocketProtocol::Udp(UdpSocket {
+                remote: None,
+                received: VecDeque::new(),
+            }),
+        };
+        Self {
+            local_port: None,
+            protocol,
+            waiters: Vec::new(),
+            refs: 1,
+        }
+    }
+
+    /// Conexión entrante, que todavía no tiene descriptores hasta que se acepte
+    pub fn new_tcp(local_port: u16, tcb: TcpControlBlock) -> Self {
+        Self {
+            local_port: Some(local_port),
+            protocol: SocketProtocol::Tcp(tcb),
+            waiters: Vec::new(),
+            refs: 0,
+        }
+    }
+
+    pub fn local_port(&self) -> Option<u16> {
+        self.local_port
+    }
+
+    pub fn tcp(&mut self) -> Option<&mut TcpControlBlock> {
+        match &mut self.protocol {
+            SocketProtocol::Tcp(tcb) => Some(tcb),
+            SocketProtocol::Udp(_) => None,
+        }
+    }
+
+    pub fn is_referenced(&self) -> bool {
+        self.refs > 0
+    }
+
+    pub fn wake(&mut self) {
+        for pid in self.waiters.drain(..) {
+            ProcessTable::wake(pid);
+        }
+    }
+
+    /// Registra a `pid` para despertarlo cuando cambie el estado del socket
+    fn wait(&mut self, pid: Pid) -> SocketError {
+        if !self.waiters.contains(&pid) {
+            self.waiters.push(pid);
+        }
+        SocketError::WouldBlock
+    }
+}
+
+struct SocketList {
+    sockets: BTreeMap<SocketId, Socket>,
+    next_id: SocketId,
+    next_port: u16,
+}
+
+pub struct SocketTable {
+    list: UnsafeCell<SocketList>,
+}
+
+unsafe impl Sync for SocketTable {}
+
+static SOCKET_TABLE: SocketTable = SocketTable::empty();
+
+impl SocketTable {
+    const fn empty() -> Self {
+        let list = SocketList {
+            sockets: BTreeMap::new(),
+            next_id: 0,
+            next_port: FIRST_EPHEMERAL_PORT,
+        };
+        let list = UnsafeCell::new(list);
+        Self { list }
+    }
+
+    /// La tabla sólo se modifica dentro de los traps, que se atienden con las
+    /// interrupciones deshabilitadas
+    fn list() -> &'static mut SocketList {
+        unsafe { &mut *SOCKET_TABLE.list.get() }
+    }
+
+    pub fn get(id: SocketId) -> Option<&'static mut Socket> {
+        SocketTable::list().sockets.get_mut(&id)
+    }
+
+    pub fn insert(socket: Socket) -> SocketId {
+        let list = SocketTable::list();
+        let id = list.next_id;
+        list.next_id += 1;
+        list.sockets.insert(id, socket);
+        id
+    }
+
+    pub fn remove(id: SocketId) {
+        SocketTable::list().sockets.remove(&id);
+    }
+
+    pub fn create(socket_type: SocketType) -> Result<SocketId, SocketError> {
+        if !NetworkStack::is_up() {
+            return Err(SocketError::NetworkDown);
+        }
+        Ok(SocketTable::insert(Socket::new(socket_type)))
+    }
+
+    /// Otro descriptor pasa a apuntar al socket, por ejemplo luego de un `fork`
+    pub fn retain(id: SocketId) {
+        if let Some(socket) = SocketTable::get(id) {
+            socket.refs += 1;
+        }
+    }
+
+    /// Asigna el puerto local. Con el puerto 0 elegimos uno libre
+    pub fn bind(id: SocketId, port: u16) -> Result<(), SocketError> {
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        if socket.local_port.is_some() {
+            return Err(SocketError::InvalidOperation);
+        }
+        let port = if port == 0 {
+            SocketTable::ephemeral_port()
+        } else if SocketTable::port_in_use(port, socket.is_tcp()) {
+            return Err(SocketError::AddressInUse);
+        } else {
+            port
+        };
+        socket.local_port = Some(port);
+        Ok(())
+    }
+
+    pub fn listen(id: SocketId) -> Result<(), SocketError> {
+        SocketTable::ensure_bound(id)?;
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        let tcb = socket.tcp().ok_or(SocketError::InvalidOperation)?;
+        match tcb.state {
+            TcpState::Closed | TcpState::Listen => {
+                tcb.state = TcpState::Listen;
+                Ok(())
+            }
+            _ => Err(SocketError::InvalidOperation),
+        }
+    }
+
+    /// Saca una conexión establecida de la cola del socket que escucha
+    pub fn accept(id: SocketId, pid: Pid) -> Result<(SocketId, Endpoint), SocketError> {
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        let tcb = socket.tcp().ok_or(SocketError::InvalidOperation)?;
+        if tcb.state != TcpState::Listen {
+            return Err(SocketError::InvalidOperation);
+        }
+        while let Some(child_id) = tcb.backlog.pop_front() {
+            // Las conexiones que se cortaron antes de aceptarse ya no están
+            let Some(child) = SocketTable::get(child_id) else {
+                continue;
+            };
+            let Some(remote) = child.tcp().and_then(|child_tcb| child_tcb.remote) else {
+                continue;
+            };
+            child.refs = 1;
+            return Ok((child_id, remote));
+        }
+        Err(socket.wait(pid))
+    }
+
+    /// Conecta el socket. En TCP el proceso espera hasta que termine el
+    /// handshake, en UDP sólo se elige el destino por defecto
+    pub fn connect(id: SocketId, remote: Endpoint, pid: Pid) -> Result<(), SocketError> {
+        SocketTable::ensure_bound(id)?;
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        let local_port = socket.local_port.ok_or(SocketError::InvalidSocket)?;
+        let tcb = match &mut socket.protocol {
+            SocketProtocol::Udp(udp_socket) => {
+                udp_socket.remote = Some(remote);
+                return Ok(());
+            }
+            SocketProtocol::Tcp(tcb) => tcb,
+        };
+        if tcb.reset {
+            return Err(SocketError::ConnectionRefused);
+        }
+        match tcb.state {
+            TcpState::Closed => tcb.connect(local_port, remote),
+            TcpState::SynSent => {}
+            TcpState::Established | TcpState::CloseWait => return Ok(()),
+            _ => return Err(SocketError::InvalidOperation),
+        }
+        Err(socket.wait(pid))
+    }
+
+    /// Envía datos por el socket. Los sockets UDP pueden indicar el destino.
+    /// En TCP se envía lo que entra en la ventana del otro extremo, y si está
+    /// llena el proceso espera a que confirme datos
+    pub fn send(
+        id: SocketId,
+        data: &[u8],
+        destination: Option<Endpoint>,
+        pid: Pid,
+    ) -> Result<usize, SocketError> {
+        SocketTable::ensure_bound(id)?;
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        let local_port = socket.local_port.ok_or(SocketError::InvalidSocket)?;
+        match &mut socket.protocol {
+            SocketProtocol::Udp(udp_socket) => {
+                let destination = destination
+                    .or(udp_socket.remote)
+                    .ok_or(SocketError::NotConnected)?;
+                udp::send(local_port, destination, data)?;
+                Ok(data.len())
+            }
+            SocketProtocol::Tcp(tcb) if tcb.can_send() => match tcb.send_data(local_port, data)? {
+                0 if !data.is_empty() => Err(socket.wait(pid)),
+                sent => Ok(sent),
+            },
+            SocketProtocol::Tcp(_) => Err(SocketError::NotConnected),
+        }
+    }
+
+    /// Recibe datos del socket, junto con el origen en UDP. En TCP devuelve 0
+    /// cuando el otro extremo cerró la conexión
+    pub fn recv(
+        id: SocketId,
+        buf: &mut [u8],
+        pid: Pid,
+    ) -> Result<(usize, Option<Endpoint>), SocketError> {
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        match &mut socket.protocol {
+            SocketProtocol::Udp(udp_socket) => {
+                if let Some((source, datagram)) = udp_socket.received.pop_front() {
+                    // Como en POSIX, lo que no entra en el buffer se descarta
+                    let read = min(buf.len(), datagram.len());
+                    buf[..read].copy_from_slice(&datagram[..read]);
+                    return Ok((read, Some(source)));
+                }
+            }
+            SocketProtocol::Tcp(tcb) => {
+                if tcb.state == TcpState::Listen {
+                    return Err(SocketError::NotConnected);
+                }
+                if !tcb.received.is_empty() || tcb.at_eof() {
+                    let read = min(buf.len(), tcb.received.len());
+                    for (dst, src) in buf.iter_mut().zip(tcb.received.drain(..read)) {
+                        *dst = src;
+                    }
+                    return Ok((read, tcb.remote));
+                }
+            }
+        }
+        Err(socket.wait(pid))
+    }
+
+    /// Cierra un descriptor del socket. Con el último se cierra la conexión
+    pub fn close(id: SocketId) {
+        let Some(socket) = SocketTable::get(id) else {
+            return;
+        };
+        socket.refs = socket.refs.saturating_sub(1);
+        if socket.is_referenced() {
+            return;
+        }
+        SocketTable::close_connection(id);
+    }
+
+    fn close_connection(id: SocketId) {
+        let Some(socket) = SocketTable::get(id) else {
+            return;
+        };
+        let local_port = socket.local_port.unwrap_or(0);
+        let (can_free, backlog) = match &mut socket.protocol {
+            SocketProtocol::Udp(_) => (true, VecDeque::new()),
+            SocketProtocol::Tcp(tcb) => {
+                let backlog = core::mem::take(&mut tcb.backlog);
+                (tcb.close(local_port), backlog)
+            }
+        };
+        if can_free {
+            SocketTable::remove(id);
+        }
+        // Las conexiones que nadie aceptó se cierran junto con el socket que escuchaba
+        for child_id in backlog {
+            SocketTable::close_connection(child_id);
+        }
+    }
+
+    /// Guarda un datagrama para el socket UDP ligado a `port`
+    pub fn deliver_datagram(port: u16, source: Endpoint, data: &[u8]) {
+        let socket = SocketTable::list()
+            .sockets
+            .values_mut()
+            .find(|socket| !socket.is_tcp() && socket.local_port == Some(port));
+        let Some(socket) = socket else {
+            return;
+        };
+        if let SocketProtocol::Udp(udp_socket) = &mut socket.protocol {
+            if udp_socket.received.len() < MAX_DATAGRAMS {
+                udp_socket.received.push_back((source, data.to_vec()));
+            }
+        }
+        socket.wake();
+    }
+
+    /// Conexión TCP establecida (o estableciéndose) entre el puerto local y `remote`
+    pub fn find_connection(port: u16, remote: Endpoint) -> Option<SocketId> {
+        SocketTable::find_tcp(port, |tcb| {
+            tcb.state != TcpState::Listen && tcb.remote == Some(remote)
+        })
+    }
+
+    /// Socket TCP escuchando en el puerto local
+    pub fn find_listener(port: u16) -> Option<SocketId> {
+        SocketTable::find_tcp(port, |tcb| tcb.state == TcpState::Listen)
+    }
+
+    fn find_tcp(port: u16, predicate: impl Fn(&TcpControlBlock) -> bool) -> Option<SocketId> {
+        SocketTable::list()
+            .sockets
+            .iter()
+            .find(|(_, socket)| match &socket.protocol {
+                SocketProtocol::Tcp(tcb) => socket.local_port == Some(port) && predicate(tcb),
+                SocketProtocol::Udp(_) => false,
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Los sockets que envían sin haber hecho `bind` usan un puerto efímero
+    fn ensure_bound(id: SocketId) -> Result<(), SocketError> {
+        let socket = SocketTable::get(id).ok_or(SocketError::InvalidSocket)?;
+        if socket.local_port.is_none() {
+            socket.local_port = Some(SocketTable::ephemeral_port());
+        }
+        Ok(())
+    }
+
+    fn port_in_use(port: u16, tcp: bool) -> bool {
+        SocketTable::list()
+            .sockets
+            .values()
+            .any(|socket| socket.is_tcp() == tcp && socket.local_port == Some(port))
+    }
+
+    fn ephemeral_port() -> u16 {
+        let list = SocketTable::list();
+        loop {
+            let port = list.next_port;
+            list.next_port = list
+                .next_port
+                .checked_add(1)
+                .unwrap_or(FIRST_EPHEMERAL_PORT);
+            let in_use = list
+                .sockets
+                .values()
+                .any(|socket| socket.local_port == Some(port));
+            if !in_use {
+                return port;
+            }
+        }
+    }
+}
+
+impl Socket {
+    fn is_tcp(&self) -> bool {
+        matches!(self.protocol, SocketProtocol::Tcp(_))
+    }
+}
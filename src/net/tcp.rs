@@ -0,0 +1,462 @@
+//! # TCP
+//! Máquina de estados básica de TCP (RFC 793). No hay retransmisiones ni
+//! control de congestión: confiamos en que la red de QEMU no pierde
+//! segmentos. Los segmentos fuera de orden se descartan y se vuelve a
+//! confirmar lo último recibido, para que el otro extremo los reenvíe. Al
+//! enviar respetamos la ventana del otro extremo, y si un segmento no entra
+//! en la cola del dispositivo no se envía: `send_data` devuelve cuánto salió.
+use crate::net::ipv4::{Ipv4Header, PROTOCOL_TCP};
+use crate::net::socket::{Socket, SocketId, SocketTable};
+use crate::net::{
+    pseudo_header_checksum, read_u16, read_u32, Endpoint, NetworkStack, SendError, LOCAL_ADDRESS,
+};
+use crate::system::random::EntropyPool;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+pub const FLAG_FIN: u8 = 0x01;
+pub const FLAG_SYN: u8 = 0x02;
+pub const FLAG_RST: u8 = 0x04;
+pub const FLAG_PSH: u8 = 0x08;
+pub const FLAG_ACK: u8 = 0x10;
+const HEADER_SIZE: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+/// MSS que anunciamos: una trama Ethernet menos los headers IPv4 y TCP
+const LOCAL_MSS: u16 = 1460;
+/// MSS a usar si el otro extremo no anuncia uno
+const DEFAULT_MSS: usize = 536;
+/// Bytes recibidos que guardamos hasta que el proceso los lea. Lo que no
+/// entra se descarta sin confirmar
+const RECEIVE_BUFFER_SIZE: usize = 0x10000;
+const RECEIVE_WINDOW: u16 = 0xffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    LastAck,
+}
+
+pub struct TcpControlBlock {
+    pub state: TcpState,
+    pub remote: Option<Endpoint>,
+    /// Primer byte enviado que el otro extremo no confirmó
+    snd_una: u32,
+    /// Próximo número de secuencia a enviar
+    snd_nxt: u32,
+    /// Próximo número de secuencia que esperamos recibir
+    rcv_nxt: u32,
+    /// Ventana que anunció el otro extremo en su último ACK
+    snd_wnd: u32,
+    /// Tamaño máximo de segmento del otro extremo
+    mss: usize,
+    pub received: VecDeque<u8>,
+    /// En los sockets que escuchan, conexiones establecidas que todavía no
+    /// se aceptaron
+    pub backlog: VecDeque<SocketId>,
+    /// Socket que escuchaba cuando llegó la conexión
+    parent: Option<SocketId>,
+    /// La conexión se cortó con un RST
+    pub reset: bool,
+    /// El otro extremo cerró su lado de la conexión
+    pub fin_received: bool,
+}
+
+pub(crate) struct TcpSegment {
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<usize>,
+}
+
+impl TcpSegment {
+    /// Separa el header del segmento de sus datos. Falla si el header no
+    /// entra en `data`
+    pub(crate) fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        let header_size = (data[12] >> 4) as usize * 4;
+        if header_size < HEADER_SIZE || header_size > data.len() {
+            return None;
+        }
+        let segment = TcpSegment {
+            source_port: read_u16(data, 0),
+            destination_port: read_u16(data, 2),
+            seq: read_u32(data, 4),
+            ack: read_u32(data, 8),
+            flags: data[13],
+            window: read_u16(data, 14),
+            mss: parse_mss(&data[HEADER_SIZE..header_size]),
+        };
+        Some((segment, &data[header_size..]))
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Espacio de secuencia que ocupa el segmento: los datos más SYN y FIN
+    fn sequence_len(&self, payload: &[u8]) -> u32 {
+        let mut len = payload.len() as u32;
+        if self.has(FLAG_SYN) {
+            len += 1;
+        }
+        if self.has(FLAG_FIN) {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Busca la opción MSS entre las opciones del header
+fn parse_mss(mut options: &[u8]) -> Option<usize> {
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => return None,
+            OPTION_NOP => options = rest,
+            _ => {
+                let len = *rest.first()? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    return Some(read_u16(options, 2) as usize);
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    None
+}
+
+impl TcpControlBlock {
+    pub fn new() -> Self {
+        Self {
+            state: TcpState::Closed,
+            remote: None,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            received: VecDeque::new(),
+            backlog: VecDeque::new(),
+            parent: None,
+            reset: false,
+            fin_received: false,
+        }
+    }
+
+    /// Abre la conexión enviando un SYN
+    pub fn connect(&mut self, local_port: u16, remote: Endpoint) {
+        let iss = initial_sequence_number();
+        self.remote = Some(remote);
+        self.snd_una = iss;
+        self.snd_nxt = iss.wrapping_add(1);
+        self.state = TcpState::SynSent;
+        // Si el SYN no sale la conexión queda en SYN-SENT, como si se
+        // hubiera perdido en la red
+        let _ = self.send(local_port, FLAG_SYN, iss, &[]);
+    }
+
+    /// Envía los datos que entran en la ventana del otro extremo, en
+    /// segmentos del tamaño que acepta. Devuelve la cantidad de bytes
+    /// enviados, que puede ser menor a la pedida o 0 si la ventana está
+    /// llena. Si no se pudo enviar ningún segmento devuelve el error
+    pub fn send_data(&mut self, local_port: u16, data: &[u8]) -> Result<usize, SendError> {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+        let window = self.snd_wnd.saturating_sub(in_flight) as usize;
+        let data = &data[..min(data.len(), window)];
+        let mut sent = 0;
+        for chunk in data.chunks(min(self.mss, LOCAL_MSS as usize)) {
+            if let Err(error) = self.send(local_port, FLAG_ACK | FLAG_PSH, self.snd_nxt, chunk) {
+                return if sent == 0 { Err(error) } else { Ok(sent) };
+            }
+            self.snd_nxt = self.snd_nxt.wrapping_add(chunk.len() as u32);
+            sent += chunk.len();
+        }
+        Ok(sent)
+    }
+
+    /// Cierra nuestro lado de la conexión. Devuelve `true` si el socket ya se
+    /// puede liberar
+    pub fn close(&mut self, local_port: u16) -> bool {
+        match self.state {
+            TcpState::SynReceived | TcpState::Established => {
+                self.send_fin(local_port);
+                self.state = TcpState::FinWait1;
+                false
+            }
+            TcpState::CloseWait => {
+                self.send_fin(local_port);
+                self.state = TcpState::LastAck;
+                false
+            }
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::LastAck => false,
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent => {
+                self.state = TcpState::Closed;
+                true
+            }
+        }
+    }
+
+    /// `true` si se pueden enviar datos
+    pub fn can_send(&self) -> bool {
+        matches!(self.state, TcpState::Established | TcpState::CloseWait)
+    }
+
+    /// `true` si no van a llegar más datos
+    pub fn at_eof(&self) -> bool {
+        self.fin_received || self.reset || self.state == TcpState::Closed
+    }
+
+    fn send_fin(&mut self, local_port: u16) {
+        let _ = self.send(local_port, FLAG_FIN | FLAG_ACK, self.snd_nxt, &[]);
+        self.snd_nxt = self.snd_nxt.wrapping_add(1);
+    }
+
+    /// Si la confirmación no sale, la repite el próximo segmento que envíe
+    /// el otro extremo
+    fn send_ack(&self, local_port: u16) {
+        let _ = self.send(local_port, FLAG_ACK, self.snd_nxt, &[]);
+    }
+
+    fn send(&self, local_port: u16, flags: u8, seq: u32, payload: &[u8]) -> Result<(), SendError> {
+        match self.remote {
+            Some(remote) => send_segment(local_port, remote, seq, self.rcv_nxt, flags, payload),
+            None => Ok(()),
+        }
+    }
+
+    /// Procesa un segmento de la conexión. Devuelve `true` si hay que
+    /// despertar a los procesos que esperan el socket
+    fn receive(&mut self, local_port: u16, segment: &TcpSegment, payload: &[u8]) -> bool {
+        if segment.has(FLAG_RST) {
+            self.reset = true;
+            self.state = TcpState::Closed;
+            return true;
+        }
+        if self.state == TcpState::SynSent {
+            if segment.has(FLAG_SYN) && segment.has(FLAG_ACK) && segment.ack == self.snd_nxt {
+                self.rcv_nxt = segment.seq.wrapping_add(1);
+                self.snd_una = segment.ack;
+                self.snd_wnd = segment.window as u32;
+                self.mss = segment.mss.unwrap_or(DEFAULT_MSS);
+                self.state = TcpState::Established;
+                self.send_ack(local_port);
+                return true;
+            }
+            return false;
+        }
+        if segment.has(FLAG_SYN) {
+            // El otro extremo no recibió nuestra confirmación, la repetimos
+            self.send_ack(local_port);
+            return false;
+        }
+        let mut notify = false;
+        if segment.has(FLAG_ACK) {
+            notify |= self.receive_ack(segment.ack, segment.window);
+        }
+        if !payload.is_empty() {
+            let accepts_data = matches!(
+                self.state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            );
+            let fits = self.received.len() + payload.len() <= RECEIVE_BUFFER_SIZE;
+            if segment.seq == self.rcv_nxt && accepts_data && fits {
+                self.received.extend(payload);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(payload.len() as u32);
+                notify = true;
+            }
+        }
+        let fin_seq = segment.seq.wrapping_add(payload.len() as u32);
+        if segment.has(FLAG_FIN) && fin_seq == self.rcv_nxt && !self.fin_received {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.state = match self.state {
+                TcpState::Established | TcpState::SynReceived => TcpState::CloseWait,
+                // Sin timers no esperamos en TIME-WAIT
+                TcpState::FinWait1 | TcpState::FinWait2 => TcpState::Closed,
+                state => state,
+            };
+            notify = true;
+        }
+        if segment.sequence_len(payload) > 0 {
+            self.send_ack(local_port);
+        }
+        notify
+    }
+
+    /// Avanza lo confirmado por el otro extremo, su ventana y los cierres que
+    /// esperaban la confirmación de nuestro FIN
+    fn receive_ack(&mut self, ack: u32, window: u16) -> bool {
+        let acked = ack.wrapping_sub(self.snd_una);
+        if acked > self.snd_nxt.wrapping_sub(self.snd_una) {
+            return false;
+        }
+        // Una ventana que se abre sin datos nuevos confirmados también
+        // despierta a quien espera para escribir
+        let window_opened = window as u32 > self.snd_wnd;
+        self.snd_wnd = window as u32;
+        if acked == 0 {
+            return window_opened;
+        }
+        self.snd_una = ack;
+        let all_acked = ack == self.snd_nxt;
+        match self.state {
+            TcpState::SynReceived => {
+                self.state = TcpState::Established;
+                true
+            }
+            TcpState::FinWait1 if all_acked => {
+                self.state = TcpState::FinWait2;
+                false
+            }
+            TcpState::LastAck if all_acked => {
+                self.state = TcpState::Closed;
+                true
+            }
+            // Se liberó espacio en la ventana
+            _ => true,
+        }
+    }
+}
+
+impl Default for TcpControlBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Números de secuencia iniciales aleatorios, para que no se repitan entre
+/// conexiones ni se puedan adivinar
+fn initial_sequence_number() -> u32 {
+    EntropyPool::random_u32()
+}
+
+pub fn handle(header: &Ipv4Header, data: &[u8]) {
+    if pseudo_header_checksum(header.source, header.destination, PROTOCOL_TCP, data) != 0 {
+        return;
+    }
+    let Some((segment, payload)) = TcpSegment::parse(data) else {
+        return;
+    };
+    let local_port = segment.destination_port;
+    let remote = Endpoint {
+        address: header.source,
+        port: segment.source_port,
+    };
+    if let Some(id) = SocketTable::find_connection(local_port, remote) {
+        receive_on_connection(id, &segment, payload);
+    } else if let Some(id) = SocketTable::find_listener(local_port) {
+        if segment.has(FLAG_SYN) && !segment.has(FLAG_ACK) {
+            accept_connection(id, local_port, remote, &segment);
+        } else if !segment.has(FLAG_RST) {
+            send_reset(local_port, remote, &segment, payload);
+        }
+    } else if !segment.has(FLAG_RST) {
+        send_reset(local_port, remote, &segment, payload);
+    }
+}
+
+fn receive_on_connection(id: SocketId, segment: &TcpSegment, payload: &[u8]) {
+    let Some(socket) = SocketTable::get(id) else {
+        return;
+    };
+    let local_port = socket.local_port().unwrap_or(segment.destination_port);
+    let Some(tcb) = socket.tcp() else {
+        return;
+    };
+    let was_pending = tcb.state == TcpState::SynReceived;
+    let notify = tcb.receive(local_port, segment, payload);
+    let established = was_pending && tcb.state == TcpState::Established;
+    let parent = tcb.parent;
+    let closed = tcb.state == TcpState::Closed;
+    if notify {
+        socket.wake();
+    }
+    if closed && !socket.is_referenced() {
+        SocketTable::remove(id);
+    }
+    // La conexión terminó el handshake: queda lista para `accept`
+    if established {
+        if let Some(listener) = parent.and_then(SocketTable::get) {
+            if let Some(listener_tcb) = listener.tcp() {
+                listener_tcb.backlog.push_back(id);
+            }
+            listener.wake();
+        }
+    }
+}
+
+/// Crea un socket para la conexión entrante y responde con SYN-ACK
+fn accept_connection(listener: SocketId, local_port: u16, remote: Endpoint, segment: &TcpSegment) {
+    let iss = initial_sequence_number();
+    let mut tcb = TcpControlBlock::new();
+    tcb.state = TcpState::SynReceived;
+    tcb.remote = Some(remote);
+    tcb.parent = Some(listener);
+    tcb.rcv_nxt = segment.seq.wrapping_add(1);
+    tcb.snd_una = iss;
+    tcb.snd_nxt = iss.wrapping_add(1);
+    tcb.snd_wnd = segment.window as u32;
+    tcb.mss = segment.mss.unwrap_or(DEFAULT_MSS);
+    // Si el SYN-ACK no sale, el otro extremo repite el SYN
+    let _ = tcb.send(local_port, FLAG_SYN | FLAG_ACK, iss, &[]);
+    SocketTable::insert(Socket::new_tcp(local_port, tcb));
+}
+
+/// Rechaza un segmento que no corresponde a ninguna conexión
+fn send_reset(local_port: u16, remote: Endpoint, segment: &TcpSegment, payload: &[u8]) {
+    if segment.has(FLAG_ACK) {
+        let _ = send_segment(local_port, remote, segment.ack, 0, FLAG_RST, &[]);
+    } else {
+        let ack = segment.seq.wrapping_add(segment.sequence_len(payload));
+        let _ = send_segment(local_port, remote, 0, ack, FLAG_RST | FLAG_ACK, &[]);
+    }
+}
+
+fn send_segment(
+    local_port: u16,
+    remote: Endpoint,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Result<(), SendError> {
+    // Anunciamos nuestro MSS al abrir la conexión
+    let options: &[u8] = if flags & FLAG_SYN != 0 {
+        let mss = LOCAL_MSS.to_be_bytes();
+        &[OPTION_MSS, 4, mss[0], mss[1]]
+    } else {
+        &[]
+    };
+    let header_size = HEADER_SIZE + options.len();
+    let mut segment = Vec::with_capacity(header_size + payload.len());
+    segment.extend_from_slice(&local_port.to_be_bytes());
+    segment.extend_from_slice(&remote.port.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[((header_size / 4) as u8) << 4, flags]);
+    segment.extend_from_slice(&RECEIVE_WINDOW.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(payload);
+    let segment_checksum =
+        pseudo_header_checksum(LOCAL_ADDRESS, remote.address, PROTOCOL_TCP, &segment);
+    segment[16..18].copy_from_slice(&segment_checksum.to_be_bytes());
+    NetworkStack::send_ipv4(remote.address, PROTOCOL_TCP, &segment)
+}
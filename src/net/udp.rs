@@ -0,0 +1,59 @@
+//! # UDP
+use crate::net::ipv4::{Ipv4Header, PROTOCOL_UDP};
+use crate::net::socket::SocketTable;
+use crate::net::{
+    pseudo_header_checksum, read_u16, Endpoint, NetworkStack, SendError, LOCAL_ADDRESS,
+};
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 8;
+/// Datos que entran en un datagrama sin fragmentar: el MTU de Ethernet menos
+/// los headers IPv4 y UDP
+pub const MAX_PAYLOAD: usize = 1500 - 20 - HEADER_SIZE;
+
+pub fn handle(header: &Ipv4Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let size = read_u16(datagram, 4) as usize;
+    if size < HEADER_SIZE || size > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..size];
+    // El checksum es opcional en IPv4: 0 indica que no se calculó
+    let has_checksum = read_u16(datagram, 6) != 0;
+    if has_checksum
+        && pseudo_header_checksum(header.source, header.destination, PROTOCOL_UDP, datagram) != 0
+    {
+        return;
+    }
+    let source = Endpoint {
+        address: header.source,
+        port: read_u16(datagram, 0),
+    };
+    let destination_port = read_u16(datagram, 2);
+    SocketTable::deliver_datagram(destination_port, source, &datagram[HEADER_SIZE..]);
+}
+
+/// Envía un datagrama. `data` no puede superar `MAX_PAYLOAD`, porque no
+/// fragmentamos
+pub fn send(source_port: u16, destination: Endpoint, data: &[u8]) -> Result<(), SendError> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(SendError::TooLarge);
+    }
+    let size = (HEADER_SIZE + data.len()) as u16;
+    let mut datagram = Vec::with_capacity(size as usize);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&destination.port.to_be_bytes());
+    datagram.extend_from_slice(&size.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let mut datagram_checksum =
+        pseudo_header_checksum(LOCAL_ADDRESS, destination.address, PROTOCOL_UDP, &datagram);
+    // Un checksum calculado de 0 se transmite como 0xffff
+    if datagram_checksum == 0 {
+        datagram_checksum = 0xffff;
+    }
+    datagram[6..8].copy_from_slice(&datagram_checksum.to_be_bytes());
+    NetworkStack::send_ipv4(destination.address, PROTOCOL_UDP, &datagram)
+}
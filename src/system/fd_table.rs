@@ -0,0 +1,80 @@
+//! # Tabla de descriptores de archivo
+//! Cada proceso tiene su propia tabla, que traduce los números de descriptor
+//! que usan las syscalls a archivos abiertos en el sistema de archivos
+//! virtual, a la consola o a sockets.
+use crate::filesystem::virtual_fs::FileDescriptor;
+use crate::net::socket::{SocketId, SocketTable};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Archivo abierto por un proceso
+#[derive(Clone, Debug)]
+pub enum OpenFile {
+    /// Entrada y salida estándar
+    Console,
+    File(FileDescriptor),
+    Socket(SocketId),
+}
+
+#[derive(Clone, Debug)]
+pub struct FdTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl FdTable {
+    /// Tabla nueva con stdin, stdout y stderr asociados a la consola
+    pub fn new() -> Self {
+        let files = vec![
+            Some(OpenFile::Console),
+            Some(OpenFile::Console),
+            Some(OpenFile::Console),
+        ];
+        Self { files }
+    }
+
+    /// Agrega el archivo en el descriptor libre más bajo y lo devuelve
+    pub fn insert(&mut self, file: OpenFile) -> usize {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn get(&mut self, fd: usize) -> Option<&mut OpenFile> {
+        self.files.get_mut(fd).and_then(Option::as_mut)
+    }
+
+    /// Cierra el descriptor, devolviendo el archivo que tenía asociado
+    pub fn remove(&mut self, fd: usize) -> Option<OpenFile> {
+        self.files.get_mut(fd).and_then(Option::take)
+    }
+
+    /// Sockets abiertos en la tabla, uno por descriptor
+    pub fn sockets(&self) -> impl Iterator<Item = SocketId> + '_ {
+        self.files.iter().filter_map(|file| match file {
+            Some(OpenFile::Socket(id)) => Some(*id),
+            _ => None,
+        })
+    }
+
+    /// Cierra todos los descriptores, liberando los sockets que ya no se usen
+    pub fn close_all(&mut self) {
+        for file in self.files.drain(..).flatten() {
+            if let OpenFile::Socket(id) = file {
+                SocketTable::close(id);
+            }
+        }
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
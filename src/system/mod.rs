@@ -1,3 +1,7 @@
+pub mod fd_table;
 pub mod process;
+pub mod process_table;
 pub mod proto;
+pub mod random;
+pub mod scheduler;
 pub mod syscall;
@@ -2,18 +2,35 @@
 //! Son la base de los sistemas operativos, cada proceso es una instancia
 //! de un programa que queremos ejecutar
 use crate::assembly::riscv64;
-use crate::cpu::riscv64::trap::TrapFrame;
-use crate::init::user_mode_init;
-use crate::mmu::map_table::{EntryBits, MapTable};
+use crate::cpu::riscv64::trap::{read_mtime, TrapFrame, MSECS_CYCLES};
+use crate::filesystem::virtual_fs::VirtualFsManager;
+use crate::mmu::alloc_stats::AllocTag;
+use crate::mmu::map_table::{Entry, EntryBits, MapTable};
 use crate::mmu::riscv64::{PageTable, PAGE_SIZE};
-use crate::system::proto::elf_loader::ElfLoader;
+use crate::mmu::vma::{Access, Vma, VmaKind, VmaList};
+use crate::net::socket::SocketTable;
+use crate::system::fd_table::FdTable;
+use crate::system::proto::elf_loader::{ElfLoader, ElfLoaderError};
+use crate::system::random::EntropyPool;
+use crate::system::scheduler::Scheduler;
+use crate::utils::error::IoError;
 use crate::{print, println};
-use core::cell::UnsafeCell;
-use core::mem::MaybeUninit;
-use core::ptr::NonNull;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::{copy_nonoverlapping, NonNull};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// Identificador de proceso. También se usa como ASID de su tabla de páginas
+pub type Pid = u16;
 
 /// # Estados del proceso
 /// Enumerado con los estados básicos en los que puede estar un proceso.
+/// * Running: listo para usar la CPU (o usándola)
+/// * Sleeping: dormido hasta que el `mtime` alcance `sleep_until`
+/// * Waiting: bloqueado esperando un evento externo
+/// * Dead: terminó su ejecución, queda como *zombie* hasta que el padre lea
+///   su estado de salida
 #[derive(Debug)]
 pub enum ProcessState {
     Running,
@@ -22,92 +39,515 @@ pub enum ProcessState {
     Dead,
 }
 
+/// Errores al reemplazar la imagen de un proceso con `exec`
+#[derive(Debug)]
+pub enum ExecError {
+    Io(IoError),
+    InvalidElf(ElfLoaderError),
+}
+
+impl From<IoError> for ExecError {
+    fn from(value: IoError) -> Self {
+        ExecError::Io(value)
+    }
+}
+
+impl From<ElfLoaderError> for ExecError {
+    fn from(value: ElfLoaderError) -> Self {
+        ExecError::InvalidElf(value)
+    }
+}
+
 /// Proceso
 /// Cada proceso posee los siguientes atributos:
 /// * frame: representa el contexto del proceso, es decir, el estado de los
 ///   registros, stack pointer, mmu, etc.
-/// * stack: una porción de memoria para el stack de variables
 /// * program_counter
 /// * pid: identificador único del proceso
 /// * root: tabla de mapeo de memoria
 /// * state: estado del proceso
+/// * sleep_until: valor de `mtime` en el que despierta un proceso dormido
+/// * parent: PID del proceso padre, si sigue vivo
+/// * exit_status: código de salida, válido cuando el proceso está muerto
+/// * vmas: áreas de memoria virtual válidas del proceso. Las páginas de
+///   usuario pertenecen a las hojas de `root`: cada hoja es una referencia a
+///   su página física, que se suelta al destruir el proceso
+/// * files: tabla de descriptores de archivo
 #[repr(C)]
 #[derive(Debug)]
 pub struct Process<'a> {
     frame: TrapFrame,
-    stack: NonNull<u8>,
     pub program_counter: usize,
-    pid: u16,
+    pid: Pid,
     pub root: &'a mut MapTable<'a>,
-    state: ProcessState,
+    pub state: ProcessState,
+    sleep_until: u64,
+    parent: Option<Pid>,
+    exit_status: isize,
+    vmas: VmaList,
+    pub files: FdTable,
     parent_page_table: &'a PageTable,
 }
 
 /// El registro *sp* es el *x2*
 const SP_REGISTER: usize = 2;
-/// Por ahora constante, la cantidad de páginas que arman el stack
+/// El registro *a0* es el *x10*, donde se devuelve el resultado de las syscalls
+const A0_REGISTER: usize = 10;
+const A1_REGISTER: usize = 11;
+const A2_REGISTER: usize = 12;
+/// Páginas del tope del stack en las que deben entrar los argumentos
 const STACK_PAGES: usize = 2;
-const EXTERNAL_ELF_START: usize = 0x8200_0000;
 /// Dónde arranca el stack (recordar que va de arriba hacia abajo)
 pub const STACK_ADDR: usize = 0x1_0000_0000;
+/// Páginas que puede ocupar el stack. Se reservan a medida que crece
+const STACK_MAX_PAGES: usize = 256;
+/// Tamaño máximo del heap, que empieza luego del ejecutable
+pub const USER_HEAP_SIZE: usize = 0x100_0000;
+/// Estado de salida de un proceso terminado por un acceso inválido, como
+/// lo informa un shell para `SIGSEGV`
+pub const SEGFAULT_STATUS: isize = 139;
+/// El ABI pide el stack alineado a 16 bytes
+const STACK_ALIGN: usize = 16;
+/// Bytes aleatorios que apunta `AT_RANDOM`
+const AT_RANDOM_SIZE: usize = 16;
+/// Entradas del vector auxiliar que agrega el proceso (ver `elf.h`)
+const AT_NULL: usize = 0;
+const AT_RANDOM: usize = 25;
 
-/// PID global: cuando sea multithread, proteger
-static mut NEXT_PID: u16 = 0;
+/// Próximo PID a asignar. El ASID 0 es el del kernel, así que `init` es el 1
+static NEXT_PID: AtomicU16 = AtomicU16::new(1);
+
+/// Reserva un PID nuevo de forma atómica
+fn allocate_pid() -> Pid {
+    NEXT_PID.fetch_add(1, Ordering::Relaxed)
+}
 
 impl<'a> Process<'a> {
-    fn new(page_table: &'a PageTable, root: &'a mut MapTable<'a>) -> Self {
+    fn new(page_table: &'a PageTable, root: &'a mut MapTable<'a>, pid: Pid) -> Self {
         Process {
             frame: TrapFrame::new(),
-            stack: page_table.alloc(STACK_PAGES).unwrap(),
             program_counter: 0,
-            pid: unsafe { NEXT_PID },
+            pid,
             root,
-            state: ProcessState::Waiting,
+            state: ProcessState::Running,
+            sleep_until: 0,
+            parent: None,
+            exit_status: 0,
+            vmas: VmaList::new(),
+            files: FdTable::new(),
             parent_page_table: page_table,
         }
     }
     /// Crea un proceso nuevo, que ejecuta la función que le pasamos por
     /// parámetros
     pub fn create(page_table: &'a PageTable) -> Self {
+        Process::with_pid(page_table, allocate_pid())
+    }
+
+    /// Crea un proceso vacío con un PID que ya está en uso. `exec` carga ahí
+    /// la imagen nueva antes de pasársela al proceso
+    fn with_pid(page_table: &'a PageTable, pid: Pid) -> Self {
+        let _tag = AllocTag::Process.enter();
         let root_ptr = page_table.zalloc(1).unwrap().as_ptr() as *mut MaybeUninit<MapTable>;
         let root = unsafe { &mut *root_ptr };
         root.write(MapTable::new(page_table));
         let root_init = unsafe {
             core::mem::transmute::<&mut MaybeUninit<MapTable<'_>>, &mut MapTable<'_>>(root)
         };
-        let mut process = Process::new(page_table, root_init);
-        unsafe {
-            // En un contexto multi-core acá habría una race condition
-            NEXT_PID += 1;
-        }
-        // Mapeo el stack en la MMU
-        // Inicializo el stack pointer
-        process.frame.regs[SP_REGISTER] = STACK_ADDR + PAGE_SIZE * STACK_PAGES - 8;
-        let saddr = process.stack.as_ptr() as usize;
-        // Mapeamos la memoria del stack en la memoria virtual del usuario
-        // También mapeamos la dirección de la función que va a ejecutar el proceso
-        for i in 0..STACK_PAGES {
-            let addr = i * PAGE_SIZE;
-            process.map_memory(
-                STACK_ADDR + addr,
-                saddr + addr,
-                EntryBits::UserReadWrite.val(),
-                0,
-            );
-        }
-        // Actualizo map_page
-        process.root.update_satp(process.pid);
+        let mut process = Process::new(page_table, root_init, pid);
+        // Inicializo el stack pointer. Las páginas del stack se reservan
+        // cuando el proceso las usa
+        let stack_top = STACK_ADDR + PAGE_SIZE * STACK_PAGES;
+        process.frame.regs[SP_REGISTER] = stack_top - 8;
+        process.add_vma(Vma::new(
+            stack_top - PAGE_SIZE * STACK_MAX_PAGES,
+            stack_top,
+            EntryBits::UserReadWrite.val(),
+            VmaKind::Stack,
+        ));
+        // El satp se escribe recién cuando el planificador activa al proceso
+        process.frame.satp = process.root.get_initial_satp(process.pid);
         process
     }
 
     pub fn map_memory(&mut self, vaddr: usize, paddr: usize, bits: i64, level: usize) {
         self.root.map(vaddr, paddr, bits, level);
     }
+
+    /// Tabla de la que se reservan las páginas del proceso
+    pub fn page_table(&self) -> &'a PageTable {
+        self.parent_page_table
+    }
+
+    pub fn get_pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn get_stack_pointer(&self) -> usize {
+        self.frame.regs[SP_REGISTER]
+    }
+
+    pub fn get_parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn set_parent(&mut self, parent: Option<Pid>) {
+        self.parent = parent;
+    }
+
+    pub fn get_exit_status(&self) -> isize {
+        self.exit_status
+    }
+
+    /// Agrega un área de memoria válida. Devuelve `false` si se superpone
+    /// con otra
+    pub fn add_vma(&mut self, vma: Vma) -> bool {
+        self.vmas.insert(vma)
+    }
+
+    pub fn vmas(&self) -> &VmaList {
+        &self.vmas
+    }
+
+    /// Límite del heap, o 0 si el proceso no tiene heap
+    pub fn heap_limit(&self) -> usize {
+        self.vmas
+            .find_kind(VmaKind::Heap)
+            .map_or(0, |heap| heap.end)
+    }
+
+    /// Resuelve un page fault en `vaddr`. Si la dirección pertenece a un área
+    /// del proceso que admite el acceso y su página todavía no está mapeada,
+    /// reserva una página en cero y la mapea. Si es una escritura a una
+    /// página compartida por `fork`, le da al proceso su propia copia.
+    /// Devuelve `false` si el acceso es inválido.
+    pub fn handle_page_fault(&mut self, vaddr: usize, access: Access) -> bool {
+        let Some(vma) = self.vmas.find(vaddr).copied() else {
+            return false;
+        };
+        if !vma.allows(access) {
+            return false;
+        }
+        let _tag = AllocTag::Process.enter();
+        let page_vaddr = vaddr & !(PAGE_SIZE - 1);
+        let resolved = match self.root.find_leaf(vaddr) {
+            None => self.map_zeroed(page_vaddr, vma.bits),
+            Some((entry, _)) if access == Access::Store && self.needs_copy(entry) => {
+                self.copy_on_write(page_vaddr, entry.get_address(), vma.bits)
+            }
+            Some(_) => false,
+        };
+        if resolved {
+            unsafe { riscv64::satp_fence_asid(self.pid as usize) };
+        }
+        resolved
+    }
+
+    /// Mapea una página nueva en cero
+    fn map_zeroed(&mut self, vaddr: usize, bits: i64) -> bool {
+        let Some(page) = self.parent_page_table.zalloc(1) else {
+            return false;
+        };
+        self.map_memory(vaddr, page.as_ptr() as usize, bits, 0);
+        true
+    }
+
+    /// Indica si hay que pasar por `copy_on_write` antes de escribir en la
+    /// página de `entry`: si quedó de sólo lectura o si la comparte otro
+    /// proceso
+    fn needs_copy(&self, entry: Entry) -> bool {
+        let frame = unsafe { NonNull::new_unchecked(entry.get_address() as *mut u8) };
+        !entry.is_writable() || self.parent_page_table.ref_count(frame) > 1
+    }
+
+    /// Resuelve la escritura a la página `paddr`, que quedó de sólo lectura
+    /// por `MapTable::clone_cow`. Si otro proceso la sigue usando la copiamos,
+    /// y si no, alcanza con devolverle el permiso de escritura
+    fn copy_on_write(&mut self, vaddr: usize, paddr: usize, bits: i64) -> bool {
+        let frame = unsafe { NonNull::new_unchecked(paddr as *mut u8) };
+        if self.parent_page_table.ref_count(frame) == 1 {
+            self.map_memory(vaddr, paddr, bits, 0);
+            return true;
+        }
+        let Some(page) = self.parent_page_table.alloc(1) else {
+            return false;
+        };
+        unsafe { copy_nonoverlapping(paddr as *const u8, page.as_ptr(), PAGE_SIZE) };
+        self.parent_page_table.release(frame);
+        self.map_memory(vaddr, page.as_ptr() as usize, bits, 0);
+        true
+    }
+
+    /// Dirección física de `vaddr` para un acceso del kernel en nombre del
+    /// proceso. Se aplican los mismos permisos que si accediera el proceso:
+    /// si el área no admite el acceso devuelve `None`. Si la página todavía
+    /// no se reservó, o si hay que escribir en una página compartida, se
+    /// resuelve igual que un page fault, ya que el kernel accede a la memoria
+    /// del proceso sin pasar por la MMU
+    fn user_to_phys(&mut self, vaddr: usize, access: Access) -> Option<usize> {
+        if !self.vmas.find(vaddr)?.allows(access) {
+            return None;
+        }
+        let needs_fault = match self.root.find_leaf(vaddr) {
+            None => true,
+            Some((entry, _)) => access == Access::Store && self.needs_copy(entry),
+        };
+        if needs_fault && !self.handle_page_fault(vaddr, access) {
+            return None;
+        }
+        self.root.virt_to_phys(vaddr)
+    }
+
+    /// Marca al proceso como terminado. La memoria se libera cuando se lo
+    /// quita de la tabla de procesos.
+    pub fn exit(&mut self, status: isize) {
+        self.exit_status = status;
+        self.state = ProcessState::Dead;
+        self.files.close_all();
+    }
+
+    /// Crea un hijo con una copia del espacio de direcciones y de los
+    /// registros del proceso. El hijo continúa en `return_pc`, y a diferencia
+    /// del padre ve un 0 como resultado de la syscall.
+    ///
+    /// Las páginas se comparten hasta que alguno de los dos escribe en ellas
+    /// (ver `MapTable::clone_cow`).
+    pub fn fork(&mut self, return_pc: usize) -> Process<'a> {
+        let _tag = AllocTag::Process.enter();
+        let mut child = Process::create(self.parent_page_table);
+        child.frame = self.frame;
+        child.frame.satp = child.root.get_initial_satp(child.pid);
+        child.frame.regs[A0_REGISTER] = 0;
+        child.program_counter = return_pc;
+        child.parent = Some(self.pid);
+        // El hijo hereda los archivos abiertos, aunque por ahora cada uno
+        // avanza su propia posición de lectura
+        child.files = self.files.clone();
+        for socket in child.files.sockets() {
+            SocketTable::retain(socket);
+        }
+        child.vmas = self.vmas.clone();
+        self.root.clone_cow(child.root);
+        // Nuestras páginas ahora son de sólo lectura
+        unsafe { riscv64::satp_fence_asid(self.pid as usize) };
+        child
+    }
+
+    /// Reemplaza la imagen del proceso por el ejecutable en `path`, con los
+    /// argumentos `args` y el entorno `env`.
+    /// Conserva el PID y el padre. Devuelve el nuevo punto de entrada.
+    pub fn exec(&mut self, path: &str, args: &[&str], env: &[&str]) -> Result<usize, ExecError> {
+        let elf_data = VirtualFsManager::read_to_end(path)?;
+        let loader = ElfLoader::new(&elf_data)?;
+        let mut image = Process::with_pid(self.parent_page_table, self.pid);
+        loader.load(&mut image, args, env)?;
+        core::mem::swap(&mut self.frame, &mut image.frame);
+        core::mem::swap(&mut self.root, &mut image.root);
+        core::mem::swap(&mut self.vmas, &mut image.vmas);
+        self.frame.satp = self.root.get_initial_satp(self.pid);
+        self.program_counter = image.program_counter;
+        // La syscall vuelve directo al proceso sin pasar por el planificador,
+        // así que cargamos la tabla nueva antes de liberar la anterior
+        unsafe { self.activate() };
+        // `image` se queda con la imagen anterior, que se libera acá
+        Ok(self.program_counter)
+    }
+
+    /// Arma el stack inicial al estilo System V. En el tope van los strings
+    /// de los argumentos y del entorno, y los bytes de `AT_RANDOM`. Debajo,
+    /// alineados a 16 bytes, `argc`, los punteros de `argv` y de `envp`
+    /// terminados en null y el vector auxiliar terminado en `AT_NULL`.
+    /// Además dejamos `argc`, `argv` y `envp` en `a0`, `a1` y `a2` para los
+    /// programas que no usan un `crt0`.
+    /// Devuelve `false` si no entra en el stack.
+    pub fn init_stack(&mut self, args: &[&str], env: &[&str], auxv: &[(usize, usize)]) -> bool {
+        let stack_top = STACK_ADDR + PAGE_SIZE * STACK_PAGES;
+        let strings_size: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+        let Some(strings_addr) = stack_top.checked_sub(strings_size) else {
+            return false;
+        };
+        let random_addr = strings_addr.saturating_sub(AT_RANDOM_SIZE) & !(STACK_ALIGN - 1);
+        // argc, argv + null, envp + null, auxv + AT_RANDOM + AT_NULL
+        let words = 1 + args.len() + 1 + env.len() + 1 + 2 * (auxv.len() + 2);
+        let sp = random_addr.saturating_sub(words * 8) & !(STACK_ALIGN - 1);
+        if sp < STACK_ADDR {
+            return false;
+        }
+        let mut strings = Vec::with_capacity(strings_size);
+        let mut pointers = Vec::with_capacity(words);
+        pointers.push(args.len());
+        for (i, s) in args.iter().chain(env).enumerate() {
+            if i == args.len() {
+                pointers.push(0);
+            }
+            pointers.push(strings_addr + strings.len());
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+        }
+        if env.is_empty() {
+            pointers.push(0);
+        }
+        pointers.push(0);
+        for (key, value) in auxv {
+            pointers.extend_from_slice(&[*key, *value]);
+        }
+        pointers.extend_from_slice(&[AT_RANDOM, random_addr, AT_NULL, 0]);
+        let vector = pointers
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut random_bytes = [0; AT_RANDOM_SIZE];
+        EntropyPool::fill(&mut random_bytes);
+        let copied = self.copy_to_user(strings_addr, &strings)
+            && self.copy_to_user(random_addr, &random_bytes)
+            && self.copy_to_user(sp, &vector);
+        self.frame.regs[SP_REGISTER] = sp;
+        self.frame.regs[A0_REGISTER] = args.len();
+        self.frame.regs[A1_REGISTER] = sp + 8;
+        self.frame.regs[A2_REGISTER] = sp + 8 * (args.len() + 2);
+        copied
+    }
+
+    /// Copia `buf.len()` bytes desde la dirección virtual `vaddr` del proceso
+    pub fn copy_from_user(&mut self, vaddr: usize, buf: &mut [u8]) -> bool {
+        let mut copied = 0;
+        while copied < buf.len() {
+            let addr = vaddr + copied;
+            let Some(paddr) = self.user_to_phys(addr, Access::Load) else {
+                return false;
+            };
+            let chunk = core::cmp::min(PAGE_SIZE - addr % PAGE_SIZE, buf.len() - copied);
+            unsafe {
+                copy_nonoverlapping(paddr as *const u8, buf[copied..].as_mut_ptr(), chunk);
+            }
+            copied += chunk;
+        }
+        true
+    }
+
+    /// Copia `data` a la dirección virtual `vaddr` del proceso
+    pub fn copy_to_user(&mut self, vaddr: usize, data: &[u8]) -> bool {
+        self.write_user(vaddr, data, |process, addr| {
+            process.user_to_phys(addr, Access::Store)
+        })
+    }
+
+    /// Copia `data` a páginas ya mapeadas del proceso sin revisar los permisos
+    /// de las áreas. Sólo lo usa el cargador de ELF para escribir los
+    /// segmentos de sólo lectura en las páginas que acaba de reservar
+    pub fn load_to_user(&mut self, vaddr: usize, data: &[u8]) -> bool {
+        self.write_user(vaddr, data, |process, addr| process.root.virt_to_phys(addr))
+    }
+
+    /// Copia `data` a `vaddr` página por página, resolviendo cada dirección
+    /// física con `resolve`
+    fn write_user(
+        &mut self,
+        vaddr: usize,
+        data: &[u8],
+        mut resolve: impl FnMut(&mut Self, usize) -> Option<usize>,
+    ) -> bool {
+        let mut copied = 0;
+        while copied < data.len() {
+            let addr = vaddr + copied;
+            let Some(paddr) = resolve(self, addr) else {
+                return false;
+            };
+            let chunk = core::cmp::min(PAGE_SIZE - addr % PAGE_SIZE, data.len() - copied);
+            unsafe {
+                copy_nonoverlapping(data[copied..].as_ptr(), paddr as *mut u8, chunk);
+            }
+            copied += chunk;
+        }
+        true
+    }
+
+    /// Lee un string terminado en 0 desde la memoria del proceso
+    pub fn read_user_str(&mut self, vaddr: usize) -> Option<String> {
+        let mut bytes = Vec::new();
+        loop {
+            let paddr = self.user_to_phys(vaddr + bytes.len(), Access::Load)?;
+            let c = unsafe { *(paddr as *const u8) };
+            if c == 0 {
+                break;
+            }
+            bytes.push(c);
+        }
+        String::from_utf8(bytes).ok()
+    }
+
+    /// Lee un arreglo de punteros a strings terminado en null, como `argv` o
+    /// `envp`. Un puntero null equivale a un arreglo vacío.
+    pub fn read_user_str_array(&mut self, vaddr: usize) -> Option<Vec<String>> {
+        let mut strings = Vec::new();
+        if vaddr == 0 {
+            return Some(strings);
+        }
+        loop {
+            let mut pointer = [0; size_of::<usize>()];
+            if !self.copy_from_user(vaddr + strings.len() * pointer.len(), &mut pointer) {
+                return None;
+            }
+            match usize::from_le_bytes(pointer) {
+                0 => return Some(strings),
+                str_vaddr => strings.push(self.read_user_str(str_vaddr)?),
+            }
+        }
+    }
+
+    /// Duerme al proceso durante `msecs` milisegundos
+    pub fn sleep(&mut self, msecs: u64) {
+        self.sleep_until = read_mtime().wrapping_add(msecs * MSECS_CYCLES);
+        self.state = ProcessState::Sleeping;
+    }
+
+    /// Indica si el planificador puede darle la CPU al proceso. Los procesos
+    /// dormidos cuyo plazo ya venció vuelven a estar listos.
+    pub fn is_ready(&mut self, now: u64) -> bool {
+        match self.state {
+            ProcessState::Running => true,
+            ProcessState::Sleeping if now >= self.sleep_until => {
+                self.state = ProcessState::Running;
+                true
+            }
+            ProcessState::Sleeping | ProcessState::Waiting | ProcessState::Dead => false,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, ProcessState::Running)
+    }
+
+    pub fn is_dead(&self) -> bool {
+        matches!(self.state, ProcessState::Dead)
+    }
+
+    /// Prepara la CPU para continuar la ejecución de este proceso: el trap
+    /// frame pasa a ser el apuntado por `mscratch` y cargamos su tabla de
+    /// páginas.
+    ///
+    /// # Safety
+    /// Sólo puede llamarse en modo máquina, antes de volver de un trap
+    pub unsafe fn activate(&self) {
+        riscv64::mscratch_write(&self.frame as *const _ as usize);
+        riscv64::satp_write(self.frame.satp);
+        riscv64::satp_fence_asid(self.pid as usize);
+    }
 }
 
 impl Drop for Process<'_> {
     fn drop(&mut self) {
-        self.parent_page_table.dealloc(self.stack);
+        // Cada hoja de usuario es una referencia a su página física
+        let mut frames = Vec::new();
+        self.root.for_each_leaf(|_, entry, _| {
+            if entry.get_entry() & EntryBits::User.val() != 0 {
+                frames.push(entry.get_address());
+            }
+        });
+        for frame in frames {
+            let frame = unsafe { NonNull::new_unchecked(frame as *mut u8) };
+            self.parent_page_table.release(frame);
+        }
         // el unmap libera a todos los hijos
         self.root.unmap();
         // libera a la raiz
@@ -118,38 +558,20 @@ impl Drop for Process<'_> {
     }
 }
 
-pub struct InitProcess {
-    init: UnsafeCell<Option<Process<'static>>>,
-}
-
-unsafe impl Sync for InitProcess {}
-
-impl InitProcess {
-    pub fn set_process(process: Process<'static>) {
-        let opt = INIT_PROCESS.init.get();
-        unsafe { *opt = Some(process) };
-    }
-
-    pub fn get_process() -> &'static Process<'static> {
-        let opt = INIT_PROCESS.init.get();
-        unsafe { (*opt).as_ref().unwrap() }
-    }
-}
-
-/// Rust me exige que inicialice todas las variables estáticas
-/// Usando un `Option` hago una suerte de lazy init
-pub static INIT_PROCESS: InitProcess = InitProcess {
-    init: UnsafeCell::new(None),
-};
-
-/// Crea el proceso `init`, el proceso que será el padre de todos
-/// Llama a `launch_init_process` que a su vez llama a `launch_user_process`
-pub fn init(page_table: &'static PageTable) {
-    let init_process = if let Ok(loader) = ElfLoader::new(EXTERNAL_ELF_START as *const u8) {
-        loader.into_process(page_table).unwrap()
-    } else {
-        panic!("ELF not found")
+/// Crea el proceso `init`, el proceso que será el padre de todos, a partir
+/// del ejecutable `path` del sistema de archivos raíz, y le cede la CPU al
+/// planificador
+pub fn init(page_table: &'static PageTable, path: &str) -> ! {
+    println!("Loading init from {}", path);
+    let elf_data = match VirtualFsManager::read_to_end(path) {
+        Ok(data) => data,
+        Err(e) => panic!("Could not read init {}: {:?}", path, e),
     };
+    let init_process =
+        match ElfLoader::new(&elf_data).and_then(|l| l.into_process(page_table, &[path], &[])) {
+            Ok(process) => process,
+            Err(e) => panic!("Init ELF {} rejected: {:?}", path, e),
+        };
     println!(
         "phys address: {:x}",
         init_process
@@ -157,17 +579,6 @@ pub fn init(page_table: &'static PageTable) {
             .virt_to_phys(init_process.program_counter)
             .unwrap()
     );
-    InitProcess::set_process(init_process);
-    launch_init_process();
-}
-
-fn launch_init_process() {
-    let init_process = InitProcess::get_process();
-    let new_pc = init_process.program_counter;
-    let new_sp = init_process.frame.regs[SP_REGISTER];
-    unsafe {
-        riscv64::mscratch_write(&init_process.frame as *const _ as usize);
-        riscv64::satp_fence_asid(init_process.pid as usize);
-        user_mode_init(new_pc, new_sp)
-    };
+    Scheduler::push(init_process);
+    Scheduler::start()
 }
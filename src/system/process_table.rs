@@ -0,0 +1,166 @@
+//! # Tabla de procesos
+//! Todos los procesos del sistema viven en esta tabla, indexados por PID.
+//! Además recordamos qué proceso está ejecutando cada núcleo, para que las
+//! syscalls, los page faults y el planificador encuentren al proceso que
+//! generó el trap.
+use crate::assembly::riscv64;
+use crate::cpu::riscv64::trap::MAX_HARTS;
+use crate::system::process::{Pid, Process, ProcessState};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+
+/// Resultado de esperar a un hijo
+pub enum WaitStatus {
+    /// Se liberó un hijo terminado, con su PID y código de salida
+    Reaped(Pid, isize),
+    /// Hay hijos vivos, el proceso queda bloqueado hasta que alguno termine
+    Blocked,
+    /// El proceso no tiene hijos
+    NoChildren,
+}
+
+struct ProcessList {
+    /// Los procesos están en el heap: sus trap frames no pueden moverse
+    /// mientras `mscratch` apunte a ellos
+    processes: BTreeMap<Pid, Box<Process<'static>>>,
+    current: [Option<Pid>; MAX_HARTS],
+}
+
+pub struct ProcessTable {
+    list: UnsafeCell<ProcessList>,
+}
+
+unsafe impl Sync for ProcessTable {}
+
+static PROCESS_TABLE: ProcessTable = ProcessTable::empty();
+
+impl ProcessTable {
+    const fn empty() -> Self {
+        let list = ProcessList {
+            processes: BTreeMap::new(),
+            current: [None; MAX_HARTS],
+        };
+        let list = UnsafeCell::new(list);
+        Self { list }
+    }
+
+    /// La tabla sólo se modifica dentro de los traps, que se atienden con las
+    /// interrupciones deshabilitadas
+    fn list() -> &'static mut ProcessList {
+        unsafe { &mut *PROCESS_TABLE.list.get() }
+    }
+
+    /// Agrega un proceso a la tabla y devuelve su PID
+    pub fn insert(process: Process<'static>) -> Pid {
+        let pid = process.get_pid();
+        ProcessTable::list()
+            .processes
+            .insert(pid, Box::new(process));
+        pid
+    }
+
+    pub fn get(pid: Pid) -> Option<&'static mut Process<'static>> {
+        ProcessTable::list()
+            .processes
+            .get_mut(&pid)
+            .map(|process| &mut **process)
+    }
+
+    /// Saca al proceso de la tabla. Al soltar el `Box` se libera su memoria
+    pub fn remove(pid: Pid) -> Option<Box<Process<'static>>> {
+        let list = ProcessTable::list();
+        for current in list.current.iter_mut() {
+            if *current == Some(pid) {
+                *current = None;
+            }
+        }
+        list.processes.remove(&pid)
+    }
+
+    /// PID del proceso que está ejecutando el núcleo `hart`
+    pub fn get_current_pid(hart: usize) -> Option<Pid> {
+        ProcessTable::list().current[hart]
+    }
+
+    pub fn set_current(hart: usize, pid: Option<Pid>) {
+        ProcessTable::list().current[hart] = pid;
+    }
+
+    /// Proceso que está ejecutando el núcleo actual
+    pub fn current() -> Option<&'static mut Process<'static>> {
+        let hart = unsafe { riscv64::mhartid_read() };
+        ProcessTable::get_current_pid(hart).and_then(ProcessTable::get)
+    }
+
+    /// Despierta al proceso si estaba esperando un evento
+    pub fn wake(pid: Pid) {
+        if let Some(process) = ProcessTable::get(pid) {
+            if let ProcessState::Waiting = process.state {
+                process.state = ProcessState::Running;
+            }
+        }
+    }
+
+    /// PIDs de los hijos de `parent`
+    fn children(parent: Pid) -> Vec<Pid> {
+        ProcessTable::list()
+            .processes
+            .values()
+            .filter(|process| process.get_parent() == Some(parent))
+            .map(|process| process.get_pid())
+            .collect()
+    }
+
+    /// Termina al proceso `pid`. Si su padre sigue vivo queda como *zombie*
+    /// (y despertamos al padre por si lo está esperando), sino se libera.
+    /// Los hijos zombies se liberan y los hijos vivos quedan huérfanos.
+    pub fn exit(pid: Pid, status: isize) {
+        for child_pid in ProcessTable::children(pid) {
+            if let Some(child) = ProcessTable::get(child_pid) {
+                if child.is_dead() {
+                    ProcessTable::remove(child_pid);
+                } else {
+                    child.set_parent(None);
+                }
+            }
+        }
+        let Some(process) = ProcessTable::get(pid) else {
+            return;
+        };
+        process.exit(status);
+        match process.get_parent().and_then(ProcessTable::get) {
+            Some(parent) => {
+                if let ProcessState::Waiting = parent.state {
+                    parent.state = ProcessState::Running;
+                }
+            }
+            None => {
+                ProcessTable::remove(pid);
+            }
+        }
+    }
+
+    /// Busca un hijo terminado de `parent` y lo libera. Si todos sus hijos
+    /// siguen vivos, `parent` queda esperando.
+    pub fn wait_child(parent: Pid) -> WaitStatus {
+        let children = ProcessTable::children(parent);
+        if children.is_empty() {
+            return WaitStatus::NoChildren;
+        }
+        for child_pid in children {
+            if let Some(child) = ProcessTable::get(child_pid) {
+                if child.is_dead() {
+                    let status = child.get_exit_status();
+                    ProcessTable::remove(child_pid);
+                    return WaitStatus::Reaped(child_pid, status);
+                }
+            }
+        }
+        if let Some(process) = ProcessTable::get(parent) {
+            process.state = ProcessState::Waiting;
+        }
+        WaitStatus::Blocked
+    }
+}
@@ -1,21 +1,70 @@
+use crate::mmu::alloc_stats::AllocTag;
 use crate::mmu::map_table::EntryBits;
-use crate::mmu::riscv64::{PageTable, PAGE_ORDER, PAGE_SIZE};
-use crate::system::process::Process;
+use crate::mmu::riscv64::{PageTable, PAGE_SIZE};
+use crate::mmu::vma::{Vma, VmaKind};
+use crate::system::process::{Process, USER_HEAP_SIZE};
 use crate::utils::NullTerminatedStr;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::mem::size_of;
-use core::ptr::copy_nonoverlapping;
 
 const MAGIC_SIZE: usize = 4;
 const ENTRY_ADDR_OFFSET: u64 = 24;
-const SHT_PROGBITS: u32 = 0x1;
 const SHT_STRTAB: u32 = 0x3;
-const SHF_WRITE: u64 = 0x1;
-const SHF_ALLOC: u64 = 0x2;
-const SHF_EXECINSTR: u64 = 0x4;
+/// Posiciones de la clase y el *endianness* dentro de `e_ident`
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+/// Ejecutable con direcciones fijas (no cargamos bibliotecas ni PIE)
+const ET_EXEC: u16 = 2;
+/// Segmento que se carga en memoria
+const PT_LOAD: u32 = 1;
+/// Segmento con la tabla de program headers
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+/// Entradas del vector auxiliar que arma el cargador (ver `elf.h`)
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
 
+/// Motivos por los que rechazamos un binario
+#[derive(Debug, PartialEq, Eq)]
 pub enum ElfLoaderError {
     InvalidMagic,
+    /// Sólo cargamos ejecutables de 64 bits
+    InvalidClass(u8),
+    /// Sólo cargamos ejecutables *little endian*
+    InvalidEndianness(u8),
+    /// El ejecutable no es para RISC-V
+    InvalidMachine(u16),
+    /// El tipo de archivo (`e_type`) no es `ET_EXEC`
+    NotExecutable(u16),
+    /// El tamaño de las entradas de la tabla de program headers no es el de ELF64
+    InvalidProgramHeaderSize(u16),
+    /// Alguna tabla o segmento se extiende más allá del final del archivo
+    Truncated,
+    /// Un segmento ocupa más bytes en el archivo que en memoria
+    InvalidSegmentSize,
+    /// Un segmento `PT_LOAD` no tiene permisos: RISC-V tomaría sus páginas
+    /// como un nivel más de la tabla de páginas
+    SegmentWithoutPermissions,
+    /// Dos segmentos `PT_LOAD` se pisan en memoria virtual
+    OverlappingSegments,
+    /// Un segmento `PT_LOAD` se pisa con el stack del proceso
+    OverlappingStack,
+    /// No hay segmentos `PT_LOAD` para cargar
+    NoLoadableSegments,
+    OutOfMemory,
+    /// No se pudo copiar un segmento a la memoria del proceso
+    SegmentCopyFailed,
+    /// Los argumentos y el entorno no entran en el stack del proceso
+    ArgumentsTooLong,
 }
 
 #[repr(C)]
@@ -25,7 +74,7 @@ struct Elf64Header {
     filetype: u16,
     machine: u16,
     version: u32,
-    entry: u32,
+    entry: u64,
     phoff: u64,
     shoff: u64,
     flags: u32,
@@ -52,6 +101,26 @@ pub struct Elf64SectionHeader {
     entsize: u64,
 }
 
+#[repr(C)]
+#[derive(Clone, Debug, Default)]
+pub struct Elf64ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+pub struct ProgramHeaderIterator {
+    base_addr: *const u8,
+    table_offset: u64,
+    current_segment: u16,
+    n_segments: u16,
+}
+
 pub struct SectionIterator {
     base_addr: *const u8,
     table_offset: u64,
@@ -60,112 +129,286 @@ pub struct SectionIterator {
 }
 
 #[derive(Debug)]
-pub struct ElfLoader {
+pub struct ElfLoader<'a> {
+    data: &'a [u8],
     base_addr: *const u8,
     header: Elf64Header,
 }
 
-impl ElfLoader {
-    pub fn new(base_addr: *const u8) -> Result<Self, ElfLoaderError> {
-        let src_header = unsafe { &*(base_addr as *const Elf64Header) };
-        if src_header.ident[0..MAGIC_SIZE] == [0x7f, b'E', b'L', b'F'] {
-            let header = src_header.clone();
-            Ok(Self { base_addr, header })
-        } else {
-            Err(ElfLoaderError::InvalidMagic)
+impl<'a> ElfLoader<'a> {
+    /// Valida el encabezado, la tabla de program headers y los segmentos del
+    /// ejecutable contenido en `data`
+    pub fn new(data: &'a [u8]) -> Result<Self, ElfLoaderError> {
+        if data.len() < size_of::<Elf64Header>() {
+            return Err(ElfLoaderError::Truncated);
+        }
+        let base_addr = data.as_ptr();
+        // El buffer no tiene por qué estar alineado, así que copiamos el
+        // encabezado en lugar de referenciarlo
+        let header = unsafe { (base_addr as *const Elf64Header).read_unaligned() };
+        let ident = &header.ident;
+        if ident[0..MAGIC_SIZE] != [0x7f, b'E', b'L', b'F'] {
+            return Err(ElfLoaderError::InvalidMagic);
+        }
+        if ident[EI_CLASS] != ELFCLASS64 {
+            return Err(ElfLoaderError::InvalidClass(ident[EI_CLASS]));
+        }
+        if ident[EI_DATA] != ELFDATA2LSB {
+            return Err(ElfLoaderError::InvalidEndianness(ident[EI_DATA]));
         }
+        if header.machine != EM_RISCV {
+            return Err(ElfLoaderError::InvalidMachine(header.machine));
+        }
+        if header.filetype != ET_EXEC {
+            return Err(ElfLoaderError::NotExecutable(header.filetype));
+        }
+        let loader = Self {
+            data,
+            base_addr,
+            header,
+        };
+        loader.validate_tables()?;
+        loader.validate_segments()?;
+        Ok(loader)
+    }
+
+    /// Verifica que las tablas de program headers y de secciones entren en el
+    /// archivo
+    fn validate_tables(&self) -> Result<(), ElfLoaderError> {
+        let header = &self.header;
+        if header.phnum > 0 && header.phentsize as usize != size_of::<Elf64ProgramHeader>() {
+            return Err(ElfLoaderError::InvalidProgramHeaderSize(header.phentsize));
+        }
+        let program_headers_size = header.phnum as u64 * size_of::<Elf64ProgramHeader>() as u64;
+        let sections_size = header.shnum as u64 * size_of::<Elf64SectionHeader>() as u64;
+        self.check_range(header.phoff, program_headers_size)?;
+        self.check_range(header.shoff, sections_size)
+    }
+
+    /// Verifica que cada segmento `PT_LOAD` esté completo en el archivo, que
+    /// tenga algún permiso y que ningún par de segmentos se superponga en
+    /// memoria
+    fn validate_segments(&self) -> Result<(), ElfLoaderError> {
+        let mut segments = self.loadable_segments();
+        for segment in &segments {
+            if segment.filesz > segment.memsz {
+                return Err(ElfLoaderError::InvalidSegmentSize);
+            }
+            if segment.flags & (PF_R | PF_W | PF_X) == 0 {
+                return Err(ElfLoaderError::SegmentWithoutPermissions);
+            }
+            self.check_range(segment.offset, segment.filesz)?;
+            segment
+                .vaddr
+                .checked_add(segment.memsz)
+                .ok_or(ElfLoaderError::InvalidSegmentSize)?;
+        }
+        segments.sort_by_key(|segment| segment.vaddr);
+        for pair in segments.windows(2) {
+            if pair[0].vaddr + pair[0].memsz > pair[1].vaddr {
+                return Err(ElfLoaderError::OverlappingSegments);
+            }
+        }
+        Ok(())
+    }
+
+    /// Falla si `size` bytes a partir de `offset` no entran en el archivo
+    fn check_range(&self, offset: u64, size: u64) -> Result<(), ElfLoaderError> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.data.len() as u64 => Ok(()),
+            _ => Err(ElfLoaderError::Truncated),
+        }
+    }
+
+    fn loadable_segments(&self) -> Vec<Elf64ProgramHeader> {
+        self.get_program_header_iterator()
+            .filter(|segment| segment.segment_type == PT_LOAD && segment.memsz > 0)
+            .collect()
     }
 
     pub fn get_section_iterator(&self) -> SectionIterator {
         SectionIterator::new(self.base_addr)
     }
 
-    pub fn into_process(self, parent_page_table: &PageTable) -> Option<Process<'_>> {
+    pub fn get_program_header_iterator(&self) -> ProgramHeaderIterator {
+        ProgramHeaderIterator::new(self.base_addr)
+    }
+
+    /// Crea un proceso nuevo con la imagen del ejecutable (ver `load`)
+    pub fn into_process<'p>(
+        self,
+        parent_page_table: &'p PageTable,
+        args: &[&str],
+        env: &[&str],
+    ) -> Result<Process<'p>, ElfLoaderError> {
         let mut process = Process::create(parent_page_table);
-        let mut memory_sections = self
-            .get_section_iterator()
-            .filter(|sec| (sec.flags & SHF_ALLOC) != 0)
-            .collect::<Vec<_>>();
-        if memory_sections.is_empty() {
-            panic!("Empty ELF");
-        }
-        memory_sections.sort_by(|l, r| l.addr.cmp(&r.addr));
-        let pages = self.needed_pages(&memory_sections);
-        let pages = parent_page_table.zalloc(pages)?;
-        let mut current_page: isize = -1;
-        let mut last_end_page = memory_sections[0].addr;
-        for section in memory_sections {
-            let section_start_page = section.addr >> PAGE_ORDER;
-            let section_end_page = (section.addr + section.size) >> PAGE_ORDER;
-            let clean_page = section_start_page != last_end_page;
-            let section_pages = section_end_page - section_start_page + 1;
-            if clean_page {
-                current_page += 1;
+        self.load(&mut process, args, env)?;
+        Ok(process)
+    }
+
+    /// Carga los segmentos `PT_LOAD` del ejecutable en `process`, que todavía
+    /// no tiene nada mapeado fuera del stack.
+    /// Primero calculamos los permisos de cada página virtual (dos segmentos
+    /// pueden compartir una página), reservamos todas las páginas juntas y
+    /// finalmente copiamos el contenido de cada segmento. Las páginas se
+    /// reservan en cero, así que el BSS (`memsz` > `filesz`) queda limpio.
+    /// El stack inicial recibe los argumentos `args`, el entorno `env` y el
+    /// vector auxiliar.
+    pub fn load(
+        self,
+        process: &mut Process,
+        args: &[&str],
+        env: &[&str],
+    ) -> Result<(), ElfLoaderError> {
+        let _tag = AllocTag::Process.enter();
+        let segments = self.loadable_segments();
+        if segments.is_empty() {
+            return Err(ElfLoaderError::NoLoadableSegments);
+        }
+        let mut page_bits = BTreeMap::new();
+        for segment in &segments {
+            let first_page = segment.vaddr as usize & !(PAGE_SIZE - 1);
+            let end = (segment.vaddr + segment.memsz) as usize;
+            for vaddr in (first_page..end).step_by(PAGE_SIZE) {
+                *page_bits.entry(vaddr).or_insert(EntryBits::User.val()) |= segment.entry_bits();
             }
-            let dest_offset =
-                (current_page << PAGE_ORDER) as usize + (section.addr as usize % PAGE_SIZE);
-            if section.section_type == SHT_PROGBITS {
-                unsafe {
-                    let src = self.base_addr.add(section.offset as usize);
-                    let dst = pages.as_ptr().add(dest_offset);
-                    copy_nonoverlapping(src, dst, section.size as usize);
-                }
+        }
+        let mut image_end = 0;
+        // Cada página se reserva por separado, ya que `fork` las comparte de
+        // a una
+        for (vaddr, bits) in page_bits {
+            let page = process
+                .page_table()
+                .zalloc(1)
+                .ok_or(ElfLoaderError::OutOfMemory)?;
+            process.map_memory(vaddr, page.as_ptr() as usize, bits, 0);
+            if !process.add_vma(Vma::new(vaddr, vaddr + PAGE_SIZE, bits, VmaKind::Elf)) {
+                return Err(ElfLoaderError::OverlappingStack);
             }
-            last_end_page = section_end_page;
-            let map_start = if clean_page { 0 } else { 1 };
-            for i in map_start..section_pages {
-                let virt_page_offset = PAGE_SIZE * i as usize;
-                let vaddr = section.addr as usize + virt_page_offset;
-                let phys_page_offset = PAGE_SIZE * (current_page as usize + i as usize);
-                let paddr = unsafe { pages.as_ptr().add(phys_page_offset) };
-                let bits = if (section.flags & SHF_EXECINSTR) != 0 {
-                    EntryBits::UserReadExecute
-                } else if (section.flags & SHF_WRITE) != 0 {
-                    EntryBits::UserReadWrite
-                } else {
-                    EntryBits::UserReadExecute
-                };
-                process.map_memory(vaddr, paddr as usize, bits.val(), 0);
+            image_end = vaddr + PAGE_SIZE;
+        }
+        // El heap arranca en la página siguiente al ejecutable, y sus páginas
+        // se reservan recién cuando el proceso las usa
+        let heap_end = process
+            .vmas()
+            .iter()
+            .map(|vma| vma.start)
+            .filter(|start| *start >= image_end)
+            .fold(image_end + USER_HEAP_SIZE, usize::min);
+        if heap_end > image_end {
+            let heap_bits = EntryBits::UserReadWrite.val();
+            process.add_vma(Vma::new(image_end, heap_end, heap_bits, VmaKind::Heap));
+        }
+        for segment in &segments {
+            let start = segment.offset as usize;
+            let data = &self.data[start..start + segment.filesz as usize];
+            if !process.load_to_user(segment.vaddr as usize, data) {
+                return Err(ElfLoaderError::SegmentCopyFailed);
             }
-            current_page += section_pages as isize - 1;
         }
         process.program_counter = self.header.entry as usize;
-        Some(process)
-    }
-
-    fn needed_pages(&self, sections: &Vec<Elf64SectionHeader>) -> usize {
-        let mut counter = 0;
-        let mut last_end_page = u64::MAX;
-        for section in sections {
-            let section_start_page = section.addr >> PAGE_ORDER;
-            let section_end_page = (section.addr + section.size) >> PAGE_ORDER;
-            let clean = section_start_page == last_end_page;
-            if !clean {
-                counter += 1;
-            }
-            counter += section_end_page - section_start_page;
-            last_end_page = section_end_page;
+        if !process.init_stack(args, env, &self.auxiliary_vector(&segments)) {
+            return Err(ElfLoaderError::ArgumentsTooLong);
+        }
+        Ok(())
+    }
+
+    /// Entradas del vector auxiliar que describen al ejecutable
+    fn auxiliary_vector(&self, segments: &[Elf64ProgramHeader]) -> Vec<(usize, usize)> {
+        let mut auxv = Vec::new();
+        if let Some(phdr) = self.program_headers_vaddr(segments) {
+            auxv.push((AT_PHDR, phdr as usize));
+        }
+        auxv.push((AT_PHENT, size_of::<Elf64ProgramHeader>()));
+        auxv.push((AT_PHNUM, self.header.phnum as usize));
+        auxv.push((AT_PAGESZ, PAGE_SIZE));
+        auxv.push((AT_ENTRY, self.header.entry as usize));
+        auxv
+    }
+
+    /// Dirección virtual de la tabla de program headers: la indica `PT_PHDR`
+    /// o, si no está, la buscamos dentro de los segmentos cargados
+    fn program_headers_vaddr(&self, segments: &[Elf64ProgramHeader]) -> Option<u64> {
+        let phoff = self.header.phoff;
+        if let Some(phdr) = self
+            .get_program_header_iterator()
+            .find(|segment| segment.segment_type == PT_PHDR)
+        {
+            return Some(phdr.vaddr);
+        }
+        segments
+            .iter()
+            .find(|segment| (segment.offset..segment.offset + segment.filesz).contains(&phoff))
+            .map(|segment| segment.vaddr + (phoff - segment.offset))
+    }
+}
+
+impl Elf64ProgramHeader {
+    /// Permisos de las páginas del segmento según sus flags `PF_R/PF_W/PF_X`.
+    /// RISC-V no admite páginas escribibles que no se puedan leer, así que
+    /// `PF_W` también da lectura
+    fn entry_bits(&self) -> i64 {
+        let mut bits = EntryBits::User.val();
+        if self.flags & (PF_R | PF_W) != 0 {
+            bits |= EntryBits::Read.val();
+        }
+        if self.flags & PF_W != 0 {
+            bits |= EntryBits::Write.val();
         }
-        counter as usize
+        if self.flags & PF_X != 0 {
+            bits |= EntryBits::Execute.val();
+        }
+        bits
     }
 }
 
 impl Elf64SectionHeader {
-    pub fn is_section_name(&self, loader: &ElfLoader, name: &str) -> bool {
+    pub fn is_section_name(&self, loader: &ElfLoader<'_>, name: &str) -> bool {
         let str_table_section_offset = loader.base_addr as u64
             + loader.header.shoff
             + loader.header.shstrndx as u64 * size_of::<Elf64SectionHeader>() as u64;
         let str_table_section =
-            unsafe { &*(str_table_section_offset as *const Elf64SectionHeader) };
+            unsafe { (str_table_section_offset as *const Elf64SectionHeader).read_unaligned() };
         let name_offset = loader.base_addr as u64 + str_table_section.offset + self.name as u64;
         let name_str = unsafe { NullTerminatedStr::as_str(name_offset as *const u8) };
         name_str.eq(name)
     }
 }
 
+impl ProgramHeaderIterator {
+    fn new(base_addr: *const u8) -> Self {
+        let header = unsafe { (base_addr as *const Elf64Header).read_unaligned() };
+        let table_offset = header.phoff;
+        let n_segments = header.phnum;
+        let current_segment = 0;
+        Self {
+            base_addr,
+            table_offset,
+            n_segments,
+            current_segment,
+        }
+    }
+}
+
+impl Iterator for ProgramHeaderIterator {
+    type Item = Elf64ProgramHeader;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_segment < self.n_segments {
+            let segment_offset = self.base_addr as u64
+                + self.table_offset
+                + self.current_segment as u64 * size_of::<Elf64ProgramHeader>() as u64;
+            let segment = unsafe { (segment_offset as *const Elf64ProgramHeader).read_unaligned() };
+            self.current_segment += 1;
+            Some(segment)
+        } else {
+            None
+        }
+    }
+}
+
 impl SectionIterator {
     fn new(base_addr: *const u8) -> Self {
-        let header = unsafe { &*(base_addr as *const Elf64Header) };
+        let header = unsafe { (base_addr as *const Elf64Header).read_unaligned() };
         let table_offset = header.shoff;
         let n_sections = header.shnum;
         let current_section = 0;
@@ -185,9 +428,9 @@ impl Iterator for SectionIterator {
             let section_offset = self.base_addr as u64
                 + self.table_offset
                 + self.current_section as u64 * size_of::<Elf64SectionHeader>() as u64;
-            let src_section = unsafe { &*(section_offset as *const Elf64SectionHeader) };
+            let section = unsafe { (section_offset as *const Elf64SectionHeader).read_unaligned() };
             self.current_section += 1;
-            Some(src_section.clone())
+            Some(section)
         } else {
             None
         }
@@ -0,0 +1,165 @@
+//! # Números aleatorios
+//! Generador criptográfico basado en ChaCha20, sembrado con la entropía del
+//! dispositivo virtio-rng. Después de cada pedido la clave se reemplaza por
+//! bytes del propio generador, así que conocer el estado actual no permite
+//! reconstruir lo que se generó antes.
+use crate::cpu::riscv64::trap::read_mtime;
+use crate::devices::virtio::common::{DeviceManager, DeviceType};
+use crate::devices::virtio::rng_device::RngDevice;
+use crate::{print, println};
+use core::cell::{RefCell, UnsafeCell};
+
+pub const KEY_WORDS: usize = 8;
+pub const BLOCK_WORDS: usize = 16;
+const BLOCK_SIZE: usize = BLOCK_WORDS * 4;
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+/// Bytes generados entre cada pedido de entropía nueva al dispositivo
+const RESEED_INTERVAL: usize = 0x10000;
+
+struct PoolState {
+    key: [u32; KEY_WORDS],
+    counter: u64,
+    /// Bytes generados desde el último pedido al dispositivo
+    generated: usize,
+}
+
+pub struct EntropyPool {
+    state: UnsafeCell<PoolState>,
+}
+
+unsafe impl Sync for EntropyPool {}
+
+static ENTROPY_POOL: EntropyPool = EntropyPool::empty();
+
+impl EntropyPool {
+    const fn empty() -> Self {
+        let state = PoolState {
+            key: [0; KEY_WORDS],
+            counter: 0,
+            generated: 0,
+        };
+        let state = UnsafeCell::new(state);
+        Self { state }
+    }
+
+    /// El estado sólo se modifica al arrancar o dentro de los traps, que se
+    /// atienden con las interrupciones deshabilitadas
+    fn state() -> &'static mut PoolState {
+        unsafe { &mut *ENTROPY_POOL.state.get() }
+    }
+
+    /// Siembra el generador con el dispositivo de entropía. Sin dispositivo
+    /// usamos el `mtime`, que no sirve para nada que requiera seguridad
+    pub fn init() {
+        match EntropyPool::device() {
+            Some(device) => device.borrow_mut().request_sync(),
+            None => {
+                println!("No entropy source found, random numbers are predictable");
+                EntropyPool::add_entropy(&read_mtime().to_le_bytes());
+            }
+        }
+    }
+
+    /// Mezcla `data` en la clave del generador
+    pub fn add_entropy(data: &[u8]) {
+        let state = EntropyPool::state();
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            state.key[i % KEY_WORDS] ^= u32::from_le_bytes(word);
+        }
+        state.rekey();
+    }
+
+    /// Llena `buf` con bytes aleatorios
+    pub fn fill(buf: &mut [u8]) {
+        let state = EntropyPool::state();
+        for chunk in buf.chunks_mut(BLOCK_SIZE) {
+            let block = state.next_block();
+            for (dst, src) in chunk
+                .iter_mut()
+                .zip(block.iter().flat_map(|w| w.to_le_bytes()))
+            {
+                *dst = src;
+            }
+        }
+        state.rekey();
+        state.generated += buf.len();
+        if state.generated >= RESEED_INTERVAL {
+            state.generated = 0;
+            // La entropía nueva llega con la interrupción del dispositivo
+            if let Some(device) = EntropyPool::device() {
+                if let Ok(mut device) = device.try_borrow_mut() {
+                    device.request();
+                }
+            }
+        }
+    }
+
+    pub fn random_u32() -> u32 {
+        let mut bytes = [0; 4];
+        EntropyPool::fill(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn device() -> Option<&'static RefCell<RngDevice>> {
+        let device_id = DeviceManager::find(DeviceType::Rng, 0)?;
+        DeviceManager::get_rng_device(device_id)
+    }
+}
+
+impl PoolState {
+    fn next_block(&mut self) -> [u32; BLOCK_WORDS] {
+        let block = chacha20_block(&self.key, self.counter, [0, 0]);
+        self.counter = self.counter.wrapping_add(1);
+        block
+    }
+
+    /// Reemplaza la clave por la salida del generador
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        self.key.copy_from_slice(&block[..KEY_WORDS]);
+    }
+}
+
+fn quarter_round(state: &mut [u32; BLOCK_WORDS], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Bloque de ChaCha20 (RFC 8439) con un contador y un nonce de 64 bits. El
+/// generador usa el nonce nulo
+pub(crate) fn chacha20_block(
+    key: &[u32; KEY_WORDS],
+    counter: u64,
+    nonce: [u32; 2],
+) -> [u32; BLOCK_WORDS] {
+    let mut input = [0; BLOCK_WORDS];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14..].copy_from_slice(&nonce);
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
@@ -0,0 +1,147 @@
+//! # Planificador
+//! Planificador *round robin* apropiativo. Cada vez que interrumpe el timer
+//! de máquina guardamos el contexto del proceso actual (el `m_trap_vector` ya
+//! dejó sus registros en el trap frame apuntado por `mscratch`) y le cedemos
+//! la CPU al siguiente proceso listo de la cola, cambiando `mscratch` y `satp`.
+use crate::assembly::riscv64;
+use crate::cpu::riscv64::trap::{
+    read_mtime, schedule_mtime_interrupt, KERNEL_TRAP_FRAME, TIMER_OFFSET_VALUE,
+};
+use crate::init::user_mode_init;
+use crate::system::process::{Pid, Process};
+use crate::system::process_table::ProcessTable;
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+
+/// El registro *sp* es el *x2*
+const SP_REGISTER: usize = 2;
+/// Bits `MPP` de `mstatus`: modo al que vuelve `mret`
+const MSTATUS_MPP_MASK: usize = 0b11 << 11;
+/// Bit `MPIE` de `mstatus`: habilita interrupciones luego de `mret`
+const MSTATUS_MPIE: usize = 1 << 7;
+
+/// Cola de procesos planificables, en orden *round robin*.
+/// Los procesos que están usando la CPU no están en la cola: vuelven al final
+/// de la misma cuando se les termina el quantum.
+struct RunQueue {
+    pids: VecDeque<Pid>,
+}
+
+pub struct Scheduler {
+    run_queue: UnsafeCell<RunQueue>,
+}
+
+unsafe impl Sync for Scheduler {}
+
+static SCHEDULER: Scheduler = Scheduler::empty();
+
+impl Scheduler {
+    const fn empty() -> Self {
+        let run_queue = RunQueue {
+            pids: VecDeque::new(),
+        };
+        let run_queue = UnsafeCell::new(run_queue);
+        Self { run_queue }
+    }
+
+    /// La cola sólo se modifica dentro de los traps, que se atienden con las
+    /// interrupciones deshabilitadas
+    fn run_queue() -> &'static mut RunQueue {
+        unsafe { &mut *SCHEDULER.run_queue.get() }
+    }
+
+    /// Registra el proceso en la tabla de procesos y lo agrega al final de la cola
+    pub fn push(process: Process<'static>) -> Pid {
+        let pid = ProcessTable::insert(process);
+        Scheduler::run_queue().pids.push_back(pid);
+        pid
+    }
+
+    /// Guarda el *program counter* del proceso que está ejecutando `hart`, lo
+    /// manda al final de la cola y activa el siguiente proceso listo.
+    /// Devuelve la dirección en la que debe continuar la ejecución luego del
+    /// `mret`. Si no hay procesos listos, esperamos interrupciones en el bucle
+    /// ocioso.
+    pub fn schedule(hart: usize, epc: usize) -> usize {
+        let queue = Scheduler::run_queue();
+        if let Some(current_pid) = ProcessTable::get_current_pid(hart) {
+            if let Some(current) = ProcessTable::get(current_pid) {
+                current.program_counter = epc;
+                queue.pids.push_back(current_pid);
+            }
+        }
+        match Scheduler::next_ready() {
+            Some(next) => {
+                ProcessTable::set_current(hart, Some(next.get_pid()));
+                unsafe {
+                    next.activate();
+                    set_previous_mode(false);
+                }
+                next.program_counter
+            }
+            None => {
+                ProcessTable::set_current(hart, None);
+                unsafe { activate_idle(hart) }
+            }
+        }
+    }
+
+    /// Arranca el primer proceso listo y el timer que dispara la planificación
+    pub fn start() -> ! {
+        let hart = unsafe { riscv64::mhartid_read() };
+        let process = Scheduler::next_ready().expect("No processes to run");
+        ProcessTable::set_current(hart, Some(process.get_pid()));
+        schedule_mtime_interrupt(TIMER_OFFSET_VALUE);
+        unsafe {
+            process.activate();
+            user_mode_init(process.program_counter, process.get_stack_pointer())
+        }
+    }
+
+    /// Saca de la cola al primer proceso listo. Los procesos muertos no vuelven
+    /// a la cola: quedan en la tabla hasta que su padre los espere.
+    fn next_ready() -> Option<&'static mut Process<'static>> {
+        let queue = Scheduler::run_queue();
+        let now = read_mtime();
+        for _ in 0..queue.pids.len() {
+            let pid = queue.pids.pop_front()?;
+            let Some(process) = ProcessTable::get(pid) else {
+                continue;
+            };
+            if process.is_ready(now) {
+                return Some(process);
+            }
+            if !process.is_dead() {
+                queue.pids.push_back(pid);
+            }
+        }
+        None
+    }
+}
+
+/// Configura el modo al que vuelve `mret`: modo máquina para el bucle ocioso
+/// o modo usuario para los procesos. En ambos casos las interrupciones quedan
+/// habilitadas.
+unsafe fn set_previous_mode(machine: bool) {
+    let mut status = riscv64::mstatus_read() & !MSTATUS_MPP_MASK;
+    if machine {
+        status |= MSTATUS_MPP_MASK;
+    }
+    riscv64::mstatus_write(status | MSTATUS_MPIE);
+}
+
+/// Cuando no hay procesos listos, volvemos a un bucle en modo máquina que
+/// espera interrupciones. Usa el trap frame del kernel y su trap stack.
+unsafe fn activate_idle(hart: usize) -> usize {
+    let frame = &mut KERNEL_TRAP_FRAME[hart];
+    frame.regs[SP_REGISTER] = frame.trap_stack as usize;
+    riscv64::mscratch_write(frame as *const _ as usize);
+    set_previous_mode(true);
+    idle_loop as *const () as usize
+}
+
+extern "C" fn idle_loop() -> ! {
+    loop {
+        unsafe { riscv64::wfi() };
+    }
+}
@@ -3,12 +3,53 @@ use core::arch::asm;
 pub const REBOOT_MAGIC_1: usize = 0x13011990;
 pub const REBOOT_MAGIC_2: usize = 0xCACAFEAA;
 
+pub const SYS_READ: usize = 0;
 pub const SYS_WRITE: usize = 1;
+pub const SYS_OPEN: usize = 2;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_LSEEK: usize = 8;
 pub const SYS_BRK: usize = 12;
+pub const SYS_SOCKET: usize = 41;
+pub const SYS_CONNECT: usize = 42;
+pub const SYS_ACCEPT: usize = 43;
+pub const SYS_SENDTO: usize = 44;
+pub const SYS_RECVFROM: usize = 45;
 pub const SYS_REBOOT: usize = 48;
+pub const SYS_BIND: usize = 49;
+pub const SYS_LISTEN: usize = 50;
+pub const SYS_FORK: usize = 57;
+pub const SYS_EXECVE: usize = 59;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_WAIT: usize = 61;
+pub const SYS_FTRUNCATE: usize = 77;
+pub const SYS_UNLINK: usize = 87;
+pub const SYS_GETRANDOM: usize = 318;
 pub const SYS_PUSHMSGBOX: usize = 500;
 pub const SYS_POPMSGBOX: usize = 501;
 
+/// Valor devuelto por las syscalls que fallan (-1)
+pub const SYSCALL_ERROR: usize = usize::MAX;
+
+/// Origen del desplazamiento de `lseek`
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Flags de `open`, con los valores de newlib
+pub const O_CREAT: usize = 0x0200;
+pub const O_TRUNC: usize = 0x0400;
+pub const O_EXCL: usize = 0x0800;
+
+/// Familia y tipos de `socket`. Sólo soportamos IPv4
+pub const AF_INET: usize = 2;
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+
+/// Flags de `getrandom`. El generador se siembra al arrancar, así que nunca
+/// bloquea y ambos se ignoran
+pub const GRND_NONBLOCK: usize = 1;
+pub const GRND_RANDOM: usize = 2;
+
 /// Esta macro recibe un id de syscall y una cantidad variable de argumentos
 /// luego llama a call_arg_n según la cantidad que posee
 macro_rules! syscalls {
@@ -20,42 +61,64 @@ macro_rules! syscalls {
         }
 
         impl $ename {
-            pub fn call(&self) {
+            pub fn call(&self) -> usize {
                 match self {
                     $($ename::$vname{$($varg),*} => syscalls!(@call ($syscall_id, $($varg)*))),*
                 }
             }
         }
     };
-    (@call ($syscall_id:expr)) => {Syscall::call_arg_0($syscall_id)};
+    (@call ($syscall_id:expr $(,)?)) => {Syscall::call_arg_0($syscall_id)};
     (@call ($syscall_id:expr, $a:tt)) => {Syscall::call_arg_1($syscall_id, *$a as usize)};
     (@call ($syscall_id:expr, $a:tt $b:tt)) => {Syscall::call_arg_2($syscall_id, *$a as usize, *$b as usize)};
-    (@call ($syscall_id:expr, $a:tt $b:tt $c:tt)) => {Syscall::call_arg_3($syscall_id, *$a, *$b as usize, *$c as usize)};
+    (@call ($syscall_id:expr, $a:tt $b:tt $c:tt)) => {Syscall::call_arg_3($syscall_id, *$a as usize, *$b as usize, *$c as usize)};
+    (@call ($syscall_id:expr, $a:tt $b:tt $c:tt $d:tt $e:tt $f:tt)) => {Syscall::call_arg_6($syscall_id, *$a as usize, *$b as usize, *$c as usize, *$d as usize, *$e as usize, *$f as usize)};
 }
 
 syscalls! {
     enum Syscall {
+        Read(SYS_READ, fd: usize, buf: *mut u8, n_bytes: usize),
         Write(SYS_WRITE, fd: usize, buf: *const u8, n_bytes: usize),
-        Reboot(SYS_REBOOT, magic1: usize, magic2: usize, poweroff: bool)
+        Open(SYS_OPEN, path: *const u8, flags: usize, mode: usize),
+        Close(SYS_CLOSE, fd: usize),
+        Lseek(SYS_LSEEK, fd: usize, offset: isize, whence: usize),
+        Socket(SYS_SOCKET, domain: usize, socket_type: usize, protocol: usize),
+        Connect(SYS_CONNECT, fd: usize, addr: *const u8, addr_len: usize),
+        Accept(SYS_ACCEPT, fd: usize, addr: *mut u8, addr_len: *mut u32),
+        Sendto(SYS_SENDTO, fd: usize, buf: *const u8, n_bytes: usize, flags: usize, addr: *const u8, addr_len: usize),
+        Recvfrom(SYS_RECVFROM, fd: usize, buf: *mut u8, n_bytes: usize, flags: usize, addr: *mut u8, addr_len: *mut u32),
+        Reboot(SYS_REBOOT, magic1: usize, magic2: usize, poweroff: bool),
+        Bind(SYS_BIND, fd: usize, addr: *const u8, addr_len: usize),
+        Listen(SYS_LISTEN, fd: usize, backlog: usize),
+        Fork(SYS_FORK,),
+        Execve(SYS_EXECVE, path: *const u8, argv: *const *const u8, envp: *const *const u8),
+        Exit(SYS_EXIT, status: isize),
+        Wait(SYS_WAIT, status: *mut i32),
+        Ftruncate(SYS_FTRUNCATE, fd: usize, length: usize),
+        Unlink(SYS_UNLINK, path: *const u8),
+        Getrandom(SYS_GETRANDOM, buf: *mut u8, n_bytes: usize, flags: usize)
     }
 }
 
 impl Syscall {
     #[no_mangle]
-    pub extern "C" fn call_arg_0(syscall_id: usize) {
+    pub extern "C" fn call_arg_0(syscall_id: usize) -> usize {
+        let result;
         // ¿Esto es trivial?
         unsafe {
             asm!(
             "mv a0, {}",
             "ecall",
             in(reg) syscall_id,
-            out("a0") _
+            out("a0") result
             );
         }
+        result
     }
 
     #[no_mangle]
-    pub extern "C" fn call_arg_1(syscall_id: usize, arg0: usize) {
+    pub extern "C" fn call_arg_1(syscall_id: usize, arg0: usize) -> usize {
+        let result;
         // ¿Esto es trivial?
         unsafe {
             asm!(
@@ -64,14 +127,16 @@ impl Syscall {
             "ecall",
             in(reg) syscall_id,
             in(reg) arg0,
-            out("a0") _,
+            out("a0") result,
             out("a1") _
             );
         }
+        result
     }
 
     #[no_mangle]
-    pub extern "C" fn call_arg_2(syscall_id: usize, arg0: usize, arg1: usize) {
+    pub extern "C" fn call_arg_2(syscall_id: usize, arg0: usize, arg1: usize) -> usize {
+        let result;
         // ¿Esto es trivial?
         unsafe {
             asm!(
@@ -82,15 +147,22 @@ impl Syscall {
             in(reg) syscall_id,
             in(reg) arg0,
             in(reg) arg1,
-            out("a0") _,
+            out("a0") result,
             out("a1") _,
             out("a2") _
             );
         }
+        result
     }
 
     #[no_mangle]
-    pub extern "C" fn call_arg_3(syscall_id: usize, arg0: usize, arg1: usize, arg2: usize) {
+    pub extern "C" fn call_arg_3(
+        syscall_id: usize,
+        arg0: usize,
+        arg1: usize,
+        arg2: usize,
+    ) -> usize {
+        let result;
         // ¿Esto es trivial?
         unsafe {
             asm!(
@@ -103,16 +175,43 @@ impl Syscall {
             in(reg) arg0,
             in(reg) arg1,
             in(reg) arg2,
-            out("a0") _,
+            out("a0") result,
             out("a1") _,
             out("a2") _,
             out("a3") _
             );
         }
+        result
+    }
+
+    #[no_mangle]
+    pub extern "C" fn call_arg_6(
+        syscall_id: usize,
+        arg0: usize,
+        arg1: usize,
+        arg2: usize,
+        arg3: usize,
+        arg4: usize,
+        arg5: usize,
+    ) -> usize {
+        let result;
+        unsafe {
+            asm!(
+            "ecall",
+            inout("a0") syscall_id => result,
+            inout("a1") arg0 => _,
+            inout("a2") arg1 => _,
+            inout("a3") arg2 => _,
+            inout("a4") arg3 => _,
+            inout("a5") arg4 => _,
+            inout("a6") arg5 => _,
+            );
+        }
+        result
     }
     // TODO: los que faltan
 }
 
-pub fn call_syscall(syscall: &Syscall) {
-    syscall.call();
+pub fn call_syscall(syscall: &Syscall) -> usize {
+    syscall.call()
 }
@@ -8,30 +8,32 @@ extern "C" {
 static mut HEAP_END: usize = 0;
 
 #[no_mangle]
-pub extern "C" fn _exit(syscall_id: isize) {
-
+pub extern "C" fn _exit(status: isize) -> ! {
+    let exit_syscall = Syscall::Exit { status };
+    exit_syscall.call();
+    loop {}
 }
 
 #[no_mangle]
 pub extern "C" fn _close(fd: isize)  -> isize {
-    -1
+    let close_syscall = Syscall::Close { fd: fd as usize };
+    close_syscall.call() as isize
 }
 
 #[no_mangle]
 pub extern "C" fn _execve(name: *mut u8, argv: *mut *mut u8, env: *mut *mut u8) -> isize {
-    let msg = "execve";
-    let write_syscall = Syscall::Write {
-        fd: 0,
-        buf: msg.as_ptr(),
-        n_bytes: msg.len(),
+    let execve_syscall = Syscall::Execve {
+        path: name,
+        argv: argv as *const *const u8,
+        envp: env as *const *const u8,
     };
-    write_syscall.call();
-    -1
+    execve_syscall.call() as isize
 }
 
 #[no_mangle]
 pub extern "C" fn _fork() -> isize {
-    -1
+    let fork_syscall = Syscall::Fork {};
+    fork_syscall.call() as isize
 }
 
 #[no_mangle]
@@ -61,17 +63,32 @@ pub extern "C" fn _link(old: *mut u8, new: *mut u8) -> isize {
 
 #[no_mangle]
 pub extern "C" fn _lseek(file: isize, ptr: isize, dir: isize) -> isize {
-    0
+    let lseek_syscall = Syscall::Lseek {
+        fd: file as usize,
+        offset: ptr,
+        whence: dir as usize,
+    };
+    lseek_syscall.call() as isize
 }
 
 #[no_mangle]
 pub extern "C" fn _open(name: *const u8, flags: isize, mode: isize) -> isize {
-    -1
+    let open_syscall = Syscall::Open {
+        path: name,
+        flags: flags as usize,
+        mode: mode as usize,
+    };
+    open_syscall.call() as isize
 }
 
 #[no_mangle]
 pub extern "C" fn _read(file: isize, ptr: *mut u8, len: isize) -> isize {
-    0
+    let read_syscall = Syscall::Read {
+        fd: file as usize,
+        buf: ptr,
+        n_bytes: len as usize,
+    };
+    read_syscall.call() as isize
 }
 
 #[no_mangle]
@@ -105,13 +122,15 @@ pub extern "C" fn _times() -> isize {
 }
 
 #[no_mangle]
-pub extern "C" fn _unlink(syscall_id: isize) -> isize {
-    -1
+pub extern "C" fn _unlink(name: *const u8) -> isize {
+    let unlink_syscall = Syscall::Unlink { path: name };
+    unlink_syscall.call() as isize
 }
 
 #[no_mangle]
-pub extern "C" fn _wait(syscall_id: isize) -> isize {
-    -1
+pub extern "C" fn _wait(status: *mut i32) -> isize {
+    let wait_syscall = Syscall::Wait { status };
+    wait_syscall.call() as isize
 }
 
 #[no_mangle]
@@ -1,29 +1,253 @@
 use crate::cpu::riscv64::trap::TrapFrame;
+use crate::devices::console::Console;
 use crate::devices::shutdown;
-use crate::print;
-use crate::system::process::InitProcess;
+use crate::devices::virtio::DeviceError;
+use crate::filesystem::virtual_fs::{FileDescriptor, VirtualFsManager};
+use crate::mmu::alloc_stats::AllocTag;
+use crate::net::socket::{SocketError, SocketTable, SocketType};
+use crate::net::Endpoint;
+use crate::system::fd_table::OpenFile;
+use crate::system::process::{Pid, Process, ProcessState};
+use crate::system::process_table::{ProcessTable, WaitStatus};
+use crate::system::random::EntropyPool;
+use crate::system::scheduler::Scheduler;
 use crate::system::syscall;
-use crate::system::syscall::{REBOOT_MAGIC_1, REBOOT_MAGIC_2};
+use crate::system::syscall::{
+    AF_INET, O_CREAT, O_EXCL, O_TRUNC, REBOOT_MAGIC_1, REBOOT_MAGIC_2, SEEK_CUR, SEEK_END,
+    SEEK_SET, SOCK_DGRAM, SOCK_STREAM, SYSCALL_ERROR,
+};
+use crate::utils::error::{IoError, IoResult};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
 
 const ARG_CODE: usize = 10;
 const ARG_1: usize = 11;
 const ARG_2: usize = 12;
 const ARG_3: usize = 13;
+const ARG_4: usize = 14;
+const ARG_5: usize = 15;
+const ARG_6: usize = 16;
+/// El resultado de la syscall se devuelve en `a0`, pisando el código
+const RETURN_VALUE: usize = ARG_CODE;
+/// Máximo de bytes que se transfieren en un `read` o `write`
+const MAX_IO_SIZE: usize = 0x10000;
+/// Tamaño de `struct sockaddr_in`
+const SOCKADDR_IN_SIZE: usize = 16;
 
 /// Ejecuta las distintas syscalls y almacena los datos en el frame del llamador
-pub fn execute_syscall(frame: &mut TrapFrame, _epc: usize) {
+///
+/// Devuelve la dirección en la que continúa el proceso: normalmente la
+/// instrucción siguiente al `ecall`, el mismo `ecall` si el proceso quedó
+/// bloqueado y debe reintentar la syscall al despertar, o el punto de entrada
+/// de la nueva imagen luego de un `execve`.
+pub fn execute_syscall(frame: &mut TrapFrame, epc: usize) -> usize {
     let code = frame.regs[ARG_CODE];
+    let _tag = syscall_tag(code).enter();
+    let next_pc = epc + 4;
+    let process = ProcessTable::current().unwrap();
     match code {
+        syscall::SYS_READ => {
+            let mut buf = vec![0; min(frame.regs[ARG_3], MAX_IO_SIZE)];
+            let pid = process.get_pid();
+            let read = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::Console) => match Console::read(pid, &mut buf) {
+                    Some(read) => Some(read),
+                    None => {
+                        // Reintentamos la lectura cuando lleguen datos
+                        process.state = ProcessState::Waiting;
+                        return epc;
+                    }
+                },
+                Some(OpenFile::File(file)) => match read_file(file, &mut buf, pid) {
+                    Err(IoError::DeviceError(DeviceError::WouldBlock)) => {
+                        // Reintentamos la lectura cuando el disco complete el pedido
+                        process.state = ProcessState::Waiting;
+                        return epc;
+                    }
+                    result => result.ok(),
+                },
+                Some(OpenFile::Socket(id)) => match SocketTable::recv(*id, &mut buf, pid) {
+                    Err(SocketError::WouldBlock) => {
+                        // Reintentamos la lectura cuando lleguen datos
+                        process.state = ProcessState::Waiting;
+                        return epc;
+                    }
+                    result => result.ok().map(|(read, _)| read),
+                },
+                None => None,
+            };
+            frame.regs[RETURN_VALUE] = match read {
+                Some(read) if process.copy_to_user(frame.regs[ARG_2], &buf[..read]) => read,
+                _ => SYSCALL_ERROR,
+            };
+        }
         syscall::SYS_WRITE => {
-            let buf_virt_ptr = frame.regs[ARG_2];
-            let buf_size = frame.regs[ARG_3];
-            // El Map table lo voy a sacar del proceso cuando tenga un buscador de procesos
-            let process = InitProcess::get_process();
-            let process_table = &*process.root;
-            let buf_phys_ptr = process_table.virt_to_phys(buf_virt_ptr).unwrap() as *const u8;
-            for i in 0..buf_size as isize {
-                print!("{}", unsafe { *buf_phys_ptr.offset(i) } as char);
+            let mut buf = vec![0; min(frame.regs[ARG_3], MAX_IO_SIZE)];
+            let copied = process.copy_from_user(frame.regs[ARG_2], &mut buf);
+            frame.regs[RETURN_VALUE] = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::Console) if copied => Console::write(&buf),
+                Some(OpenFile::File(file)) if copied => {
+                    write_file(file, &buf).unwrap_or(SYSCALL_ERROR)
+                }
+                Some(OpenFile::Socket(id)) if copied => {
+                    match SocketTable::send(*id, &buf, None, process.get_pid()) {
+                        Err(SocketError::WouldBlock) => {
+                            // Reintentamos la escritura cuando el otro extremo
+                            // confirme datos
+                            process.state = ProcessState::Waiting;
+                            return epc;
+                        }
+                        result => result.unwrap_or(SYSCALL_ERROR),
+                    }
+                }
+                _ => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_OPEN => {
+            let flags = frame.regs[ARG_2];
+            let file = process
+                .read_user_str(frame.regs[ARG_1])
+                .filter(|path| path.starts_with('/'))
+                .and_then(|path| open_file(&path, flags));
+            frame.regs[RETURN_VALUE] = match file {
+                Some(file) => process.files.insert(OpenFile::File(file)),
+                None => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_CLOSE => {
+            frame.regs[RETURN_VALUE] = match process.files.remove(frame.regs[ARG_1]) {
+                Some(OpenFile::File(file)) => match VirtualFsManager::close(file) {
+                    Ok(()) => 0,
+                    Err(_) => SYSCALL_ERROR,
+                },
+                Some(OpenFile::Socket(id)) => {
+                    SocketTable::close(id);
+                    0
+                }
+                Some(OpenFile::Console) => 0,
+                None => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_LSEEK => {
+            let offset = frame.regs[ARG_2] as isize;
+            let whence = frame.regs[ARG_3];
+            frame.regs[RETURN_VALUE] = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::File(file)) => seek_file(file, offset, whence),
+                _ => None,
             }
+            .unwrap_or(SYSCALL_ERROR);
+        }
+        syscall::SYS_SOCKET => {
+            let socket_type = match (frame.regs[ARG_1], frame.regs[ARG_2]) {
+                (AF_INET, SOCK_STREAM) => Some(SocketType::Stream),
+                (AF_INET, SOCK_DGRAM) => Some(SocketType::Datagram),
+                _ => None,
+            };
+            frame.regs[RETURN_VALUE] = match socket_type.map(SocketTable::create) {
+                Some(Ok(id)) => process.files.insert(OpenFile::Socket(id)),
+                _ => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_BIND => {
+            let address = read_sockaddr(process, frame.regs[ARG_2], frame.regs[ARG_3]);
+            frame.regs[RETURN_VALUE] = match (process.files.get(frame.regs[ARG_1]), address) {
+                (Some(OpenFile::Socket(id)), Some(address)) => {
+                    match SocketTable::bind(*id, address.port) {
+                        Ok(()) => 0,
+                        Err(_) => SYSCALL_ERROR,
+                    }
+                }
+                _ => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_LISTEN => {
+            frame.regs[RETURN_VALUE] = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::Socket(id)) if SocketTable::listen(*id).is_ok() => 0,
+                _ => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_CONNECT => {
+            let pid = process.get_pid();
+            let address = read_sockaddr(process, frame.regs[ARG_2], frame.regs[ARG_3]);
+            let result = match (process.files.get(frame.regs[ARG_1]), address) {
+                (Some(OpenFile::Socket(id)), Some(address)) => {
+                    SocketTable::connect(*id, address, pid)
+                }
+                _ => Err(SocketError::InvalidSocket),
+            };
+            frame.regs[RETURN_VALUE] = match result {
+                Ok(()) => 0,
+                Err(SocketError::WouldBlock) => {
+                    // Reintentamos cuando termine el handshake
+                    process.state = ProcessState::Waiting;
+                    return epc;
+                }
+                Err(_) => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_ACCEPT => {
+            let result = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::Socket(id)) => SocketTable::accept(*id, process.get_pid()),
+                _ => Err(SocketError::InvalidSocket),
+            };
+            frame.regs[RETURN_VALUE] = match result {
+                Ok((id, remote)) => {
+                    write_sockaddr(process, frame.regs[ARG_2], frame.regs[ARG_3], remote);
+                    process.files.insert(OpenFile::Socket(id))
+                }
+                Err(SocketError::WouldBlock) => {
+                    // Reintentamos cuando llegue una conexión
+                    process.state = ProcessState::Waiting;
+                    return epc;
+                }
+                Err(_) => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_SENDTO => {
+            let mut buf = vec![0; min(frame.regs[ARG_3], MAX_IO_SIZE)];
+            let copied = process.copy_from_user(frame.regs[ARG_2], &mut buf);
+            let destination = match frame.regs[ARG_5] {
+                0 => None,
+                addr => read_sockaddr(process, addr, frame.regs[ARG_6]),
+            };
+            let invalid_destination = frame.regs[ARG_5] != 0 && destination.is_none();
+            frame.regs[RETURN_VALUE] = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::Socket(id)) if copied && !invalid_destination => {
+                    match SocketTable::send(*id, &buf, destination, process.get_pid()) {
+                        Err(SocketError::WouldBlock) => {
+                            // Reintentamos el envío cuando el otro extremo
+                            // confirme datos
+                            process.state = ProcessState::Waiting;
+                            return epc;
+                        }
+                        result => result.unwrap_or(SYSCALL_ERROR),
+                    }
+                }
+                _ => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_RECVFROM => {
+            let mut buf = vec![0; min(frame.regs[ARG_3], MAX_IO_SIZE)];
+            let result = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::Socket(id)) => SocketTable::recv(*id, &mut buf, process.get_pid()),
+                _ => Err(SocketError::InvalidSocket),
+            };
+            frame.regs[RETURN_VALUE] = match result {
+                Ok((read, source)) if process.copy_to_user(frame.regs[ARG_2], &buf[..read]) => {
+                    if let Some(source) = source {
+                        write_sockaddr(process, frame.regs[ARG_5], frame.regs[ARG_6], source);
+                    }
+                    read
+                }
+                Err(SocketError::WouldBlock) => {
+                    // Reintentamos la lectura cuando lleguen datos
+                    process.state = ProcessState::Waiting;
+                    return epc;
+                }
+                _ => SYSCALL_ERROR,
+            };
         }
         syscall::SYS_REBOOT => {
             if frame.regs[ARG_1] == REBOOT_MAGIC_1 && frame.regs[ARG_2] == REBOOT_MAGIC_2 {
@@ -31,7 +255,75 @@ pub fn execute_syscall(frame: &mut TrapFrame, _epc: usize) {
             }
         }
         syscall::SYS_BRK => {
-            print!("SYS_BRK not implemented, skipping...\n");
+            // La biblioteca de usuario pide el límite del heap en `*ARG_1`, y
+            // lo va ocupando con `sbrk` sin volver a llamar al kernel
+            let heap_limit = process.heap_limit();
+            let limit_ptr = frame.regs[ARG_1];
+            frame.regs[RETURN_VALUE] = if heap_limit == 0 {
+                SYSCALL_ERROR
+            } else if limit_ptr == 0 || process.copy_to_user(limit_ptr, &heap_limit.to_le_bytes()) {
+                heap_limit
+            } else {
+                SYSCALL_ERROR
+            };
+        }
+        syscall::SYS_FORK => {
+            frame.regs[RETURN_VALUE] = Scheduler::push(process.fork(next_pc)) as usize;
+        }
+        syscall::SYS_EXECVE => {
+            let path = process.read_user_str(frame.regs[ARG_1]);
+            let args = process.read_user_str_array(frame.regs[ARG_2]);
+            let env = process.read_user_str_array(frame.regs[ARG_3]);
+            if let (Some(path), Some(args), Some(env)) = (path, args, env) {
+                let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+                let env = env.iter().map(String::as_str).collect::<Vec<_>>();
+                // Si `exec` funciona, el frame del proceso ya es el de la nueva imagen
+                if let Ok(entry_point) = process.exec(&path, &args, &env) {
+                    return entry_point;
+                }
+            }
+            frame.regs[RETURN_VALUE] = SYSCALL_ERROR;
+        }
+        syscall::SYS_EXIT => {
+            ProcessTable::exit(process.get_pid(), frame.regs[ARG_1] as isize);
+        }
+        syscall::SYS_WAIT => {
+            let status_ptr = frame.regs[ARG_1];
+            match ProcessTable::wait_child(process.get_pid()) {
+                WaitStatus::Reaped(child_pid, status) => {
+                    // Mismo formato que `WEXITSTATUS`: el código va en el segundo byte
+                    let wait_status = ((status & 0xff) << 8) as i32;
+                    if status_ptr != 0 {
+                        process.copy_to_user(status_ptr, &wait_status.to_le_bytes());
+                    }
+                    frame.regs[RETURN_VALUE] = child_pid as usize;
+                }
+                WaitStatus::Blocked => return epc,
+                WaitStatus::NoChildren => frame.regs[RETURN_VALUE] = SYSCALL_ERROR,
+            }
+        }
+        syscall::SYS_FTRUNCATE => {
+            let length = frame.regs[ARG_2] as u64;
+            frame.regs[RETURN_VALUE] = match process.files.get(frame.regs[ARG_1]) {
+                Some(OpenFile::File(file)) if VirtualFsManager::truncate(file, length).is_ok() => 0,
+                _ => SYSCALL_ERROR,
+            };
+        }
+        syscall::SYS_UNLINK => {
+            let unlinked = process
+                .read_user_str(frame.regs[ARG_1])
+                .filter(|path| path.starts_with('/'))
+                .is_some_and(|path| VirtualFsManager::unlink(&path).is_ok());
+            frame.regs[RETURN_VALUE] = if unlinked { 0 } else { SYSCALL_ERROR };
+        }
+        syscall::SYS_GETRANDOM => {
+            let mut buf = vec![0; min(frame.regs[ARG_2], MAX_IO_SIZE)];
+            EntropyPool::fill(&mut buf);
+            frame.regs[RETURN_VALUE] = if process.copy_to_user(frame.regs[ARG_1], &buf) {
+                buf.len()
+            } else {
+                SYSCALL_ERROR
+            };
         }
         syscall::SYS_POPMSGBOX => {
             unimplemented!("POPMSGBOX syscall ({}) not implemented", code);
@@ -40,4 +332,116 @@ pub fn execute_syscall(frame: &mut TrapFrame, _epc: usize) {
             unimplemented!("Unknown syscall: {}", code);
         }
     }
+    next_pc
+}
+
+/// Abre el archivo según los flags de `open`: lo crea con `O_CREAT` (falla
+/// si ya existe y se pidió `O_EXCL`) y lo vacía con `O_TRUNC`
+fn open_file(path: &str, flags: usize) -> Option<FileDescriptor> {
+    let create = flags & O_CREAT != 0;
+    let file = match VirtualFsManager::open(path) {
+        Ok(_) if create && flags & O_EXCL != 0 => return None,
+        Ok(file) => file,
+        Err(_) if create => VirtualFsManager::create(path).ok()?,
+        Err(_) => return None,
+    };
+    if flags & O_TRUNC != 0 {
+        VirtualFsManager::truncate(&file, 0).ok()?;
+    }
+    Some(file)
+}
+
+/// Subsistema al que se le cuentan las reservas de la syscall. `read` y
+/// `write` se cuentan como VFS aunque el descriptor sea la consola o un socket
+fn syscall_tag(code: usize) -> AllocTag {
+    match code {
+        syscall::SYS_READ
+        | syscall::SYS_WRITE
+        | syscall::SYS_OPEN
+        | syscall::SYS_CLOSE
+        | syscall::SYS_LSEEK
+        | syscall::SYS_FTRUNCATE
+        | syscall::SYS_UNLINK => AllocTag::Vfs,
+        syscall::SYS_SOCKET
+        | syscall::SYS_BIND
+        | syscall::SYS_LISTEN
+        | syscall::SYS_CONNECT
+        | syscall::SYS_ACCEPT
+        | syscall::SYS_SENDTO
+        | syscall::SYS_RECVFROM => AllocTag::Net,
+        syscall::SYS_BRK
+        | syscall::SYS_FORK
+        | syscall::SYS_EXECVE
+        | syscall::SYS_EXIT
+        | syscall::SYS_WAIT => AllocTag::Process,
+        _ => AllocTag::Kernel,
+    }
+}
+
+/// Escribe en la posición actual del archivo y la avanza
+fn write_file(file: &mut FileDescriptor, buf: &[u8]) -> Option<usize> {
+    let written = VirtualFsManager::write(file, buf, file.file_pos as u64).ok()?;
+    file.file_pos += written;
+    Some(written)
+}
+
+/// Lee desde la posición actual del archivo y la avanza. Si hay que esperar
+/// al disco la posición no cambia, para que la lectura se pueda reintentar
+fn read_file(file: &mut FileDescriptor, buf: &mut [u8], pid: Pid) -> IoResult<usize> {
+    let read = VirtualFsManager::read_nonblocking(file, buf, file.file_pos as u64, pid)?;
+    file.file_pos += read;
+    // Las lecturas pueden ser cortas sin llegar al final del archivo
+    file.eof_flag = read == 0 && !buf.is_empty();
+    Ok(read)
+}
+
+/// Mueve la posición del archivo según `whence` y devuelve la nueva posición
+fn seek_file(file: &mut FileDescriptor, offset: isize, whence: usize) -> Option<usize> {
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.file_pos,
+        SEEK_END => VirtualFsManager::stat(file).ok()?.size as usize,
+        _ => return None,
+    };
+    file.file_pos = base.checked_add_signed(offset)?;
+    file.eof_flag = false;
+    Some(file.file_pos)
+}
+
+/// Lee un `struct sockaddr_in` del proceso: familia, puerto en big endian y
+/// dirección IPv4
+fn read_sockaddr(process: &mut Process, addr: usize, addr_len: usize) -> Option<Endpoint> {
+    if addr_len < SOCKADDR_IN_SIZE {
+        return None;
+    }
+    let mut sockaddr = [0; SOCKADDR_IN_SIZE];
+    if !process.copy_from_user(addr, &mut sockaddr) {
+        return None;
+    }
+    if u16::from_le_bytes([sockaddr[0], sockaddr[1]]) as usize != AF_INET {
+        return None;
+    }
+    let port = u16::from_be_bytes([sockaddr[2], sockaddr[3]]);
+    let address = [sockaddr[4], sockaddr[5], sockaddr[6], sockaddr[7]];
+    Some(Endpoint { address, port })
+}
+
+/// Escribe `endpoint` como `struct sockaddr_in` si el proceso pasó un buffer.
+/// Igual que en POSIX, se trunca al tamaño que indica `addr_len_ptr`, y se
+/// guarda ahí el tamaño completo de la dirección
+fn write_sockaddr(process: &mut Process, addr: usize, addr_len_ptr: usize, endpoint: Endpoint) {
+    if addr == 0 || addr_len_ptr == 0 {
+        return;
+    }
+    let mut addr_len = [0; 4];
+    if !process.copy_from_user(addr_len_ptr, &mut addr_len) {
+        return;
+    }
+    let mut sockaddr = [0; SOCKADDR_IN_SIZE];
+    sockaddr[..2].copy_from_slice(&(AF_INET as u16).to_le_bytes());
+    sockaddr[2..4].copy_from_slice(&endpoint.port.to_be_bytes());
+    sockaddr[4..8].copy_from_slice(&endpoint.address);
+    let len = min(u32::from_le_bytes(addr_len) as usize, SOCKADDR_IN_SIZE);
+    process.copy_to_user(addr, &sockaddr[..len]);
+    process.copy_to_user(addr_len_ptr, &(SOCKADDR_IN_SIZE as u32).to_le_bytes());
 }
@@ -0,0 +1,119 @@
+use crate::system::proto::elf_loader::{ElfLoader, ElfLoaderError};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PF_R: u32 = 0x4;
+
+/// Segmento `PT_LOAD` de prueba: (flags, offset, vaddr, filesz, memsz)
+type Segment = (u32, u64, u64, u64, u64);
+
+/// Arma un ejecutable RISC-V válido con los segmentos pedidos, seguidos de
+/// una página de datos para que entren en el archivo
+fn build_elf(segments: &[Segment]) -> Vec<u8> {
+    let table_size = segments.len() * PROGRAM_HEADER_SIZE;
+    let mut data = vec![0; HEADER_SIZE + table_size + 0x1000];
+    data[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    data[4] = 2; // ELFCLASS64
+    data[5] = 1; // ELFDATA2LSB
+    data[6] = 1; // EV_CURRENT
+    data[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    data[18..20].copy_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+    data[24..32].copy_from_slice(&0x1_0000u64.to_le_bytes());
+    data[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    data[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    data[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (i, (flags, offset, vaddr, filesz, memsz)) in segments.iter().enumerate() {
+        let entry = &mut data[HEADER_SIZE + i * PROGRAM_HEADER_SIZE..][..PROGRAM_HEADER_SIZE];
+        entry[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        entry[4..8].copy_from_slice(&flags.to_le_bytes());
+        entry[8..16].copy_from_slice(&offset.to_le_bytes());
+        entry[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        entry[32..40].copy_from_slice(&filesz.to_le_bytes());
+        entry[40..48].copy_from_slice(&memsz.to_le_bytes());
+    }
+    data
+}
+
+fn validate(data: &[u8]) -> Result<(), ElfLoaderError> {
+    ElfLoader::new(data).map(|_| ())
+}
+
+/// Un ejecutable bien formado pasa la validación
+#[test_case]
+fn elf_valid_header() {
+    let elf = build_elf(&[(PF_R, 0x100, 0x1_0000, 0x100, 0x2000)]);
+    assert_eq!(validate(&elf), Ok(()));
+}
+
+/// Se rechazan los encabezados que no son de un ejecutable RISC-V de 64 bits
+#[test_case]
+fn elf_invalid_header() {
+    let valid = build_elf(&[]);
+    let with = |offset: usize, bytes: &[u8]| {
+        let mut elf = valid.clone();
+        elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+        elf
+    };
+    assert_eq!(
+        validate(&with(1, b"ELG")),
+        Err(ElfLoaderError::InvalidMagic)
+    );
+    assert_eq!(
+        validate(&with(4, &[1])),
+        Err(ElfLoaderError::InvalidClass(1))
+    );
+    assert_eq!(
+        validate(&with(5, &[2])),
+        Err(ElfLoaderError::InvalidEndianness(2))
+    );
+    assert_eq!(
+        validate(&with(18, &62u16.to_le_bytes())),
+        Err(ElfLoaderError::InvalidMachine(62))
+    );
+    assert_eq!(
+        validate(&with(16, &3u16.to_le_bytes())),
+        Err(ElfLoaderError::NotExecutable(3))
+    );
+    let elf = build_elf(&[(PF_R, 0x100, 0x1_0000, 0x100, 0x100)]);
+    let mut wrong_size = elf.clone();
+    wrong_size[54..56].copy_from_slice(&32u16.to_le_bytes());
+    assert_eq!(
+        validate(&wrong_size),
+        Err(ElfLoaderError::InvalidProgramHeaderSize(32))
+    );
+    assert_eq!(
+        validate(&elf[..HEADER_SIZE - 1]),
+        Err(ElfLoaderError::Truncated)
+    );
+    // La tabla de program headers no entra en el archivo
+    assert_eq!(
+        validate(&elf[..HEADER_SIZE + PROGRAM_HEADER_SIZE - 1]),
+        Err(ElfLoaderError::Truncated)
+    );
+}
+
+/// Se rechazan los segmentos que no entran en el archivo, que ocupan más en
+/// el archivo que en memoria, que no tienen permisos o que se superponen
+#[test_case]
+fn elf_invalid_segments() {
+    let elf = build_elf(&[(PF_R, 0x100, 0x1_0000, 0x10_0000, 0x10_0000)]);
+    assert_eq!(validate(&elf), Err(ElfLoaderError::Truncated));
+    let elf = build_elf(&[(PF_R, 0x100, 0x1_0000, 0x200, 0x100)]);
+    assert_eq!(validate(&elf), Err(ElfLoaderError::InvalidSegmentSize));
+    let elf = build_elf(&[(PF_R, 0x100, u64::MAX - 0x10, 0x100, 0x100)]);
+    assert_eq!(validate(&elf), Err(ElfLoaderError::InvalidSegmentSize));
+    let elf = build_elf(&[(0, 0x100, 0x1_0000, 0x100, 0x100)]);
+    assert_eq!(
+        validate(&elf),
+        Err(ElfLoaderError::SegmentWithoutPermissions)
+    );
+    let elf = build_elf(&[
+        (PF_R, 0x100, 0x1_0000, 0x100, 0x2000),
+        (PF_R, 0x200, 0x1_1000, 0x100, 0x100),
+    ]);
+    assert_eq!(validate(&elf), Err(ElfLoaderError::OverlappingSegments));
+}
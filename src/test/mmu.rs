@@ -1,70 +1,351 @@
-use crate::mmu::riscv64::{PageBits, PageTable, PAGE_ALLOCATOR};
-use crate::mmu::HEAP_START;
+use crate::mmu::alloc_stats::AllocTag;
+use crate::mmu::map_table::EntryBits;
+use crate::mmu::riscv64::{PageBits, PageTable, GLOBAL_PAGE_TABLE, PAGE_ALLOCATOR, PAGE_SIZE};
+use crate::mmu::slab::SlabCaches;
+use crate::mmu::vma::{Access, Vma, VmaKind};
+use crate::system::process::{Process, STACK_ADDR};
 use alloc::boxed::Box;
+use core::alloc::Layout;
 use core::ptr::NonNull;
 
+/// Región reservada a la tabla global sobre la que un test arma su propia
+/// tabla de páginas, sin tocar las páginas que ya están en uso
+struct PrivateHeap {
+    region: NonNull<u8>,
+    page_table: PageTable,
+}
+
+impl PrivateHeap {
+    fn new(size: usize) -> Self {
+        let region = GLOBAL_PAGE_TABLE
+            .get_root()
+            .alloc(size / PAGE_SIZE)
+            .unwrap();
+        let mut page_table = PageTable::new(region.as_ptr() as usize, size);
+        page_table.init();
+        Self { region, page_table }
+    }
+}
+
+impl Drop for PrivateHeap {
+    fn drop(&mut self) {
+        GLOBAL_PAGE_TABLE.get_root().dealloc(self.region);
+    }
+}
+
 /// Tests básicos de alloc y dealloc
 #[test_case]
 fn single_alloc() {
     // Test alloc simple
-    let heap_start = unsafe { HEAP_START };
-    let mut page_table = PageTable::new(heap_start, 0x8_0000);
-    page_table.init();
+    let heap = PrivateHeap::new(0x8_0000);
+    let page_table = &heap.page_table;
     let ptr = page_table.alloc(1).unwrap();
-    unsafe {
-        assert_eq!(
-            *(HEAP_START as *const u8),
-            PageBits::Last.val() | PageBits::Used.val()
-        )
-    }
+    let address = ptr.as_ptr() as usize;
+    assert_eq!(
+        page_table.page_bits(address),
+        PageBits::Last.val() | PageBits::Used.val()
+    );
     page_table.dealloc(ptr);
-    unsafe { assert_eq!(*(HEAP_START as *const u8), PageBits::Empty.val()) }
+    assert_eq!(page_table.page_bits(address), PageBits::Empty.val());
 }
 
 /// test multiples allocs
 #[test_case]
 fn multiple_alloc() {
-    let heap_start = unsafe { HEAP_START };
-    let mut page_table = PageTable::new(heap_start, 0x80_0000);
-    page_table.init();
-    let ptr = page_table.alloc(16).unwrap();
-    for _ in 1..32 {
-        page_table.alloc(16);
-    }
-    for i in 1..(32 * 16) {
-        unsafe {
-            if i % 16 == 15 {
+    let heap = PrivateHeap::new(0x80_0000);
+    let page_table = &heap.page_table;
+    // Con el buddy system los bloques no quedan necesariamente contiguos
+    let ptrs: [NonNull<u8>; 32] = core::array::from_fn(|_| page_table.alloc(16).unwrap());
+    for ptr in ptrs {
+        for i in 0..16 {
+            let address = ptr.as_ptr() as usize + i * PAGE_SIZE;
+            if i == 15 {
                 assert_eq!(
-                    *((HEAP_START + i) as *const u8),
+                    page_table.page_bits(address),
                     PageBits::Last.val() | PageBits::Used.val()
                 )
             } else {
-                assert_eq!(*((HEAP_START + i) as *const u8), PageBits::Used.val())
+                assert_eq!(page_table.page_bits(address), PageBits::Used.val())
             }
         }
     }
-    for i in 0..32 {
-        unsafe {
-            let ptr = ptr.as_ptr().add(16 * i * 4096);
-            page_table.dealloc(NonNull::new_unchecked(ptr));
-        }
+    for ptr in ptrs {
+        page_table.dealloc(ptr);
     }
-    for i in 1..(32 * 16) {
-        unsafe { assert_eq!(*((HEAP_START + i) as *const u8), PageBits::Empty.val()) }
+    for ptr in ptrs {
+        for i in 0..16 {
+            let address = ptr.as_ptr() as usize + i * PAGE_SIZE;
+            assert_eq!(page_table.page_bits(address), PageBits::Empty.val())
+        }
     }
 }
 
+/// Los pedidos que no son potencia de 2 devuelven las páginas sobrantes, y al
+/// liberar los bloques se unen con sus buddies
+#[test_case]
+fn buddy_split_and_merge() {
+    let heap = PrivateHeap::new(0x8_0000);
+    let page_table = &heap.page_table;
+    let initial_blocks = page_table.free_blocks();
+    let first = page_table.alloc(3).unwrap();
+    let second = page_table.alloc(1).unwrap();
+    assert_eq!(
+        second.as_ptr() as usize,
+        first.as_ptr() as usize + 3 * PAGE_SIZE
+    );
+    page_table.dealloc(first);
+    page_table.dealloc(second);
+    assert_eq!(page_table.free_blocks(), initial_blocks);
+}
+
 /// Test allocator
 #[test_case]
 fn box_test() {
+    let page_table = GLOBAL_PAGE_TABLE.get_root();
+    let address;
     {
-        let _boxed = Box::new_in(0, PAGE_ALLOCATOR);
-        unsafe {
-            assert_eq!(
-                *(HEAP_START as *const u8),
-                PageBits::Last.val() | PageBits::Used.val()
-            )
+        let boxed = Box::new_in(0, PAGE_ALLOCATOR);
+        address = &*boxed as *const i32 as usize;
+        assert_eq!(
+            page_table.page_bits(address),
+            PageBits::Last.val() | PageBits::Used.val()
+        )
+    }
+    assert_eq!(page_table.page_bits(address), PageBits::Empty.val()) // Test alloc simple
+}
+
+/// Los objetos chicos comparten páginas, respetan su alineación y las páginas
+/// se devuelven cuando se liberan todos sus objetos
+#[test_case]
+fn slab_alloc() {
+    let heap = PrivateHeap::new(0x8_0000);
+    let page_table = &heap.page_table;
+    let initial_blocks = page_table.free_blocks();
+    let mut slabs = SlabCaches::new();
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let objects: [*mut u8; 256] = core::array::from_fn(|_| slabs.alloc(page_table, layout));
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(*object as usize % 32, 0);
+        assert!(!objects[..i].contains(object));
+    }
+    let aligned = Layout::from_size_align(8, 256).unwrap();
+    let aligned_object = slabs.alloc(page_table, aligned);
+    assert_eq!(aligned_object as usize % 256, 0);
+    slabs.dealloc(page_table, aligned_object, aligned);
+    // Los objetos de otro subsistema van a sus propios slabs
+    let vfs_object = {
+        let _tag = AllocTag::Vfs.enter();
+        slabs.alloc(page_table, layout)
+    };
+    assert_eq!(page_table.stats().tag_pages(AllocTag::Vfs), 1);
+    assert!(!objects.iter().any(|object| {
+        *object as usize & !(PAGE_SIZE - 1) == vfs_object as usize & !(PAGE_SIZE - 1)
+    }));
+    slabs.dealloc(page_table, vfs_object, layout);
+    assert_eq!(page_table.stats().tag_pages(AllocTag::Vfs), 0);
+    for object in objects {
+        slabs.dealloc(page_table, object, layout);
+    }
+    assert_eq!(page_table.free_blocks(), initial_blocks);
+}
+
+/// Contadores por subsistema, máximo de páginas y lugar de cada reserva
+#[test_case]
+fn alloc_stats() {
+    let heap = PrivateHeap::new(0x8_0000);
+    let page_table = &heap.page_table;
+    let before = page_table.stats();
+    assert_eq!(before.allocated, 0);
+    assert_eq!(before.free(), before.total);
+    let first = {
+        let _tag = AllocTag::Vfs.enter();
+        page_table.alloc(3).unwrap()
+    };
+    let second = page_table.alloc(1).unwrap();
+    let stats = page_table.stats();
+    assert_eq!(stats.allocated, 4);
+    assert_eq!(stats.tag_pages(AllocTag::Vfs), 3);
+    assert_eq!(stats.tag_pages(AllocTag::Kernel), 1);
+    page_table.dealloc(first);
+    let diff = page_table.stats().diff(&before);
+    assert_eq!(diff.pages, 1);
+    assert!(!diff.is_balanced());
+    page_table.dealloc(second);
+    let stats = page_table.stats();
+    assert!(stats.diff(&before).is_balanced());
+    assert_eq!(stats.peak, 4);
+    // En modo debug cada reserva guarda dónde se hizo
+    assert!(page_table.track_call_sites());
+    let tracked = page_table.stats();
+    let ptr = page_table.alloc(2).unwrap();
+    let line = line!() - 1;
+    let mut found = false;
+    page_table.for_each_allocation(|allocation| {
+        if allocation.address == ptr.as_ptr() as usize {
+            let location = allocation.call_site.unwrap();
+            assert_eq!(allocation.pages, 2);
+            assert_eq!(location.file(), file!());
+            assert_eq!(location.line(), line);
+            found = true;
+        }
+    });
+    assert!(found);
+    page_table.dealloc(ptr);
+    assert!(page_table.stats().diff(&tracked).is_balanced());
+}
+
+/// Los lugares registrados son los de quien pidió la memoria, nunca el
+/// allocator: las páginas de los slabs quedan sin lugar
+#[test_case]
+fn call_sites_outside_allocator() {
+    let heap = PrivateHeap::new(0x8_0000);
+    let page_table = &heap.page_table;
+    assert!(page_table.track_call_sites());
+    let mut slabs = SlabCaches::new();
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let object = slabs.alloc(page_table, layout);
+    let ptr = page_table.zalloc(1).unwrap();
+    let mut recorded = 0;
+    page_table.for_each_allocation(|allocation| match allocation.call_site {
+        Some(location) => {
+            assert!(!location.file().starts_with("src/mmu"));
+            recorded += 1;
         }
+        None => assert_eq!(allocation.address, object as usize & !(PAGE_SIZE - 1)),
+    });
+    // El arreglo de lugares y la página reservada por el test
+    assert_eq!(recorded, 2);
+    slabs.dealloc(page_table, object, layout);
+    page_table.dealloc(ptr);
+}
+
+/// Un proceso devuelve todas sus páginas al destruirse
+#[test_case]
+fn process_returns_every_page() {
+    let page_table = GLOBAL_PAGE_TABLE.get_root();
+    let before = page_table.stats();
+    {
+        let process = Process::create(page_table);
+        let diff = page_table.stats().diff(&before);
+        assert!(diff.by_tag[AllocTag::Process as usize] > 0);
+        drop(process);
+    }
+    let diff = page_table.stats().diff(&before);
+    assert!(diff.is_balanced(), "Leaked pages: {}", diff);
+}
+
+/// Las páginas de las áreas válidas se reservan en cero al primer acceso, y
+/// los accesos fuera de las áreas o sin permiso no se resuelven
+#[test_case]
+fn demand_paging() {
+    let page_table = GLOBAL_PAGE_TABLE.get_root();
+    let before = page_table.stats();
+    {
+        let mut process = Process::create(page_table);
+        let heap = 0x4000_0000;
+        let heap_bits = EntryBits::UserReadWrite.val();
+        assert!(process.add_vma(Vma::new(
+            heap,
+            heap + 4 * PAGE_SIZE,
+            heap_bits,
+            VmaKind::Heap
+        )));
+        let text_bits = EntryBits::UserReadExecute.val();
+        assert!(process.add_vma(Vma::new(0x2000_0000, 0x2000_1000, text_bits, VmaKind::Elf)));
+        assert!(!process.add_vma(Vma::new(heap, heap + PAGE_SIZE, heap_bits, VmaKind::Heap)));
+        assert_eq!(process.heap_limit(), heap + 4 * PAGE_SIZE);
+        // Heap y crecimiento del stack
+        assert!(process.root.virt_to_phys(heap + PAGE_SIZE).is_none());
+        assert!(process.handle_page_fault(heap + PAGE_SIZE + 8, Access::Store));
+        assert!(process.handle_page_fault(STACK_ADDR - 8, Access::Load));
+        assert!(process.root.virt_to_phys(heap + PAGE_SIZE).is_some());
+        assert!(process.root.virt_to_phys(STACK_ADDR - PAGE_SIZE).is_some());
+        assert!(process.root.virt_to_phys(heap).is_none());
+        let mut data = [0xff; 16];
+        assert!(process.copy_from_user(heap + PAGE_SIZE, &mut data));
+        assert_eq!(data, [0; 16]);
+        // Una página ya mapeada o un acceso inválido no se resuelven
+        assert!(!process.handle_page_fault(heap + PAGE_SIZE, Access::Store));
+        assert!(!process.handle_page_fault(0x2000_0000, Access::Store));
+        assert!(!process.handle_page_fault(heap + 4 * PAGE_SIZE, Access::Load));
+        assert!(!process.handle_page_fault(0, Access::Load));
+        // El kernel respeta los permisos de las áreas
+        assert!(process.handle_page_fault(0x2000_0000, Access::Load));
+        assert!(!process.copy_to_user(0x2000_0000, b"text"));
+        assert!(!process.copy_to_user(heap + 4 * PAGE_SIZE, b"out"));
+        assert!(process.load_to_user(0x2000_0000, b"text"));
+        // El kernel también reserva las páginas al copiar
+        assert!(process.copy_to_user(heap + 3 * PAGE_SIZE, b"lazy"));
+        assert!(process.root.virt_to_phys(heap + 3 * PAGE_SIZE).is_some());
+    }
+    let diff = page_table.stats().diff(&before);
+    assert!(diff.is_balanced(), "Leaked pages: {}", diff);
+}
+
+/// Una página compartida se libera recién al soltar la última referencia
+#[test_case]
+fn shared_pages() {
+    let heap = PrivateHeap::new(0x8_0000);
+    let page_table = &heap.page_table;
+    let ptr = page_table.alloc(1).unwrap();
+    assert_eq!(page_table.ref_count(ptr), 1);
+    page_table.share(ptr);
+    assert_eq!(page_table.ref_count(ptr), 2);
+    assert!(!page_table.release(ptr));
+    assert_eq!(page_table.ref_count(ptr), 1);
+    assert!(page_table.release(ptr));
+    assert_eq!(page_table.ref_count(ptr), 0);
+    assert_eq!(page_table.stats().allocated, 0);
+}
+
+/// Después de un fork las páginas se comparten, y se copian recién cuando
+/// alguno de los procesos escribe
+#[test_case]
+fn copy_on_write_fork() {
+    let page_table = GLOBAL_PAGE_TABLE.get_root();
+    let before = page_table.stats();
+    {
+        let mut parent = Process::create(page_table);
+        let heap = 0x4000_0000;
+        let heap_bits = EntryBits::UserReadWrite.val();
+        assert!(parent.add_vma(Vma::new(heap, heap + PAGE_SIZE, heap_bits, VmaKind::Heap)));
+        assert!(parent.copy_to_user(heap, b"parent"));
+        let text = 0x2000_0000;
+        let text_bits = EntryBits::UserReadExecute.val();
+        assert!(parent.add_vma(Vma::new(text, text + PAGE_SIZE, text_bits, VmaKind::Elf)));
+        assert!(parent.handle_page_fault(text, Access::Execute));
+        assert!(parent.load_to_user(text, b"code"));
+        let shared = parent.root.virt_to_phys(heap).unwrap();
+        let frame = NonNull::new(shared as *mut u8).unwrap();
+        let mut child = parent.fork(0);
+        assert_eq!(page_table.ref_count(frame), 2);
+        assert_eq!(child.root.virt_to_phys(heap), Some(shared));
+        assert!(!parent.root.find_leaf(heap).unwrap().0.is_writable());
+        assert!(!child.root.find_leaf(heap).unwrap().0.is_writable());
+        // El hijo escribe en su propia copia
+        assert!(child.handle_page_fault(heap, Access::Store));
+        let copy = child.root.virt_to_phys(heap).unwrap();
+        assert_ne!(copy, shared);
+        assert_eq!(page_table.ref_count(frame), 1);
+        assert!(child.copy_to_user(heap, b"child!"));
+        let mut data = [0; 6];
+        assert!(parent.copy_from_user(heap, &mut data));
+        assert_eq!(&data, b"parent");
+        // El padre quedó como único dueño y recupera la escritura sin copiar
+        assert!(parent.handle_page_fault(heap, Access::Store));
+        assert_eq!(parent.root.virt_to_phys(heap), Some(shared));
+        assert!(parent.root.find_leaf(heap).unwrap().0.is_writable());
+        // Las escrituras del kernel también copian las páginas compartidas
+        let grandchild = child.fork(0);
+        assert!(child.copy_to_user(heap, b"again!"));
+        assert_eq!(grandchild.root.virt_to_phys(heap), Some(copy));
+        assert_ne!(child.root.virt_to_phys(heap), Some(copy));
+        // pero no escriben en el código que comparten
+        assert!(!child.copy_to_user(text, b"evil"));
+        let mut code = [0; 4];
+        assert!(parent.copy_from_user(text, &mut code));
+        assert_eq!(&code, b"code");
     }
-    unsafe { assert_eq!(*(HEAP_START as *const u8), PageBits::Empty.val()) } // Test alloc simple
+    let diff = page_table.stats().diff(&before);
+    assert!(diff.is_balanced(), "Leaked pages: {}", diff);
 }
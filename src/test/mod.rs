@@ -1,5 +1,9 @@
 /// Basado en https://os.phil-opp.com/testing/
+mod elf_loader;
 mod mmu;
+mod net;
+mod random;
+mod virtio;
 
 use crate::{print, println};
 
@@ -0,0 +1,63 @@
+use crate::net::tcp::TcpSegment;
+use crate::net::{checksum, ipv4};
+use alloc::vec;
+
+/// Header IPv4 de ejemplo, con checksum 0xb861
+const IPV4_HEADER: [u8; 20] = [
+    0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01,
+    0xc0, 0xa8, 0x00, 0xc7,
+];
+
+/// El checksum de un header conocido, y 0 al verificarlo con su checksum
+#[test_case]
+fn internet_checksum() {
+    let mut header = IPV4_HEADER;
+    assert_eq!(checksum(&header), 0);
+    header[10..12].copy_from_slice(&[0, 0]);
+    assert_eq!(checksum(&header), 0xb861);
+    // Los datos de largo impar se completan con un byte en cero
+    assert_eq!(checksum(&[0x12, 0x34, 0x56]), !0x6834);
+}
+
+/// Se descartan los paquetes IPv4 cortados o con un header que no entra
+#[test_case]
+fn ipv4_parse_rejects_bad_headers() {
+    let mut packet = IPV4_HEADER.to_vec();
+    packet.resize(0x73, 0);
+    let (header, payload) = ipv4::parse(&packet).unwrap();
+    assert_eq!(header.source, [192, 168, 0, 1]);
+    assert_eq!(header.destination, [192, 168, 0, 199]);
+    assert_eq!(payload.len(), 0x73 - IPV4_HEADER.len());
+    // Menos bytes que los que indica el largo total
+    assert!(ipv4::parse(&packet[..0x72]).is_none());
+    // Menos bytes que un header
+    assert!(ipv4::parse(&packet[..19]).is_none());
+    // IHL menor al mínimo o mayor al paquete
+    let with_ihl = |ihl: u8, total_size: u16| {
+        let mut packet = packet.clone();
+        packet[0] = 0x40 | ihl;
+        packet[2..4].copy_from_slice(&total_size.to_be_bytes());
+        packet.truncate(total_size as usize);
+        packet
+    };
+    assert!(ipv4::parse(&with_ihl(4, 0x73)).is_none());
+    assert!(ipv4::parse(&with_ihl(15, 40)).is_none());
+}
+
+/// Se descartan los segmentos TCP cortados o con un header que no entra
+#[test_case]
+fn tcp_parse_rejects_bad_headers() {
+    // Segmento con la opción MSS en los bytes que siguen al header fijo
+    let segment = |data_offset: u8, size: usize| {
+        let mut segment = vec![0; size];
+        segment[12] = data_offset << 4;
+        segment[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+        segment
+    };
+    let valid = segment(6, 27);
+    let (_, payload) = TcpSegment::parse(&valid).unwrap();
+    assert_eq!(payload.len(), 3);
+    assert!(TcpSegment::parse(&valid[..19]).is_none());
+    assert!(TcpSegment::parse(&segment(4, 27)).is_none());
+    assert!(TcpSegment::parse(&segment(15, 40)).is_none());
+}
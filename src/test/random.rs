@@ -0,0 +1,33 @@
+use crate::system::random::{chacha20_block, KEY_WORDS};
+
+/// Vector de prueba de la función de bloque (RFC 8439, sección 2.3.2)
+#[test_case]
+fn chacha20_block_vector() {
+    let key: [u32; KEY_WORDS] = core::array::from_fn(|i| {
+        let byte = i as u8 * 4;
+        u32::from_le_bytes([byte, byte + 1, byte + 2, byte + 3])
+    });
+    // El RFC usa un contador de 32 bits (1) y un nonce de 96 bits
+    // (00:00:00:09:00:00:00:4a:00:00:00:00). Con nuestro contador de 64 bits
+    // la primera palabra del nonce queda en la parte alta del contador
+    let block = chacha20_block(&key, 0x0900_0000_0000_0001, [0x4a00_0000, 0]);
+    let expected = [
+        0xe4e7_f110,
+        0x1559_3bd1,
+        0x1fdd_0f50,
+        0xc471_20a3,
+        0xc7f4_d1c7,
+        0x0368_c033,
+        0x9aaa_2204,
+        0x4e6c_d4c3,
+        0x4664_82d2,
+        0x09aa_9f07,
+        0x05d7_c214,
+        0xa202_8bd9,
+        0xd19c_12b5,
+        0xb94e_16de,
+        0xe883_d0cb,
+        0x4e3c_50a2,
+    ];
+    assert_eq!(block, expected);
+}
@@ -0,0 +1,34 @@
+use crate::devices::virtio::common::{Descriptor, VirtQueue, VIRTIO_QUEUE_SIZE};
+use alloc::vec::Vec;
+
+/// Las cadenas ocupan descriptores hasta agotar la cola, y al completarse
+/// todos vuelven a la lista de libres
+#[test_case]
+fn virtqueue_free_list() {
+    let mut queue = VirtQueue::unregistered();
+    let chain = [Descriptor::default(); 3];
+    let mut heads = Vec::new();
+    while let Some(head) = queue.push_chain(&chain) {
+        heads.push(head);
+    }
+    assert_eq!(heads.len(), VIRTIO_QUEUE_SIZE / chain.len());
+    assert_eq!(queue.free_descriptors(), VIRTIO_QUEUE_SIZE % chain.len());
+    assert!(queue.pop_used().is_none());
+    for (i, head) in heads.iter().enumerate() {
+        queue.complete(*head, i as u32);
+    }
+    for (i, head) in heads.iter().enumerate() {
+        let used = queue.pop_used().unwrap();
+        assert_eq!(used.id, *head as u32);
+        assert_eq!(used.len, i as u32);
+    }
+    assert!(queue.pop_used().is_none());
+    assert_eq!(queue.free_descriptors(), VIRTIO_QUEUE_SIZE);
+    // Con la cola vacía entra una cadena del tamaño de toda la tabla
+    let whole_table = [Descriptor::default(); VIRTIO_QUEUE_SIZE];
+    let head = queue.push_chain(&whole_table).unwrap();
+    assert_eq!(queue.free_descriptors(), 0);
+    queue.complete(head, 0);
+    queue.pop_used().unwrap();
+    assert_eq!(queue.free_descriptors(), VIRTIO_QUEUE_SIZE);
+}
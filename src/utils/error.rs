@@ -9,6 +9,10 @@ pub enum OsError {
 pub enum IoError {
     DeviceError(DeviceError),
     FileNotExists,
+    NotADirectory,
+    IsADirectory,
+    /// Los datos del sistema de archivos no son consistentes
+    CorruptedFilesystem,
 }
 
 impl From<DeviceError> for IoError {
@@ -9,20 +9,33 @@ char *__env[1] = { 0 };
 char **environ = __env;
 
 void _exit(int status) {
+    call_syscall(SYS_EXIT, status);
+    while (1) {}
 }
 
 int _close(int fd) {
-    return -1;
+    if (call_syscall(SYS_CLOSE, fd) < 0) {
+        errno = EBADF;
+        return -1;
+    }
+    return 0;
 }
 
 int _execve(char *name, char **argv, char **env) {
-    errno = ENOMEM;
-    return -1;
+    if (call_syscall(SYS_EXECVE, name, argv, env) < 0) {
+        errno = ENOENT;
+        return -1;
+    }
+    return 0;
 }
 
 int _fork(void) {
-    errno = EAGAIN;
-    return -1;
+    long pid = call_syscall(SYS_FORK);
+    if (pid < 0) {
+        errno = EAGAIN;
+        return -1;
+    }
+    return pid;
 }
 
 int _fstat(int file, struct stat *st) {
@@ -49,15 +62,30 @@ int _link(char *old, char *new) {
 }
 
 int _lseek(int file, int ptr, int dir) {
-    return 0;
+    long pos = call_syscall(SYS_LSEEK, file, ptr, dir);
+    if (pos < 0) {
+        errno = ESPIPE;
+        return -1;
+    }
+    return pos;
 }
 
 int _open(const char *name, int flags, int mode) {
-    return -1;
+    long fd = call_syscall(SYS_OPEN, name, flags, mode);
+    if (fd < 0) {
+        errno = ENOENT;
+        return -1;
+    }
+    return fd;
 }
 
 int _read(int file, char *ptr, int len) {
-    return 0;
+    long read = call_syscall(SYS_READ, file, ptr, len);
+    if (read < 0) {
+        errno = EBADF;
+        return -1;
+    }
+    return read;
 }
 
 caddr_t _sbrk(int incr) {
@@ -96,16 +124,27 @@ int _times(struct tms *buf) {
 }
 
 int _unlink(char *name) {
-    errno = ENOENT;
-    return -1;
+    if (call_syscall(SYS_UNLINK, name) < 0) {
+        errno = ENOENT;
+        return -1;
+    }
+    return 0;
 }
 
 int _wait(int *status) {
-    errno = ECHILD;
-    return -1;
+    long pid = call_syscall(SYS_WAIT, status);
+    if (pid < 0) {
+        errno = ECHILD;
+        return -1;
+    }
+    return pid;
 }
 
 int _write(int file, char *buf, int len) {
-    call_syscall(SYS_WRITE, file, buf, len);
-    return len;
+    long written = call_syscall(SYS_WRITE, file, buf, len);
+    if (written < 0) {
+        errno = EBADF;
+        return -1;
+    }
+    return written;
 }
@@ -3,16 +3,72 @@
 
 typedef unsigned long int uintptr_t;
 
+#define UINTPTR_MAX (~(uintptr_t) 0)
+
 static const uintptr_t REBOOT_MAGIC_1 = 318839184;
 
 static const uintptr_t REBOOT_MAGIC_2 = 3402301098;
 
+static const uintptr_t SYS_READ = 0;
+
 static const uintptr_t SYS_WRITE = 1;
 
+static const uintptr_t SYS_OPEN = 2;
+
+static const uintptr_t SYS_CLOSE = 3;
+
+static const uintptr_t SYS_LSEEK = 8;
+
 static const uintptr_t SYS_BRK = 12;
 
+static const uintptr_t SYS_SOCKET = 41;
+
+static const uintptr_t SYS_CONNECT = 42;
+
+static const uintptr_t SYS_ACCEPT = 43;
+
+static const uintptr_t SYS_SENDTO = 44;
+
+static const uintptr_t SYS_RECVFROM = 45;
+
 static const uintptr_t SYS_REBOOT = 48;
 
-void call_syscall(int id, ...);
+static const uintptr_t SYS_BIND = 49;
+
+static const uintptr_t SYS_LISTEN = 50;
+
+static const uintptr_t SYS_FORK = 57;
+
+static const uintptr_t SYS_EXECVE = 59;
+
+static const uintptr_t SYS_EXIT = 60;
+
+static const uintptr_t SYS_WAIT = 61;
+
+static const uintptr_t SYS_FTRUNCATE = 77;
+
+static const uintptr_t SYS_UNLINK = 87;
+
+static const uintptr_t SYS_GETRANDOM = 318;
+
+static const uintptr_t SYSCALL_ERROR = UINTPTR_MAX;
+
+static const uintptr_t SEEK_SET = 0;
+
+static const uintptr_t SEEK_CUR = 1;
+
+static const uintptr_t SEEK_END = 2;
+
+static const uintptr_t AF_INET = 2;
+
+static const uintptr_t SOCK_STREAM = 1;
+
+static const uintptr_t SOCK_DGRAM = 2;
+
+static const uintptr_t GRND_NONBLOCK = 1;
+
+static const uintptr_t GRND_RANDOM = 2;
+
+long call_syscall(uintptr_t id, ...);
 
 #endif